@@ -1,21 +1,443 @@
-mod system;
+use chip_8::system::assembler;
+use chip_8::system::breakpoint::{Breakpoint, Watchpoint};
+use chip_8::system::cpu::{self, Backend, InvalidOpcodePolicy, CPU};
+use chip_8::system::disassembler;
+#[cfg(feature = "gui")]
+use chip_8::system::gui::GuiOptions;
+use chip_8::system::quirks::{self, Quirks};
+use chip_8::system::rewind;
+use chip_8::system::symbols::Symbols;
 use std::env;
-use std::fs::File;
-use system::cpu::CPU;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+// the length of the `bench` runs, long enough for the roms to get past their title screens
+const BENCHMARK_FRAMES: u32 = 600;
+const BENCHMARK_INSTRUCTIONS_PER_FRAME: u32 = 2000;
+
+fn print_usage(program: &str) {
+    println!("USAGE: {} [OPTIONS] <rom-file>", program);
+    println!(
+        "       {} disasm <rom-file> [<output-file>]    write the rom as assembler source",
+        program
+    );
+    println!(
+        "       {} asm <source-file> [<output-file>]    assemble the source into a rom (default: <source-file>.ch8)",
+        program
+    );
+    println!(
+        "       {} bench <rom-file>...    compare the speed of the backends, without and with the decode cache",
+        program
+    );
+    println!();
+    println!("OPTIONS:");
+    println!(
+        "    --quirks <preset>    the quirks of the interpreter to emulate, one of: {} (default: modern)",
+        quirks::PRESET_NAMES.join(", ")
+    );
+    println!(
+        "    --invalid-opcodes <policy>    what to do on invalid opcodes, one of: {} (default: ignore)",
+        cpu::INVALID_OPCODE_POLICY_NAMES.join(", ")
+    );
+    println!(
+        "    --backend <backend>    how the instructions are executed, one of: {} (default: interpreter)",
+        cpu::BACKEND_NAMES.join(", ")
+    );
+    println!(
+        "    --break <breakpoint>    stop before the instruction at the address, can be repeated, the breakpoint is `<address> [if <condition>] [after <count>]` (e.g. `0x2A4 if V3 == 0x10 after 2`)"
+    );
+    println!(
+        "    --watch <watchpoint>    stop when the instructions access the memory, can be repeated, the watchpoint is `<address>[-<end>] [r][w][x]` (e.g. `0x300-0x302 w`, writes if not given)"
+    );
+    println!(
+        "    --symbols <symbol-file>    the names of the addresses shown by the debuggers and accepted in breakpoints, `name = 0xADDR` lines or JSON (as written by Octo)"
+    );
+    println!(
+        "    --state <state-file>    start from a save state (the quirks are taken from the state)"
+    );
+    println!(
+        "    --repl    debug the rom from the terminal instead of opening the window (`help` for the commands)"
+    );
+    println!(
+        "    --gdb <port>    wait for gdb on 127.0.0.1:<port> (GDB remote protocol) instead of opening the window"
+    );
+    println!(
+        "    --rewind-memory <MiB>    the memory used to rewind in the window, 0 to disable (default: {})",
+        rewind::DEFAULT_REWIND_MEMORY / (1024 * 1024)
+    );
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     // mostly redundant
-    assert_eq!(args.len() > 0, true);
+    assert!(!args.is_empty());
+
+    match args.get(1).map(|x| x.as_str()) {
+        Some("disasm") => {
+            disasm(&args);
+            return;
+        }
+        Some("asm") => {
+            asm(&args);
+            return;
+        }
+        Some("bench") => {
+            bench(&args);
+            return;
+        }
+        _ => {}
+    }
+
+    let mut rom_file = None;
+    let mut state_file = None;
+    let mut rewind_memory = rewind::DEFAULT_REWIND_MEMORY;
+    let mut quirks = Quirks::default();
+    let mut invalid_opcode_policy = InvalidOpcodePolicy::Ignore;
+    let mut backend = Backend::Interpreter;
+    let mut breakpoints = Vec::new();
+    let mut watchpoints = Vec::new();
+    let mut gdb_port = None;
+    let mut symbols_file = None;
+    let mut repl = false;
+
+    let mut args_iter = args.iter().skip(1);
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--quirks" => {
+                let name = args_iter.next().map(|x| x.as_str()).unwrap_or("");
+                match Quirks::from_name(name) {
+                    Some(preset) => quirks = preset,
+                    None => {
+                        eprintln!("Unknown quirks preset `{}`", name);
+                        print_usage(&args[0]);
+                        return;
+                    }
+                }
+            }
+            "--invalid-opcodes" => {
+                let name = args_iter.next().map(|x| x.as_str()).unwrap_or("");
+                match InvalidOpcodePolicy::from_name(name) {
+                    Some(policy) => invalid_opcode_policy = policy,
+                    None => {
+                        eprintln!("Unknown invalid opcodes policy `{}`", name);
+                        print_usage(&args[0]);
+                        return;
+                    }
+                }
+            }
+            "--backend" => {
+                let name = args_iter.next().map(|x| x.as_str()).unwrap_or("");
+                match Backend::from_name(name) {
+                    Some(value) => backend = value,
+                    None => {
+                        eprintln!("Unknown backend `{}`", name);
+                        print_usage(&args[0]);
+                        return;
+                    }
+                }
+            }
+            // parsed after the arguments, they can use the symbols
+            "--break" => breakpoints.push(args_iter.next().map(|x| x.as_str()).unwrap_or("")),
+            "--watch" => watchpoints.push(args_iter.next().map(|x| x.as_str()).unwrap_or("")),
+            "--symbols" => match args_iter.next() {
+                Some(file) => symbols_file = Some(file),
+                None => {
+                    eprintln!("Missing the symbol file after `--symbols`");
+                    print_usage(&args[0]);
+                    return;
+                }
+            },
+            "--repl" => repl = true,
+            "--gdb" => {
+                let value = args_iter.next().map(|x| x.as_str()).unwrap_or("");
+                match value.parse::<u16>() {
+                    Ok(port) => gdb_port = Some(port),
+                    Err(_) => {
+                        eprintln!("Invalid port `{}`", value);
+                        print_usage(&args[0]);
+                        return;
+                    }
+                }
+            }
+            "--state" => match args_iter.next() {
+                Some(file) => state_file = Some(file),
+                None => {
+                    eprintln!("Missing the state file after `--state`");
+                    print_usage(&args[0]);
+                    return;
+                }
+            },
+            "--rewind-memory" => {
+                let value = args_iter.next().map(|x| x.as_str()).unwrap_or("");
+                match value
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|mebibytes| mebibytes.checked_mul(1024 * 1024))
+                {
+                    Some(bytes) => rewind_memory = bytes,
+                    None => {
+                        eprintln!("Invalid rewind memory `{}`", value);
+                        print_usage(&args[0]);
+                        return;
+                    }
+                }
+            }
+            _ if rom_file.is_none() && !arg.starts_with("--") => rom_file = Some(arg),
+            _ => {
+                eprintln!("Unknown argument `{}`", arg);
+                print_usage(&args[0]);
+                return;
+            }
+        }
+    }
+
+    let rom_file = match rom_file {
+        Some(rom_file) => rom_file,
+        None => {
+            print_usage(&args[0]);
+            return;
+        }
+    };
+
+    let symbols = match symbols_file {
+        Some(symbols_file) => {
+            let result = fs::read_to_string(symbols_file)
+                .map_err(|error| error.to_string())
+                .and_then(|text| Symbols::parse(&text));
+
+            match result {
+                Ok(symbols) => symbols,
+                Err(error) => {
+                    eprintln!("Could not load the symbols `{}`: {}", symbols_file, error);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => Symbols::new(),
+    };
 
-    if args.len() < 2 {
-        println!("USAGE: {} <rom-file>", args[0]);
+    let mut cpu = CPU::new();
+    cpu.set_quirks(quirks);
+    cpu.set_invalid_opcode_policy(invalid_opcode_policy);
+    cpu.set_backend(backend);
+    for text in breakpoints {
+        match Breakpoint::parse(text, &symbols) {
+            Ok(breakpoint) => cpu.add_breakpoint(breakpoint),
+            Err(error) => {
+                eprintln!("Invalid breakpoint `{}`: {}", text, error);
+                print_usage(&args[0]);
+                return;
+            }
+        }
+    }
+    for text in watchpoints {
+        match Watchpoint::parse(text, &symbols) {
+            Ok(watchpoint) => cpu.get_memory_mut().add_watchpoint(watchpoint),
+            Err(error) => {
+                eprintln!("Invalid watchpoint `{}`: {}", text, error);
+                print_usage(&args[0]);
+                return;
+            }
+        }
+    }
+    cpu.set_symbols(symbols);
+    match fs::read(rom_file) {
+        Ok(rom) => cpu.read_file(&mut &rom[..]),
+        Err(error) => {
+            eprintln!("Could not read the rom `{}`: {}", rom_file, error);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(state_file) = state_file {
+        let result = fs::read(state_file)
+            .map_err(|error| error.to_string())
+            .and_then(|state| cpu.load_state(&state).map_err(|error| error.to_string()));
+
+        if let Err(error) = result {
+            eprintln!("Could not load the state `{}`: {}", state_file, error);
+            std::process::exit(1);
+        }
+    }
+
+    if repl {
+        cpu.run_repl();
         return;
     }
 
+    if let Some(port) = gdb_port {
+        if let Err(error) = cpu.run_gdb_server(port) {
+            eprintln!("Could not run the gdb server on port {}: {}", port, error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    run(cpu, rom_file, rewind_memory);
+}
+
+/// the `disasm` command, writes the rom as assembler source to the output
+/// file or to stdout
+fn disasm(args: &[String]) {
+    let (rom_file, output_file) = match &args[2..] {
+        [rom_file] => (rom_file, None),
+        [rom_file, output_file] => (rom_file, Some(output_file)),
+        _ => {
+            print_usage(&args[0]);
+            return;
+        }
+    };
+
+    let rom = match fs::read(rom_file) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("Could not read the rom `{}`: {}", rom_file, error);
+            std::process::exit(1);
+        }
+    };
+
+    let source = disassembler::export_source(&rom);
+
+    match output_file {
+        Some(output_file) => {
+            if let Err(error) = fs::write(output_file, source) {
+                eprintln!("Could not write `{}`: {}", output_file, error);
+                std::process::exit(1);
+            }
+        }
+        None => print!("{}", source),
+    }
+}
+
+/// the `asm` command, assembles the source file into a rom
+fn asm(args: &[String]) {
+    let (source_file, output_file) = match &args[2..] {
+        [source_file] => (source_file, Path::new(source_file).with_extension("ch8")),
+        [source_file, output_file] => (source_file, PathBuf::from(output_file)),
+        _ => {
+            print_usage(&args[0]);
+            return;
+        }
+    };
+
+    let source = match fs::read_to_string(source_file) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("Could not read `{}`: {}", source_file, error);
+            std::process::exit(1);
+        }
+    };
+
+    let rom = match assembler::assemble(&source, source_file) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(error) = fs::write(&output_file, rom) {
+        eprintln!("Could not write `{}`: {}", output_file.display(), error);
+        std::process::exit(1);
+    }
+}
+
+/// the `bench` command, runs every rom without a window with the interpreter
+/// (without and with the decode cache) and the blocks backend, and prints the
+/// instructions per second of each
+fn bench(args: &[String]) {
+    let rom_files = &args[2..];
+    if rom_files.is_empty() {
+        print_usage(&args[0]);
+        return;
+    }
+
+    println!(
+        "{:<30} {:>14} {:>14} {:>9} {:>14} {:>9}",
+        "rom", "IPS (no cache)", "IPS (cache)", "speedup", "IPS (blocks)", "speedup"
+    );
+
+    for rom_file in rom_files {
+        let rom = match fs::read(rom_file) {
+            Ok(rom) => rom,
+            Err(error) => {
+                eprintln!("Could not read the rom `{}`: {}", rom_file, error);
+                std::process::exit(1);
+            }
+        };
+
+        let (uncached_ips, uncached_state) = bench_rom(&rom, Backend::Interpreter, false);
+        let (cached_ips, cached_state) = bench_rom(&rom, Backend::Interpreter, true);
+        let (blocks_ips, blocks_state) = bench_rom(&rom, Backend::Blocks, true);
+
+        println!(
+            "{:<30} {:>14.0} {:>14.0} {:>8.2}x {:>14.0} {:>8.2}x",
+            Path::new(rom_file)
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default(),
+            uncached_ips,
+            cached_ips,
+            cached_ips / uncached_ips,
+            blocks_ips,
+            blocks_ips / uncached_ips
+        );
+
+        // all the runs are deterministic and should end in the same state
+        if cached_state != uncached_state {
+            eprintln!(
+                "warning: `{}` ended differently with the decode cache",
+                rom_file
+            );
+        }
+        if blocks_state != uncached_state {
+            eprintln!(
+                "warning: `{}` ended differently with the blocks backend",
+                rom_file
+            );
+        }
+    }
+}
+
+/// run `rom` for `BENCHMARK_FRAMES` frames, returns the instructions per
+/// second and the state at the end
+fn bench_rom(rom: &[u8], backend: Backend, decode_cache: bool) -> (f64, Vec<u8>) {
     let mut cpu = CPU::new();
-    cpu.read_file(&mut File::open(&args[1]).unwrap());
+    cpu.set_seed(0);
+    cpu.set_backend(backend);
+    cpu.set_decode_cache_enabled(decode_cache);
+    cpu.set_instructions_per_frame(BENCHMARK_INSTRUCTIONS_PER_FRAME);
+    cpu.read_file(&mut &rom[..]);
+
+    let start = Instant::now();
+    for frame in 0..BENCHMARK_FRAMES {
+        // press the keys one after the other, so the roms waiting for a key continue
+        let mut keyboard = [false; 16];
+        keyboard[(frame / 2 % 16) as usize] = frame % 2 == 0;
+        cpu.set_keyboard(keyboard);
+
+        if cpu.run_frame().is_err() || cpu.has_exited() {
+            break;
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    (cpu.get_cycles() as f64 / elapsed, cpu.save_state())
+}
+
+#[cfg(feature = "gui")]
+fn run(cpu: CPU, rom_file: &str, rewind_memory: usize) {
+    cpu.run_display_application_with_options(GuiOptions {
+        // the save state slots are stored next to the rom
+        state_prefix: rom_file.to_owned(),
+        rewind_memory,
+    });
+}
 
-    cpu.run_display_application();
+#[cfg(not(feature = "gui"))]
+fn run(_cpu: CPU, _rom_file: &str, _rewind_memory: usize) {
+    eprintln!("This build has no GUI, rebuild with the `gui` feature to run roms in a window");
+    std::process::exit(1);
 }
@@ -0,0 +1,867 @@
+use super::disassembler::PROGRAM_START;
+use super::number::parse_number;
+use super::op::Op;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// how deep `include` can go, to stop include loops
+const MAX_INCLUDE_DEPTH: usize = 16;
+// how deep constants can refer to other constants, to stop loops
+const MAX_CONSTANT_DEPTH: usize = 32;
+// all the instructions
+const MNEMONICS: [&str; 33] = [
+    "CLS", "RET", "SCR", "SCL", "EXIT", "LOW", "HIGH", "AUDIO", "SCD", "SCU", "SYS", "JP", "JMP",
+    "CALL", "SE", "SNE", "SAVE", "LOAD", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SHR", "SUBN",
+    "SHL", "RND", "DRW", "SKP", "SKNP", "PLANE", "PITCH",
+];
+// the names that can't be used for labels and constants
+const RESERVED_NAMES: [&str; 9] = ["I", "DT", "ST", "K", "F", "HF", "B", "R", "LONG"];
+
+/// An error in the assembled source, with the place where it happened
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssemblerError {
+    pub file: String,
+    pub line: usize,   // starting from 1
+    pub column: usize, // starting from 1
+    pub message: String,
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+impl Error for AssemblerError {}
+
+/// Where a token is in the source
+#[derive(Clone, Debug)]
+struct Location {
+    file: String,
+    line: usize,
+    column: usize,
+}
+
+impl Location {
+    fn error<T>(&self, message: String) -> Result<T, AssemblerError> {
+        Err(AssemblerError {
+            file: self.file.clone(),
+            line: self.line,
+            column: self.column,
+            message,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+    Identifier(String),
+    Number(i64),
+    Text(String), // a string in double quotes, used by `include`
+    Comma,
+    Colon,
+    Equals,
+    Plus,
+    Minus,
+    OpenBracket,
+    CloseBracket,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: TokenKind,
+    location: Location,
+}
+
+/// split a line into tokens, comments start with `;`
+fn tokenize(text: &str, file: &str, line: usize) -> Result<Vec<Token>, AssemblerError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let location = Location {
+            file: file.to_owned(),
+            line,
+            column: i + 1,
+        };
+
+        let kind = match c {
+            ';' => break,
+            _ if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ',' => TokenKind::Comma,
+            ':' => TokenKind::Colon,
+            '=' => TokenKind::Equals,
+            '+' => TokenKind::Plus,
+            '-' => TokenKind::Minus,
+            '[' => TokenKind::OpenBracket,
+            ']' => TokenKind::CloseBracket,
+            '"' => {
+                let start = i + 1;
+                let end = match chars[start..].iter().position(|&c| c == '"') {
+                    Some(length) => start + length,
+                    None => return location.error("unterminated string".to_owned()),
+                };
+                i = end;
+                TokenKind::Text(chars[start..end].iter().collect())
+            }
+            _ if c.is_ascii_alphanumeric() || c == '_' || c == '.' => {
+                let start = i;
+                while i + 1 < chars.len()
+                    && (chars[i + 1].is_ascii_alphanumeric()
+                        || chars[i + 1] == '_'
+                        || chars[i + 1] == '.')
+                {
+                    i += 1;
+                }
+                let word: String = chars[start..=i].iter().collect();
+
+                if c.is_ascii_digit() {
+                    match parse_number(&word) {
+                        Some(number) => TokenKind::Number(number as i64),
+                        None => return location.error(format!("invalid number `{}`", word)),
+                    }
+                } else {
+                    TokenKind::Identifier(word)
+                }
+            }
+            _ => return location.error(format!("unexpected character `{}`", c)),
+        };
+
+        tokens.push(Token { kind, location });
+        i += 1;
+    }
+
+    Ok(tokens)
+}
+
+/// A value that can refer to labels and constants, like `sprites + 5`
+#[derive(Clone, Debug)]
+struct Expression {
+    terms: Vec<(bool, Term)>, // (is negative, term)
+    location: Location,
+}
+
+#[derive(Clone, Debug)]
+enum Term {
+    Number(i64),
+    Symbol(String, Location),
+}
+
+impl Expression {
+    /// the value of the expression if it is only a number
+    fn get_literal(&self) -> Option<i64> {
+        match self.terms.as_slice() {
+            [(negative, Term::Number(number))] => Some(if *negative { -number } else { *number }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    Register(u8), // V0..VF
+    I,
+    IndirectI, // [I]
+    DT,
+    ST,
+    K,
+    F,
+    HF,
+    B,
+    R,
+    Long(Expression), // `long addr`, the 16-bit address of `LD I, long addr`
+    Value(Expression),
+}
+
+#[derive(Clone, Debug)]
+enum Item {
+    Instruction {
+        mnemonic: String, // in upper case
+        operands: Vec<Operand>,
+        location: Location,
+    },
+    Bytes(Vec<Expression>),
+    Words(Vec<Expression>),
+}
+
+impl Item {
+    fn get_size(&self) -> u32 {
+        match self {
+            Item::Instruction {
+                mnemonic, operands, ..
+            } => {
+                if is_long_load(mnemonic, operands) {
+                    4
+                } else {
+                    2
+                }
+            }
+            Item::Bytes(values) => values.len() as u32,
+            Item::Words(values) => values.len() as u32 * 2,
+        }
+    }
+}
+
+/// is it `LD I, long addr` (F000 nnnn), `LD I, addr` with a number that does
+/// not fit in 12 bits is also a long load
+fn is_long_load(mnemonic: &str, operands: &[Operand]) -> bool {
+    match (mnemonic, operands) {
+        ("LD", [Operand::I, Operand::Long(_)]) => true,
+        ("LD", [Operand::I, Operand::Value(value)]) => {
+            value.get_literal().is_some_and(|value| value > 0xFFF)
+        }
+        _ => false,
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Symbol {
+    Label(u16),
+    Constant(Expression),
+}
+
+/// Reads the tokens of one line
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    end: Location, // the location after the last token, for errors at the end of the line
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.position).map(|token| &token.kind)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&TokenKind> {
+        self.tokens
+            .get(self.position + offset)
+            .map(|token| &token.kind)
+    }
+
+    fn location(&self) -> Location {
+        self.tokens
+            .get(self.position)
+            .map_or_else(|| self.end.clone(), |token| token.location.clone())
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.position >= self.tokens.len()
+    }
+
+    fn expect_end(&self) -> Result<(), AssemblerError> {
+        if self.is_at_end() {
+            Ok(())
+        } else {
+            self.location()
+                .error("expected the end of the line".to_owned())
+        }
+    }
+
+    fn parse_expression(&mut self) -> Result<Expression, AssemblerError> {
+        let location = self.location();
+        let mut terms = Vec::new();
+        let mut negative = false;
+
+        if self.peek() == Some(&TokenKind::Minus) {
+            self.next();
+            negative = true;
+        }
+
+        loop {
+            let term_location = self.location();
+            let term = match self.next().map(|token| token.kind) {
+                Some(TokenKind::Number(number)) => Term::Number(number),
+                Some(TokenKind::Identifier(name)) => Term::Symbol(name, term_location),
+                _ => return term_location.error("expected a number or a label".to_owned()),
+            };
+            terms.push((negative, term));
+
+            match self.peek() {
+                Some(TokenKind::Plus) => negative = false,
+                Some(TokenKind::Minus) => negative = true,
+                _ => break,
+            }
+            self.next();
+        }
+
+        Ok(Expression { terms, location })
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, AssemblerError> {
+        if self.peek() == Some(&TokenKind::OpenBracket) {
+            let location = self.location();
+            self.next();
+            match (self.next().map(|token| token.kind), self.next()) {
+                (Some(TokenKind::Identifier(name)), Some(close))
+                    if name.eq_ignore_ascii_case("I") && close.kind == TokenKind::CloseBracket =>
+                {
+                    return Ok(Operand::IndirectI);
+                }
+                _ => return location.error("expected `[I]`".to_owned()),
+            }
+        }
+
+        // a name alone can be a register
+        let alone = matches!(self.peek_at(1), None | Some(TokenKind::Comma));
+        if let (Some(TokenKind::Identifier(name)), true) = (self.peek(), alone) {
+            let upper = name.to_uppercase();
+            let operand = match upper.as_str() {
+                "I" => Some(Operand::I),
+                "DT" => Some(Operand::DT),
+                "ST" => Some(Operand::ST),
+                "K" => Some(Operand::K),
+                "F" => Some(Operand::F),
+                "HF" => Some(Operand::HF),
+                "B" => Some(Operand::B),
+                "R" => Some(Operand::R),
+                _ => parse_register(&upper).map(Operand::Register),
+            };
+            if let Some(operand) = operand {
+                self.next();
+                return Ok(operand);
+            }
+        }
+
+        if let Some(TokenKind::Identifier(name)) = self.peek() {
+            if name.eq_ignore_ascii_case("long") {
+                self.next();
+                return Ok(Operand::Long(self.parse_expression()?));
+            }
+        }
+
+        Ok(Operand::Value(self.parse_expression()?))
+    }
+
+    /// comma separated operands until the end of the line
+    fn parse_operands(&mut self) -> Result<Vec<Operand>, AssemblerError> {
+        let mut operands = Vec::new();
+        if self.is_at_end() {
+            return Ok(operands);
+        }
+
+        loop {
+            operands.push(self.parse_operand()?);
+            if self.is_at_end() {
+                break;
+            }
+            if self.next().map(|token| token.kind) != Some(TokenKind::Comma) {
+                return self.tokens[self.position - 1]
+                    .location
+                    .error("expected `,`".to_owned());
+            }
+        }
+
+        Ok(operands)
+    }
+
+    /// comma separated values until the end of the line, for `db` and `dw`
+    fn parse_values(&mut self) -> Result<Vec<Expression>, AssemblerError> {
+        let mut values = vec![self.parse_expression()?];
+
+        while !self.is_at_end() {
+            if self.next().map(|token| token.kind) != Some(TokenKind::Comma) {
+                return self.tokens[self.position - 1]
+                    .location
+                    .error("expected `,`".to_owned());
+            }
+            values.push(self.parse_expression()?);
+        }
+
+        Ok(values)
+    }
+}
+
+/// the index of the register from its name (`V0`..`VF`)
+fn parse_register(name: &str) -> Option<u8> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('V'), Some(index), None) => index.to_digit(16).map(|index| index as u8),
+        _ => None,
+    }
+}
+
+/// Assembles the source in two passes, the first one finds the addresses of
+/// all the labels and the second one encodes the instructions
+struct Assembler {
+    items: Vec<Item>,
+    symbols: HashMap<String, (Symbol, Location)>,
+    address: u32, // the address of the next item
+}
+
+impl Assembler {
+    /// read all the lines of `source`, `file` is used in errors and to find
+    /// the included files
+    fn parse_source(
+        &mut self,
+        source: &str,
+        file: &str,
+        depth: usize,
+    ) -> Result<(), AssemblerError> {
+        for (index, text) in source.lines().enumerate() {
+            let tokens = tokenize(text, file, index + 1)?;
+            let end = Location {
+                file: file.to_owned(),
+                line: index + 1,
+                column: text.chars().count() + 1,
+            };
+            let mut parser = Parser {
+                tokens,
+                position: 0,
+                end,
+            };
+
+            self.parse_line(&mut parser, file, depth)?;
+        }
+
+        Ok(())
+    }
+
+    fn define_symbol(
+        &mut self,
+        name: &str,
+        symbol: Symbol,
+        location: Location,
+    ) -> Result<(), AssemblerError> {
+        let upper = name.to_uppercase();
+        if RESERVED_NAMES.contains(&upper.as_str()) || parse_register(&upper).is_some() {
+            return location.error(format!("`{}` is a reserved name", name));
+        }
+        if let Some((_, previous)) = self.symbols.get(name) {
+            return location.error(format!(
+                "`{}` is already defined at {}:{}",
+                name, previous.line, previous.column
+            ));
+        }
+
+        self.symbols.insert(name.to_owned(), (symbol, location));
+        Ok(())
+    }
+
+    fn parse_line(
+        &mut self,
+        parser: &mut Parser,
+        file: &str,
+        depth: usize,
+    ) -> Result<(), AssemblerError> {
+        // label:
+        if let (Some(TokenKind::Identifier(name)), Some(TokenKind::Colon)) =
+            (parser.peek(), parser.peek_at(1))
+        {
+            let name = name.clone();
+            let location = parser.location();
+            parser.next();
+            parser.next();
+            self.define_symbol(&name, Symbol::Label(self.address as u16), location)?;
+        }
+
+        // name = value
+        if let (Some(TokenKind::Identifier(name)), Some(TokenKind::Equals)) =
+            (parser.peek(), parser.peek_at(1))
+        {
+            let name = name.clone();
+            let location = parser.location();
+            parser.next();
+            parser.next();
+            let value = parser.parse_expression()?;
+            parser.expect_end()?;
+            return self.define_symbol(&name, Symbol::Constant(value), location);
+        }
+
+        let location = parser.location();
+        let mnemonic = match parser.next().map(|token| token.kind) {
+            None => return Ok(()),
+            Some(TokenKind::Identifier(mnemonic)) => mnemonic.to_uppercase(),
+            Some(_) => return location.error("expected an instruction or a label".to_owned()),
+        };
+
+        let item = match mnemonic.as_str() {
+            "DB" => Item::Bytes(parser.parse_values()?),
+            "DW" => Item::Words(parser.parse_values()?),
+            "INCLUDE" => {
+                let path_location = parser.location();
+                let path = match parser.next().map(|token| token.kind) {
+                    Some(TokenKind::Text(path)) => path,
+                    _ => return path_location.error("expected a file name in quotes".to_owned()),
+                };
+                parser.expect_end()?;
+
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return path_location.error("too many nested includes".to_owned());
+                }
+
+                // relative to the file that includes it
+                let full_path = Path::new(file).parent().map_or_else(
+                    || Path::new(&path).to_path_buf(),
+                    |parent| parent.join(&path),
+                );
+                let full_path = full_path.to_string_lossy().into_owned();
+                let source = match fs::read_to_string(&full_path) {
+                    Ok(source) => source,
+                    Err(error) => {
+                        return path_location
+                            .error(format!("could not read `{}`: {}", full_path, error))
+                    }
+                };
+
+                return self.parse_source(&source, &full_path, depth + 1);
+            }
+            _ if !MNEMONICS.contains(&mnemonic.as_str()) => {
+                return location.error(format!("unknown instruction `{}`", mnemonic));
+            }
+            _ => Item::Instruction {
+                mnemonic,
+                operands: parser.parse_operands()?,
+                location: location.clone(),
+            },
+        };
+
+        self.address += item.get_size();
+        if self.address > 0x10000 {
+            return location.error("the program does not fit in memory".to_owned());
+        }
+        self.items.push(item);
+
+        Ok(())
+    }
+
+    fn evaluate(&self, expression: &Expression, depth: usize) -> Result<i64, AssemblerError> {
+        if depth > MAX_CONSTANT_DEPTH {
+            return expression
+                .location
+                .error("constants refer to each other in a loop".to_owned());
+        }
+
+        let mut result = 0i64;
+        for (negative, term) in expression.terms.iter() {
+            let value = match term {
+                Term::Number(number) => *number,
+                Term::Symbol(name, location) => match self.symbols.get(name) {
+                    Some((Symbol::Label(address), _)) => *address as i64,
+                    Some((Symbol::Constant(value), _)) => self.evaluate(value, depth + 1)?,
+                    None => return location.error(format!("undefined label `{}`", name)),
+                },
+            };
+
+            result = if *negative {
+                result.wrapping_sub(value)
+            } else {
+                result.wrapping_add(value)
+            };
+        }
+
+        Ok(result)
+    }
+
+    /// evaluate and check that the value is in `min..=max`
+    fn evaluate_in_range(
+        &self,
+        expression: &Expression,
+        min: i64,
+        max: i64,
+        what: &str,
+    ) -> Result<u16, AssemblerError> {
+        let value = self.evaluate(expression, 0)?;
+        if value < min || value > max {
+            return expression
+                .location
+                .error(format!("{} 0x{:X} out of range", what, value));
+        }
+
+        // negative bytes are stored as their two's complement
+        Ok((value & 0xFFFF) as u16)
+    }
+
+    fn address_value(&self, expression: &Expression) -> Result<u16, AssemblerError> {
+        self.evaluate_in_range(expression, 0, 0xFFF, "address")
+    }
+
+    fn byte_value(&self, expression: &Expression) -> Result<u16, AssemblerError> {
+        Ok(self.evaluate_in_range(expression, -0x80, 0xFF, "byte")? & 0xFF)
+    }
+
+    fn nibble_value(&self, expression: &Expression) -> Result<u16, AssemblerError> {
+        self.evaluate_in_range(expression, 0, 0xF, "nibble")
+    }
+
+    /// the instruction of `mnemonic` with `operands`
+    fn encode_instruction(
+        &self,
+        mnemonic: &str,
+        operands: &[Operand],
+        location: &Location,
+    ) -> Result<Op, AssemblerError> {
+        use Operand::*;
+
+        let op = match (mnemonic, operands) {
+            ("CLS", []) => Op::Cls,
+            ("RET", []) => Op::Ret,
+            ("SCR", []) => Op::Scr,
+            ("SCL", []) => Op::Scl,
+            ("EXIT", []) => Op::Exit,
+            ("LOW", []) => Op::Low,
+            ("HIGH", []) => Op::High,
+            ("AUDIO", []) => Op::Audio,
+            ("SCD", [Value(n)]) => Op::Scd(self.nibble_value(n)? as u8),
+            ("SCU", [Value(n)]) => Op::Scu(self.nibble_value(n)? as u8),
+            ("SYS", [Value(addr)]) => Op::Sys(self.address_value(addr)?),
+            ("JP", [Value(addr)]) | ("JMP", [Value(addr)]) => Op::Jp(self.address_value(addr)?),
+            ("JP", [Register(0), Value(addr)]) | ("JMP", [Register(0), Value(addr)]) => {
+                Op::JpV0(self.address_value(addr)?)
+            }
+            ("CALL", [Value(addr)]) => Op::Call(self.address_value(addr)?),
+            ("SE", [Register(vx), Register(vy)]) => Op::SeReg(*vx, *vy),
+            ("SE", [Register(vx), Value(byte)]) => Op::Se(*vx, self.byte_value(byte)? as u8),
+            ("SNE", [Register(vx), Register(vy)]) => Op::SneReg(*vx, *vy),
+            ("SNE", [Register(vx), Value(byte)]) => Op::Sne(*vx, self.byte_value(byte)? as u8),
+            ("SAVE", [Register(vx), Register(vy)]) => Op::Save(*vx, *vy),
+            ("LOAD", [Register(vx), Register(vy)]) => Op::Load(*vx, *vy),
+            ("LD", [Register(vx), Register(vy)]) => Op::LdReg(*vx, *vy),
+            ("LD", [Register(vx), Value(byte)]) => Op::Ld(*vx, self.byte_value(byte)? as u8),
+            ("LD", [I, Long(addr)]) => {
+                Op::LdILong(self.evaluate_in_range(addr, 0, 0xFFFF, "address")?)
+            }
+            ("LD", [I, Value(addr)]) if is_long_load(mnemonic, operands) => {
+                Op::LdILong(self.evaluate_in_range(addr, 0, 0xFFFF, "address")?)
+            }
+            ("LD", [I, Value(addr)]) => {
+                // the size of the instruction is chosen before the labels are known
+                let value = self.evaluate(addr, 0)?;
+                if value > 0xFFF && value <= 0xFFFF {
+                    return addr.location.error(format!(
+                        "address 0x{:X} does not fit in 12 bits, use `LD I, long`",
+                        value
+                    ));
+                }
+                Op::LdI(self.address_value(addr)?)
+            }
+            ("LD", [Register(vx), DT]) => Op::LdVxDt(*vx),
+            ("LD", [Register(vx), K]) => Op::LdVxK(*vx),
+            ("LD", [DT, Register(vx)]) => Op::LdDtVx(*vx),
+            ("LD", [ST, Register(vx)]) => Op::LdStVx(*vx),
+            ("LD", [F, Register(vx)]) => Op::LdF(*vx),
+            ("LD", [HF, Register(vx)]) => Op::LdHf(*vx),
+            ("LD", [B, Register(vx)]) => Op::LdB(*vx),
+            ("LD", [IndirectI, Register(vx)]) => Op::LdMemVx(*vx),
+            ("LD", [Register(vx), IndirectI]) => Op::LdVxMem(*vx),
+            ("LD", [R, Register(vx)]) => Op::LdRVx(*vx),
+            ("LD", [Register(vx), R]) => Op::LdVxR(*vx),
+            ("ADD", [Register(vx), Register(vy)]) => Op::AddReg(*vx, *vy),
+            ("ADD", [Register(vx), Value(byte)]) => Op::Add(*vx, self.byte_value(byte)? as u8),
+            ("ADD", [I, Register(vx)]) => Op::AddI(*vx),
+            ("OR", [Register(vx), Register(vy)]) => Op::Or(*vx, *vy),
+            ("AND", [Register(vx), Register(vy)]) => Op::And(*vx, *vy),
+            ("XOR", [Register(vx), Register(vy)]) => Op::Xor(*vx, *vy),
+            ("SUB", [Register(vx), Register(vy)]) => Op::Sub(*vx, *vy),
+            ("SHR", [Register(vx)]) => Op::Shr(*vx, 0),
+            ("SHR", [Register(vx), Register(vy)]) => Op::Shr(*vx, *vy),
+            ("SUBN", [Register(vx), Register(vy)]) => Op::Subn(*vx, *vy),
+            ("SHL", [Register(vx)]) => Op::Shl(*vx, 0),
+            ("SHL", [Register(vx), Register(vy)]) => Op::Shl(*vx, *vy),
+            ("RND", [Register(vx), Value(byte)]) => Op::Rnd(*vx, self.byte_value(byte)? as u8),
+            ("DRW", [Register(vx), Register(vy), Value(n)]) => {
+                Op::Drw(*vx, *vy, self.nibble_value(n)? as u8)
+            }
+            ("SKP", [Register(vx)]) => Op::Skp(*vx),
+            ("SKNP", [Register(vx)]) => Op::Sknp(*vx),
+            ("PLANE", [Value(n)]) => Op::Plane(self.nibble_value(n)? as u8),
+            ("PITCH", [Register(vx)]) => Op::Pitch(*vx),
+            _ => {
+                return location.error(format!("invalid operands for `{}`", mnemonic));
+            }
+        };
+
+        Ok(op)
+    }
+
+    /// the second pass, encode all the items
+    fn encode(&self) -> Result<Vec<u8>, AssemblerError> {
+        let mut result = Vec::new();
+
+        for item in self.items.iter() {
+            match item {
+                Item::Instruction {
+                    mnemonic,
+                    operands,
+                    location,
+                } => {
+                    for word in self
+                        .encode_instruction(mnemonic, operands, location)?
+                        .encode()
+                    {
+                        result.extend_from_slice(&word.to_be_bytes());
+                    }
+                }
+                Item::Bytes(values) => {
+                    for value in values {
+                        result.push(self.byte_value(value)? as u8);
+                    }
+                }
+                Item::Words(values) => {
+                    for value in values {
+                        let word = self.evaluate_in_range(value, -0x8000, 0xFFFF, "word")?;
+                        result.extend_from_slice(&word.to_be_bytes());
+                    }
+                }
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// assemble `source` into a rom that is loaded at `PROGRAM_START` (0x200),
+/// `file` is the name used in errors and `include` paths are relative to it.
+///
+/// The syntax is the one of the disassembler, one instruction per line,
+/// `label:` before an instruction, `name = value` constants, `db`/`dw`
+/// directives, `include "file"`, and `;` comments
+pub fn assemble(source: &str, file: &str) -> Result<Vec<u8>, AssemblerError> {
+    let mut assembler = Assembler {
+        items: Vec::new(),
+        symbols: HashMap::new(),
+        address: PROGRAM_START as u32,
+    };
+
+    assembler.parse_source(source, file, 0)?;
+    assembler.encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn assert_error(source: &str, line: usize, column: usize, message: &str) {
+        let error = assemble(source, "test.asm").unwrap_err();
+        assert_eq!(
+            (error.line, error.column, error.message.as_str()),
+            (line, column, message)
+        );
+        assert_eq!(error.file, "test.asm");
+    }
+
+    #[test]
+    fn assembles_instructions() {
+        let rom = assemble(
+            "CLS\n  LD V1, 0x05 ; comment\nDRW V0, V1, 0b101\nld i, long 0x1234\nSHR VA",
+            "test.asm",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            [0x00, 0xE0, 0x61, 0x05, 0xD0, 0x15, 0xF0, 0x00, 0x12, 0x34, 0x8A, 0x06]
+        );
+    }
+
+    #[test]
+    fn labels_can_be_used_before_they_are_defined() {
+        let rom = assemble(
+            "start:\n    CALL sub\n    JP start\nsub:\n    LD I, data + 1\n    RET\ndata: db 1, 2\n",
+            "test.asm",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            [0x22, 0x04, 0x12, 0x00, 0xA2, 0x09, 0x00, 0xEE, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn constants_refer_to_labels_and_constants() {
+        let rom = assemble(
+            "HEIGHT = SIZE - 1\nSIZE = 6\nLD V0, HEIGHT\nLD V1, -1\nJP end\nend = here\nhere: dw here, -2",
+            "test.asm",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            [0x60, 0x05, 0x61, 0xFF, 0x12, 0x06, 0x02, 0x06, 0xFF, 0xFE]
+        );
+
+        assert_error(
+            "X = Y\nY = X\nLD V0, X",
+            1,
+            5,
+            "constants refer to each other in a loop",
+        );
+    }
+
+    #[test]
+    fn long_loads() {
+        // numbers past 0xFFF are long loads, labels only with `long`
+        let rom = assemble("LD I, 0x1200\nLD I, long far\nfar = 0x200", "test.asm").unwrap();
+        assert_eq!(rom, [0xF0, 0x00, 0x12, 0x00, 0xF0, 0x00, 0x02, 0x00]);
+
+        assert_error(
+            "LD I, far\nfar = 0x1200",
+            1,
+            7,
+            "address 0x1200 does not fit in 12 bits, use `LD I, long`",
+        );
+        assert_error("LD I, long 0x10000", 1, 12, "invalid number `0x10000`");
+    }
+
+    #[test]
+    fn errors_have_the_line_and_column() {
+        assert_error("CLS\n  JP plyer\n", 2, 6, "undefined label `plyer`");
+        assert_error("  FOO V0", 1, 3, "unknown instruction `FOO`");
+        assert_error("LD V0, 0x100", 1, 8, "byte 0x100 out of range");
+        assert_error("LD V0 V1", 1, 7, "expected `,`");
+        assert_error("DRW V0, V1, 16", 1, 13, "nibble 0x10 out of range");
+        assert_error("SE V0, I", 1, 1, "invalid operands for `SE`");
+        assert_error("a:\nCLS\na: RET", 3, 1, "`a` is already defined at 1:1");
+        assert_error("v3: CLS", 1, 1, "`v3` is a reserved name");
+        assert_error("LD V0, 1 $", 1, 10, "unexpected character `$`");
+        assert_error("include \"missing", 1, 9, "unterminated string");
+        assert_error("db 0x1G", 1, 4, "invalid number `0x1G`");
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_file() {
+        let directory = env::temp_dir().join(format!("chip_8_asm_test_{}", std::process::id()));
+        fs::create_dir_all(directory.join("lib")).unwrap();
+        fs::write(
+            directory.join("lib/sprites.asm"),
+            "include \"size.asm\"\nsprite: db 0xF0, SIZE",
+        )
+        .unwrap();
+        fs::write(directory.join("lib/size.asm"), "SIZE = 5").unwrap();
+        fs::write(directory.join("loop.asm"), "include \"loop.asm\"").unwrap();
+        fs::write(directory.join("bad.asm"), "CLS\nJP nowhere").unwrap();
+
+        let main = directory.join("main.asm");
+        let main = main.to_str().unwrap();
+        let rom = assemble("LD I, sprite\ninclude \"lib/sprites.asm\"", main);
+
+        let looped = assemble("include \"loop.asm\"", main).unwrap_err();
+        let bad = assemble("CLS\ninclude \"bad.asm\"", main).unwrap_err();
+        let missing = assemble("include \"missing.asm\"", main).unwrap_err();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(rom.unwrap(), [0xA2, 0x02, 0xF0, 0x05]);
+        assert_eq!(looped.message, "too many nested includes");
+        // errors in included files have their name
+        assert!(bad.file.ends_with("bad.asm"));
+        assert_eq!((bad.line, bad.column), (2, 4));
+        assert_eq!((missing.line, missing.column), (1, 9));
+        assert!(missing.message.starts_with("could not read"));
+    }
+}
@@ -0,0 +1,189 @@
+use super::state::{SaveStateError, StateReader, StateWriter};
+
+/// The size of the XO-CHIP audio pattern buffer in bytes (128 1-bit samples)
+pub const PATTERN_SIZE: usize = 16;
+/// The pitch at which the pattern is played at 4000 samples per second
+pub const DEFAULT_PITCH: u8 = 64;
+/// The pattern played by roms that do not load their own (with `AUDIO`),
+/// a square wave of 500Hz at the default pitch
+pub const DEFAULT_PATTERN: [u8; PATTERN_SIZE] = [0xF0; PATTERN_SIZE];
+
+// the volume of the rendered samples, they are in the range [-AMPLITUDE, AMPLITUDE]
+const AMPLITUDE: f32 = 0.25;
+
+/// The XO-CHIP audio state of the CPU, a pattern of 128 1-bit samples that
+/// is played in a loop while ST is not zero, at a rate controlled by the pitch
+#[derive(Clone)]
+pub struct Audio {
+    pattern: [u8; PATTERN_SIZE], // loaded from memory with `AUDIO` (F002)
+    pitch: u8,                   // set with `PITCH Vx` (Fx3A)
+}
+
+impl Default for Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Audio {
+    pub fn new() -> Audio {
+        Audio {
+            pattern: DEFAULT_PATTERN,
+            pitch: DEFAULT_PITCH,
+        }
+    }
+
+    pub fn set_pattern(&mut self, pattern: &[u8]) {
+        self.pattern.clone_from_slice(pattern);
+    }
+
+    pub fn get_pattern(&self) -> &[u8; PATTERN_SIZE] {
+        &self.pattern
+    }
+
+    pub fn set_pitch(&mut self, pitch: u8) {
+        self.pitch = pitch;
+    }
+
+    pub fn get_pitch(&self) -> u8 {
+        self.pitch
+    }
+
+    /// write the pattern and the pitch to a save state
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.pattern);
+        writer.write_u8(self.pitch);
+    }
+
+    /// read the pattern and the pitch from a save state
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.set_pattern(reader.read_bytes(PATTERN_SIZE)?);
+        self.pitch = reader.read_u8()?;
+        Ok(())
+    }
+
+    /// the number of pattern bits played per second
+    pub fn get_playback_rate(&self) -> f64 {
+        4000. * 2f64.powf((self.pitch as f64 - 64.) / 48.)
+    }
+}
+
+/// Converts the audio state of the CPU to samples, used by the sound output
+/// of the frontends and to render the sound offline
+pub struct AudioRenderer {
+    audio: Audio,
+    playing: bool,
+    sample_rate: u32,
+    position: f64, // the current position in the pattern in bits
+}
+
+impl AudioRenderer {
+    pub fn new(sample_rate: u32) -> AudioRenderer {
+        AudioRenderer {
+            audio: Audio::new(),
+            playing: false,
+            sample_rate,
+            position: 0.,
+        }
+    }
+
+    pub fn get_sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// copy the audio state of the CPU, `playing` should be true while ST is not zero
+    pub fn update(&mut self, audio: &Audio, playing: bool) {
+        self.audio = audio.clone();
+        self.playing = playing;
+    }
+
+    /// fill `output` with the next samples, silence if not playing
+    pub fn render(&mut self, output: &mut [f32]) {
+        if !self.playing {
+            for sample in output.iter_mut() {
+                *sample = 0.;
+            }
+            return;
+        }
+
+        let pattern_bits = (PATTERN_SIZE * 8) as f64;
+        let step = self.audio.get_playback_rate() / self.sample_rate as f64;
+        let pattern = self.audio.get_pattern();
+
+        for sample in output.iter_mut() {
+            let bit = self.position as usize;
+            let value = pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+            *sample = if value { AMPLITUDE } else { -AMPLITUDE };
+
+            self.position = (self.position + step) % pattern_bits;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_is_silent_when_not_playing() {
+        let mut renderer = AudioRenderer::new(4000);
+        let mut output = [1.; 32];
+        renderer.render(&mut output);
+        assert!(output.iter().all(|&sample| sample == 0.));
+    }
+
+    #[test]
+    fn render_plays_one_bit_per_sample_at_the_default_pitch() {
+        let mut audio = Audio::new();
+        let mut pattern = [0; PATTERN_SIZE];
+        pattern[0] = 0b1010_0011;
+        pattern[PATTERN_SIZE - 1] = 0b0000_0001;
+        audio.set_pattern(&pattern);
+
+        let mut renderer = AudioRenderer::new(4000);
+        renderer.update(&audio, true);
+        let mut output = [0.; PATTERN_SIZE * 8 + 8];
+        renderer.render(&mut output);
+
+        let bits: Vec<bool> = output.iter().map(|&sample| sample > 0.).collect();
+        let expected = [true, false, true, false, false, false, true, true];
+        assert_eq!(bits[..8], expected);
+        assert!(bits[8..PATTERN_SIZE * 8 - 1].iter().all(|&bit| !bit));
+        assert!(bits[PATTERN_SIZE * 8 - 1]);
+        // the pattern loops
+        assert_eq!(bits[PATTERN_SIZE * 8..], expected);
+        assert!(output
+            .iter()
+            .all(|&sample| sample == AMPLITUDE || sample == -AMPLITUDE));
+    }
+
+    #[test]
+    fn render_follows_the_pitch() {
+        let mut audio = Audio::new();
+        audio.set_pattern(&[0b1100_1100; PATTERN_SIZE]);
+        // 48 more than the default doubles the playback rate
+        audio.set_pitch(DEFAULT_PITCH + 48);
+        assert_eq!(audio.get_playback_rate(), 8000.);
+
+        let mut renderer = AudioRenderer::new(4000);
+        renderer.update(&audio, true);
+        let mut output = [0.; 8];
+        renderer.render(&mut output);
+
+        // every other bit is played
+        let bits: Vec<bool> = output.iter().map(|&sample| sample > 0.).collect();
+        assert_eq!(bits, [true, false, true, false, true, false, true, false]);
+    }
+
+    #[test]
+    fn default_pattern_is_a_500hz_square_wave() {
+        let mut renderer = AudioRenderer::new(8000);
+        renderer.update(&Audio::new(), true);
+        let mut output = [0.; 16];
+        renderer.render(&mut output);
+
+        // 8 samples high and 8 low, 16 samples per period at 8000Hz
+        assert!(output[..8].iter().all(|&sample| sample == AMPLITUDE));
+        assert!(output[8..].iter().all(|&sample| sample == -AMPLITUDE));
+    }
+}
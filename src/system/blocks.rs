@@ -0,0 +1,435 @@
+use super::cpu::CPU;
+use super::error::EmulationError;
+use super::memory::{Memory, MEMORY_SIZE};
+use super::op::Op;
+use std::rc::Rc;
+
+/// The maximum number of instructions in one block
+pub const MAX_BLOCK_INSTRUCTIONS: usize = 64;
+// the longest instruction is `LD I, long addr`
+const MAX_BLOCK_BYTES: u32 = MAX_BLOCK_INSTRUCTIONS as u32 * 4;
+
+/// An instruction compiled to a closure with its operands already bound
+pub type Handler = Box<dyn Fn(&mut CPU) -> Result<(), EmulationError>>;
+
+pub struct BlockInstruction {
+    pub address: u16, // the address of the instruction
+    pub next: u16,    // the address after the instruction, PC during its execution
+    pub handler: Handler,
+}
+
+/// A basic block, instructions that always run one after the other,
+/// only the last one can change the flow of the program
+pub struct Block {
+    pub instructions: Vec<BlockInstruction>,
+    end: u32, // the address after the last byte of the block
+}
+
+impl Block {
+    pub fn new(instructions: Vec<BlockInstruction>) -> Block {
+        let end = instructions.last().map_or(0, |last| last.next as u32);
+
+        Block { instructions, end }
+    }
+
+    /// the address of the first instruction
+    pub fn get_start(&self) -> u16 {
+        self.instructions.first().map_or(0, |first| first.address)
+    }
+}
+
+/// The compiled blocks, by the address of their first instruction
+pub struct BlockCache {
+    blocks: Vec<Option<Rc<Block>>>, // the block starting at each address
+    count: usize,                   // the number of blocks in `blocks`
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockCache {
+    pub fn new() -> BlockCache {
+        BlockCache {
+            blocks: vec![None; MEMORY_SIZE],
+            count: 0,
+        }
+    }
+
+    pub fn get(&self, start: u16) -> Option<Rc<Block>> {
+        self.blocks[start as usize].clone()
+    }
+
+    pub fn insert(&mut self, block: Block) -> Rc<Block> {
+        let block = Rc::new(block);
+        let entry = &mut self.blocks[block.get_start() as usize];
+        if entry.is_none() {
+            self.count += 1;
+        }
+        *entry = Some(block.clone());
+        block
+    }
+
+    /// remove the blocks that contain the byte at `addr`, after it was written
+    pub fn invalidate(&mut self, addr: u16) {
+        let first = (addr as u32).saturating_sub(MAX_BLOCK_BYTES - 1) as usize;
+        for entry in &mut self.blocks[first..=addr as usize] {
+            if entry
+                .as_ref()
+                .is_some_and(|block| (addr as u32) < block.end)
+            {
+                *entry = None;
+                self.count -= 1;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        if self.count > 0 {
+            self.blocks.iter_mut().for_each(|entry| *entry = None);
+            self.count = 0;
+        }
+    }
+
+    /// the number of compiled blocks
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+/// does the block end after `op`, because it changes the flow of the program,
+/// makes the CPU wait or writes to memory (which can change the block itself)
+pub fn ends_block(op: &Op) -> bool {
+    op.is_skip()
+        || matches!(
+            op,
+            Op::Ret
+                | Op::Sys(_)
+                | Op::Exit
+                | Op::Jp(_)
+                | Op::Call(_)
+                | Op::JpV0(_)
+                | Op::Drw(..)
+                | Op::LdVxK(_)
+                | Op::Save(..)
+                | Op::LdB(_)
+                | Op::LdMemVx(_)
+        )
+}
+
+/// decode the block starting at `start` with the addresses of its instructions.
+///
+/// It stops before invalid instructions and the ones that leave PC outside
+/// of memory, so it can be empty, the interpreter handles (and reports) these
+pub fn decode_block(memory: &Memory, start: u16) -> Vec<(u16, Op)> {
+    // the bytes after the end of memory are 0, the instructions using them are not kept
+    let byte = |addr: u32| memory.get_memory().get(addr as usize).copied().unwrap_or(0) as u16;
+    let word = |addr: u32| byte(addr) << 8 | byte(addr + 1);
+
+    let mut result = Vec::new();
+    let mut address = start as u32;
+
+    while result.len() < MAX_BLOCK_INSTRUCTIONS {
+        let op = match Op::decode(word(address), word(address + 2)) {
+            Some(op) => op,
+            None => break,
+        };
+
+        let next = address + op.length() as u32;
+        if next >= MEMORY_SIZE as u32 {
+            break;
+        }
+
+        result.push((address as u16, op));
+        address = next;
+
+        if ends_block(&op) {
+            break;
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::system::assembler::assemble;
+    use crate::system::cpu::{Backend, CPU};
+    use crate::system::error::EmulationError;
+    use crate::system::quirks::{Quirks, PRESET_NAMES};
+
+    // the frames every rom is run for, their length, and how often the states are compared
+    const FRAMES: u32 = 60;
+    const INSTRUCTIONS_PER_FRAME: u32 = 100;
+    const COMPARED_FRAMES: u32 = 10;
+
+    /// the backends (and the decode cache) that must give the same results
+    const CONFIGURATIONS: [(Backend, bool); 3] = [
+        (Backend::Interpreter, false),
+        (Backend::Interpreter, true),
+        (Backend::Blocks, true),
+    ];
+
+    /// uses most of the instructions, in subroutines, loops, skips and a jump table
+    const INSTRUCTIONS_SOURCE: &str = "
+            LD V0, 5
+            LD V1, 7
+            LD VE, 0
+        main:
+            RND V2, 0xFF
+            ADD V0, V2
+            SUB V0, V1
+            SUBN V1, V0
+            SHR V3, V0
+            SHL V4, V1
+            OR V5, V4
+            AND V5, V3
+            XOR V6, V5
+            ADD V7, V6
+            SE V7, 0x10
+            ADD V8, 1
+            SNE V8, V0
+            CALL sub
+            LD DT, V8
+            LD V9, DT
+            LD ST, V9
+            LD F, V0
+            DRW V0, V1, 5
+            LD HF, V1
+            DRW V1, V0, 10
+            SKP V2
+            ADD VE, 1
+            SKNP V3
+            ADD VE, 2
+            LD I, buffer
+            LD B, V7
+            LD [I], V3
+            LD V3, [I]
+            ADD I, V0
+            LD VA, 1
+            AND VA, V2
+            ADD VA, VA
+            JP V0, table
+        table:
+            JP main
+            JP wait
+        wait:
+            LD VB, K
+            JP main
+        sub:
+            SE VE, V8
+            CALL sub2
+            RET
+        sub2:
+            LD R, V3
+            LD V3, R
+            RET
+        buffer:
+            db 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+    ";
+
+    /// changes its own instructions, after they were run (and compiled)
+    const SELF_MODIFYING_SOURCE: &str = "
+        loop:
+            CALL patch
+            ; add 3 to the immediate of `ADD V5, 1`
+            LD I, patch
+            LD V1, [I]
+            ADD V1, 3
+            LD I, patch
+            LD [I], V1
+            ; replace the instruction after this block with `ADD V6, 0x50`
+            LD I, next
+            LD V0, 0x76
+            LD V1, 0x50
+            LD [I], V1
+        next:
+            ADD V7, 1
+            ADD V8, 1
+            ADD V9, V8
+            JP loop
+        patch:
+            ADD V5, 1
+            ADD V6, V5
+            RET
+    ";
+
+    /// the SUPER-CHIP and XO-CHIP instructions
+    const EXTENDED_SOURCE: &str = "
+            HIGH
+            LD I, long sprite
+            LD V0, 3
+            PLANE 3
+        loop:
+            DRW V0, V1, 0
+            SCD 2
+            SCU 1
+            SCR
+            SCL
+            ADD V0, 7
+            ADD V1, 3
+            SAVE V0, V3
+            LOAD V4, V7
+            LD I, long sprite
+            AUDIO
+            PITCH V0
+            SE V0, 0xF6
+            JP loop
+            LOW
+            PLANE 1
+            DRW V0, V1, 3
+            EXIT
+        sprite:
+            dw 0xFFFF, 0x8001, 0x8181, 0xFFFF, 0x1234, 0x5678, 0x9ABC, 0xDEF0
+            dw 0xFFFF, 0x8001, 0x8181, 0xFFFF, 0x1234, 0x5678, 0x9ABC, 0xDEF0
+    ";
+
+    /// bytes of a simple random generator, mostly valid instructions
+    fn random_rom(seed: u32, length: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn roms() -> Vec<Vec<u8>> {
+        let mut roms: Vec<Vec<u8>> = [INSTRUCTIONS_SOURCE, SELF_MODIFYING_SOURCE, EXTENDED_SOURCE]
+            .iter()
+            .map(|source| assemble(source, "test.asm").unwrap())
+            .collect();
+        roms.extend((1..=8).map(|seed| random_rom(seed, 256)));
+        roms
+    }
+
+    fn new_cpu(backend: Backend, decode_cache: bool, quirks: Quirks) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.set_seed(0);
+        cpu.set_backend(backend);
+        cpu.set_decode_cache_enabled(decode_cache);
+        cpu.set_quirks(quirks);
+        cpu.set_instructions_per_frame(INSTRUCTIONS_PER_FRAME);
+        cpu
+    }
+
+    /// run the frames, pressing the keys one after the other, returns the
+    /// state after every `COMPARED_FRAMES` frames and the last one, and the
+    /// error that stopped it, if any
+    fn run(cpu: &mut CPU, frames: u32) -> (Vec<Vec<u8>>, Option<EmulationError>) {
+        let mut states = Vec::new();
+        for frame in 0..frames {
+            let mut keyboard = [false; 16];
+            keyboard[(frame / 2 % 16) as usize] = frame % 2 == 0;
+            cpu.set_keyboard(keyboard);
+
+            let result = cpu.run_frame();
+            if result.is_err() || frame % COMPARED_FRAMES == 0 || frame == frames - 1 {
+                states.push(cpu.save_state());
+            }
+            if let Err(error) = result {
+                return (states, Some(error));
+            }
+        }
+        (states, None)
+    }
+
+    fn assert_same_runs(runs: &[(Vec<Vec<u8>>, Option<EmulationError>)], rom: usize, quirks: &str) {
+        let (expected_states, expected_error) = &runs[0];
+        for (configuration, (states, error)) in CONFIGURATIONS.iter().zip(runs).skip(1) {
+            assert_eq!(
+                error, expected_error,
+                "rom {} with {} quirks and {:?}",
+                rom, quirks, configuration
+            );
+            assert_eq!(states.len(), expected_states.len());
+            for (frame, (state, expected)) in states.iter().zip(expected_states).enumerate() {
+                assert!(
+                    state == expected,
+                    "rom {} with {} quirks and {:?} differs at frame {}",
+                    rom,
+                    quirks,
+                    configuration,
+                    frame
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn backends_give_identical_results() {
+        for (index, rom) in roms().iter().enumerate() {
+            for &name in PRESET_NAMES.iter() {
+                let quirks = Quirks::from_name(name).unwrap();
+                let runs: Vec<_> = CONFIGURATIONS
+                    .iter()
+                    .map(|&(backend, decode_cache)| {
+                        let mut cpu = new_cpu(backend, decode_cache, quirks);
+                        cpu.read_file(&mut &rom[..]);
+                        run(&mut cpu, FRAMES)
+                    })
+                    .collect();
+
+                assert_same_runs(&runs, index, name);
+            }
+        }
+    }
+
+    #[test]
+    fn self_modifying_code_is_run_again() {
+        let rom = assemble(SELF_MODIFYING_SOURCE, "test.asm").unwrap();
+        let mut cpu = new_cpu(Backend::Blocks, true, Quirks::default());
+        cpu.read_file(&mut &rom[..]);
+        // 17 instructions in every loop
+        let loops = 50;
+        cpu.run_cycles(17 * loops).unwrap();
+        assert_eq!(cpu.get_v()[8] as u32, loops);
+
+        // the immediate grows by 3 on every loop, V5 is the sum of 1, 4, 7, ...
+        let sum: u32 = (0..loops).map(|i| 1 + 3 * i).sum();
+        assert_eq!(cpu.get_v()[5] as u32, sum & 0xFF);
+        // `ADD V7, 1` is replaced by `ADD V6, 0x50` before it runs
+        assert_eq!(cpu.get_v()[7], 0);
+    }
+
+    #[test]
+    fn backends_give_identical_results_after_loading_a_state() {
+        let roms = roms();
+        let quirks = Quirks::default();
+
+        // the state of the second rom, in the middle of its run
+        let mut other = new_cpu(Backend::Interpreter, false, quirks);
+        other.read_file(&mut &roms[1][..]);
+        run(&mut other, 10);
+        let other_state = other.save_state();
+
+        let runs: Vec<_> = CONFIGURATIONS
+            .iter()
+            .map(|&(backend, decode_cache)| {
+                let mut cpu = new_cpu(backend, decode_cache, quirks);
+                cpu.read_file(&mut &roms[0][..]);
+                let (mut states, _) = run(&mut cpu, 20);
+                let state = cpu.save_state();
+
+                // another program, where the blocks of the first one are compiled
+                cpu.load_state(&other_state).unwrap();
+                states.extend(run(&mut cpu, 20).0);
+
+                // back to the first one
+                cpu.load_state(&state).unwrap();
+                let (rest, error) = run(&mut cpu, 20);
+                states.extend(rest);
+                (states, error)
+            })
+            .collect();
+
+        assert_same_runs(&runs, 0, "modern");
+    }
+}
@@ -0,0 +1,524 @@
+use super::cpu::CPU;
+use super::op::Reg;
+use super::symbols::Symbols;
+use std::fmt;
+
+/// A value compared in the condition of a breakpoint, the registers can
+/// also be changed with `set_value`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    V(Reg),
+    I,
+    DT,
+    ST,
+    SP,
+    PC,
+    Value(u16),
+}
+
+impl Operand {
+    /// parse a register name (`V0`..`VF`, `I`, `DT`, `ST`, `SP`, `PC`), a number
+    /// or the name of a symbol
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Operand, String> {
+        let operand = match text.to_ascii_uppercase().as_str() {
+            "I" => Operand::I,
+            "DT" => Operand::DT,
+            "ST" => Operand::ST,
+            "SP" => Operand::SP,
+            "PC" => Operand::PC,
+            name if name.len() == 2 && name.starts_with('V') => {
+                match u8::from_str_radix(&name[1..], 16) {
+                    Ok(x) => Operand::V(x),
+                    Err(_) => return Err(format!("unknown register `{}`", text)),
+                }
+            }
+            _ => match symbols.parse_address(text) {
+                Some(value) => Operand::Value(value),
+                None => return Err(format!("invalid value `{}`", text)),
+            },
+        };
+
+        Ok(operand)
+    }
+
+    pub fn get_value(&self, cpu: &CPU) -> u16 {
+        match *self {
+            Operand::V(x) => cpu.get_v()[x as usize] as u16,
+            Operand::I => cpu.get_i(),
+            Operand::DT => cpu.get_dt() as u16,
+            Operand::ST => cpu.get_st() as u16,
+            Operand::SP => cpu.get_sp() as u16,
+            Operand::PC => cpu.get_pc(),
+            Operand::Value(value) => value,
+        }
+    }
+
+    /// set the register to `value`, fails for numbers and values that do not fit in the register
+    pub fn set_value(&self, cpu: &mut CPU, value: u16) -> Result<(), String> {
+        let max = match *self {
+            Operand::V(_) | Operand::DT | Operand::ST => 0xFF,
+            Operand::SP => cpu.get_stack_size() as u16,
+            Operand::I | Operand::PC => 0xFFFF,
+            Operand::Value(_) => return Err(format!("`{}` is not a register", self)),
+        };
+        if value > max {
+            return Err(format!(
+                "0x{:X} does not fit in {} (max 0x{:X})",
+                value, self, max
+            ));
+        }
+
+        match *self {
+            Operand::V(x) => cpu.set_v(x, value as u8),
+            Operand::I => cpu.set_i(value),
+            Operand::DT => cpu.set_dt(value as u8),
+            Operand::ST => cpu.set_st(value as u8),
+            Operand::SP => cpu.set_sp(value as u8),
+            Operand::PC => cpu.set_pc(value),
+            Operand::Value(_) => unreachable!(),
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Operand::V(x) => write!(f, "V{:X}", x),
+            Operand::I => write!(f, "I"),
+            Operand::DT => write!(f, "DT"),
+            Operand::ST => write!(f, "ST"),
+            Operand::SP => write!(f, "SP"),
+            Operand::PC => write!(f, "PC"),
+            Operand::Value(value) => write!(f, "0x{:02X}", value),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+// the two characters operators first, so `<=` is not found as `<`
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Equal),
+    ("!=", Comparison::NotEqual),
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+];
+
+/// The condition of a breakpoint, like `V3 == 0x10` or `I >= 0x300`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Condition {
+    pub left: Operand,
+    pub comparison: Comparison,
+    pub right: Operand,
+}
+
+impl Condition {
+    /// parse `<operand> <comparison> <operand>`, the operands are registers
+    /// (`V0`..`VF`, `I`, `DT`, `ST`, `SP`, `PC`) or numbers, the comparisons
+    /// are `==`, `!=`, `<`, `<=`, `>` and `>=`
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Condition, String> {
+        let text = text.trim();
+        for &(symbol, comparison) in COMPARISONS.iter() {
+            if let Some(position) = text.find(symbol) {
+                return Ok(Condition {
+                    left: Operand::parse(text[..position].trim(), symbols)?,
+                    comparison,
+                    right: Operand::parse(text[position + symbol.len()..].trim(), symbols)?,
+                });
+            }
+        }
+
+        Err(format!("no comparison in the condition `{}`", text))
+    }
+
+    pub fn evaluate(&self, cpu: &CPU) -> bool {
+        let left = self.left.get_value(cpu);
+        let right = self.right.get_value(cpu);
+
+        match self.comparison {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = COMPARISONS
+            .iter()
+            .find(|(_, comparison)| *comparison == self.comparison)
+            .map(|(symbol, _)| *symbol)
+            .unwrap();
+
+        write!(f, "{} {} {}", self.left, symbol, self.right)
+    }
+}
+
+/// Stops running before the instruction at `address` is executed
+#[derive(Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub address: u16,
+    /// only stop when the condition is true
+    pub condition: Option<Condition>,
+    /// the number of hits to ignore before stopping
+    pub ignore_count: u32,
+    /// the number of times the instruction was reached with the condition true
+    pub hits: u32,
+}
+
+impl Breakpoint {
+    pub fn new(address: u16) -> Breakpoint {
+        Breakpoint {
+            address,
+            condition: None,
+            ignore_count: 0,
+            hits: 0,
+        }
+    }
+
+    /// parse `<address> [if <condition>] [after <count>]`, for example
+    /// `0x2A4`, `0x2A4 if V3 == 0x10` or `0x2A4 after 5`, the addresses and
+    /// the values can also be the names of symbols
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Breakpoint, String> {
+        let text = text.trim();
+        let (address, mut rest) =
+            text.split_at(text.find(char::is_whitespace).unwrap_or(text.len()));
+
+        let mut breakpoint = match symbols.parse_address(address) {
+            Some(address) => Breakpoint::new(address),
+            None => return Err(format!("invalid address `{}`", address)),
+        };
+
+        rest = rest.trim();
+        // the last `after`, the names of symbols in the condition can contain it
+        if let Some(position) = rest
+            .rfind("after")
+            .filter(|&position| position == 0 || rest[..position].ends_with(char::is_whitespace))
+        {
+            let count = rest[position + "after".len()..].trim();
+            breakpoint.ignore_count = count
+                .parse()
+                .map_err(|_| format!("invalid count `{}`", count))?;
+            rest = rest[..position].trim();
+        }
+
+        if let Some(condition) = rest.strip_prefix("if") {
+            breakpoint.condition = Some(Condition::parse(condition, symbols)?);
+        } else if !rest.is_empty() {
+            return Err(format!("unexpected `{}` after the address", rest));
+        }
+
+        Ok(breakpoint)
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:03X}", self.address)?;
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        if self.ignore_count > 0 {
+            write!(f, " after {}", self.ignore_count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the CPU stops running, for the step over, step out and run to
+/// commands of the debuggers (see `CPU::run_to`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunTarget {
+    /// the instruction at `address`, with at most `max_sp` entries in the stack
+    Address { address: u16, max_sp: u8 },
+    /// the first instruction with less than `sp` entries in the stack, after a `RET`
+    Return { sp: u8 },
+}
+
+/// A kind of memory access by the instructions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    /// read as data, by `DRW`, `LD Vx, [I]`, ...
+    Read,
+    /// written by `LD B, Vx`, `LD [I], Vx`, ...
+    Write,
+    /// fetched as an instruction
+    Execute,
+}
+
+impl Access {
+    /// the bit of the access in `Memory`'s table of watched bytes
+    pub fn get_bit(self) -> u8 {
+        match self {
+            Access::Read => 1,
+            Access::Write => 2,
+            Access::Execute => 4,
+        }
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "execute"),
+        }
+    }
+}
+
+/// Stops running when the instructions access the bytes from `start` to `end` (included)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Watchpoint {
+    /// a watchpoint on the writes to the bytes from `start` to `end` (included)
+    pub fn new(start: u16, end: u16) -> Watchpoint {
+        Watchpoint {
+            start,
+            end,
+            read: false,
+            write: true,
+            execute: false,
+        }
+    }
+
+    /// parse `<address>[-<end>] [r][w][x]`, the accesses are writes if not
+    /// given, for example `0x300`, `0x300-0x302 rw` or `0x2A4 x`, the addresses
+    /// can also be the names of symbols
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Watchpoint, String> {
+        let text = text.trim();
+        let (range, accesses) = text.split_at(text.find(char::is_whitespace).unwrap_or(text.len()));
+
+        let (start, end) = match range.find('-') {
+            Some(position) if symbols.get_address(range).is_none() => {
+                (&range[..position], &range[position + 1..])
+            }
+            _ => (range, range),
+        };
+        let start = symbols
+            .parse_address(start)
+            .ok_or_else(|| format!("invalid address `{}`", start))?;
+        let end = symbols
+            .parse_address(end)
+            .ok_or_else(|| format!("invalid address `{}`", end))?;
+        if end < start {
+            return Err(format!("the range `{}` ends before it starts", range));
+        }
+
+        let mut watchpoint = Watchpoint::new(start, end);
+        let accesses = accesses.trim();
+        if !accesses.is_empty() {
+            watchpoint.write = false;
+            for access in accesses.chars() {
+                match access.to_ascii_lowercase() {
+                    'r' => watchpoint.read = true,
+                    'w' => watchpoint.write = true,
+                    'x' => watchpoint.execute = true,
+                    _ => return Err(format!("unknown access `{}`, expected r, w or x", access)),
+                }
+            }
+        }
+
+        Ok(watchpoint)
+    }
+
+    pub fn is_watching(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+
+    pub fn contains(&self, addr: u16) -> bool {
+        self.start <= addr && addr <= self.end
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:03X}", self.start)?;
+        if self.end != self.start {
+            write!(f, "-0x{:03X}", self.end)?;
+        }
+        write!(f, " ")?;
+        for &(watched, letter) in &[(self.read, 'r'), (self.write, 'w'), (self.execute, 'x')] {
+            if watched {
+                write!(f, "{}", letter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::cpu::BreakReason;
+
+    fn symbols() -> Symbols {
+        Symbols::parse("loop = 0x204\nthereafter = 0x300\nsprite-end = 0x310").unwrap()
+    }
+
+    #[test]
+    fn breakpoints_are_parsed() {
+        let symbols = symbols();
+
+        assert_eq!(
+            Breakpoint::parse("0x2A4", &symbols),
+            Ok(Breakpoint::new(0x2A4))
+        );
+        assert_eq!(
+            Breakpoint::parse(" loop ", &symbols),
+            Ok(Breakpoint::new(0x204))
+        );
+
+        let breakpoint = Breakpoint::parse("loop if V3 == 0x10 after 5", &symbols).unwrap();
+        assert_eq!(breakpoint.address, 0x204);
+        assert_eq!(
+            breakpoint.condition,
+            Some(Condition {
+                left: Operand::V(3),
+                comparison: Comparison::Equal,
+                right: Operand::Value(0x10),
+            })
+        );
+        assert_eq!(breakpoint.ignore_count, 5);
+        assert_eq!(breakpoint.to_string(), "0x204 if V3 == 0x10 after 5");
+
+        // a symbol containing `after` is not the count
+        let breakpoint = Breakpoint::parse("0x200 if I >= thereafter", &symbols).unwrap();
+        assert_eq!(breakpoint.ignore_count, 0);
+        assert_eq!(breakpoint.condition.unwrap().right, Operand::Value(0x300));
+        assert_eq!(breakpoint.to_string(), "0x200 if I >= 0x300");
+    }
+
+    #[test]
+    fn invalid_breakpoints_are_rejected() {
+        let symbols = symbols();
+
+        for (text, error) in [
+            ("nowhere", "invalid address `nowhere`"),
+            ("0x200 after many", "invalid count `many`"),
+            ("0x200 if V3", "no comparison in the condition `V3`"),
+            ("0x200 if VG == 1", "unknown register `VG`"),
+            (
+                "0x200 when V3 == 1",
+                "unexpected `when V3 == 1` after the address",
+            ),
+        ] {
+            assert_eq!(Breakpoint::parse(text, &symbols), Err(error.to_owned()));
+        }
+    }
+
+    #[test]
+    fn conditions_compare_the_registers() {
+        let symbols = Symbols::new();
+        let mut cpu = CPU::new();
+        cpu.set_v(3, 0x10);
+        cpu.set_i(0x300);
+
+        let evaluate =
+            |text: &str, cpu: &CPU| Condition::parse(text, &symbols).unwrap().evaluate(cpu);
+        assert!(evaluate("V3 == 0x10", &cpu));
+        assert!(!evaluate("V3 != 16", &cpu));
+        // `<=` is not read as `<` followed by `= 0x10`
+        assert!(evaluate("V3 <= 0x10", &cpu));
+        assert!(!evaluate("V3 < 0x10", &cpu));
+        assert!(evaluate("I > V3", &cpu));
+        assert!(evaluate("0x300 >= I", &cpu));
+        assert!(evaluate("pc == 0x200", &cpu));
+
+        cpu.set_v(3, 0x11);
+        assert!(!evaluate("V3 <= 0x10", &cpu));
+    }
+
+    #[test]
+    fn breakpoints_count_the_hits_with_the_condition_true() {
+        let rom = [
+            0x70, 0x01, // ADD V0, 1
+            0x12, 0x00, // JP 0x200
+        ];
+        let mut cpu = CPU::new();
+        cpu.read_file(&mut &rom[..]);
+        cpu.add_breakpoint(Breakpoint::parse("0x202 if V0 >= 2 after 1", &Symbols::new()).unwrap());
+
+        // V0 == 2 is the ignored hit
+        cpu.run_cycles(100).unwrap();
+        assert_eq!(
+            cpu.take_break(),
+            Some(BreakReason::Breakpoint { pc: 0x202, hits: 2 })
+        );
+        assert_eq!(cpu.get_v()[0], 3);
+
+        // continuing does not stop again before the instruction runs
+        cpu.run_cycles(100).unwrap();
+        assert_eq!(
+            cpu.take_break(),
+            Some(BreakReason::Breakpoint { pc: 0x202, hits: 3 })
+        );
+        assert_eq!(cpu.get_v()[0], 4);
+        assert_eq!(cpu.get_breakpoint(0x202).unwrap().hits, 3);
+    }
+
+    #[test]
+    fn watchpoints_are_parsed() {
+        let symbols = symbols();
+
+        let watchpoint = Watchpoint::parse("0x300", &symbols).unwrap();
+        assert_eq!(watchpoint, Watchpoint::new(0x300, 0x300));
+        assert!(watchpoint.is_watching(Access::Write));
+        assert!(!watchpoint.is_watching(Access::Read));
+
+        let watchpoint = Watchpoint::parse("0x300-0x302 rX", &symbols).unwrap();
+        assert_eq!((watchpoint.start, watchpoint.end), (0x300, 0x302));
+        assert!(watchpoint.read && !watchpoint.write && watchpoint.execute);
+        assert!(watchpoint.contains(0x302) && !watchpoint.contains(0x303));
+        assert_eq!(watchpoint.to_string(), "0x300-0x302 rx");
+
+        // a symbol with a `-` is not a range
+        let watchpoint = Watchpoint::parse("sprite-end w", &symbols).unwrap();
+        assert_eq!((watchpoint.start, watchpoint.end), (0x310, 0x310));
+        let watchpoint = Watchpoint::parse("thereafter-sprite-end", &symbols).unwrap();
+        assert_eq!((watchpoint.start, watchpoint.end), (0x300, 0x310));
+    }
+
+    #[test]
+    fn invalid_watchpoints_are_rejected() {
+        let symbols = symbols();
+
+        for (text, error) in [
+            (
+                "0x302-0x300",
+                "the range `0x302-0x300` ends before it starts",
+            ),
+            ("0x300 rq", "unknown access `q`, expected r, w or x"),
+            ("0x300-", "invalid address ``"),
+        ] {
+            assert_eq!(Watchpoint::parse(text, &symbols), Err(error.to_owned()));
+        }
+    }
+}
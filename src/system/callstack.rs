@@ -0,0 +1,199 @@
+use super::cpu::CPU;
+use super::op::Op;
+use std::fmt;
+
+/// A subroutine call in the stack, found from the return address pushed by its `CALL`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    /// the address of the `CALL`, `None` if the return address does not follow
+    /// a `CALL` (the stack or the code were changed since)
+    pub call_site: Option<u16>,
+    /// the address of the called subroutine
+    pub entry: Option<u16>,
+    pub return_address: u16,
+    /// the number of calls of the same subroutine under this one in the stack,
+    /// more than 0 with recursion
+    pub recursion: usize,
+}
+
+/// A `CALL` or `RET` that does not fit the stack, found before it is executed
+/// (executing it faults with `StackOverflow` or `StackUnderflow`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StackImbalance {
+    /// the `RET` at `pc` has no call to return to
+    UnmatchedReturn { pc: u16 },
+    /// the `CALL` at `pc` is one more call than the 16 entries of the stack
+    Overflow { pc: u16 },
+}
+
+impl fmt::Display for StackImbalance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            StackImbalance::UnmatchedReturn { pc } => {
+                write!(f, "RET at {:04X} with an empty stack", pc)
+            }
+            StackImbalance::Overflow { pc } => {
+                write!(f, "CALL at {:04X} with a full stack (16 calls deep)", pc)
+            }
+        }
+    }
+}
+
+impl CPU {
+    /// the calls in the stack, the innermost (the subroutine running) first
+    pub fn get_call_stack(&self) -> Vec<Frame> {
+        let mut frames: Vec<Frame> = Vec::new();
+
+        // from the outermost, to count the calls of the same subroutine under each frame
+        for &return_address in self.get_stack() {
+            let call = return_address.checked_sub(2).and_then(|call_site| {
+                match self.get_instruction(call_site) {
+                    Some(Op::Call(entry)) => Some((call_site, entry)),
+                    _ => None,
+                }
+            });
+            let entry = call.map(|(_, entry)| entry);
+
+            frames.push(Frame {
+                call_site: call.map(|(call_site, _)| call_site),
+                entry,
+                return_address,
+                recursion: frames
+                    .iter()
+                    .filter(|frame| entry.is_some() && frame.entry == entry)
+                    .count(),
+            });
+        }
+
+        frames.reverse();
+        frames
+    }
+
+    /// the imbalance of the stack the instruction at PC would cause, if any
+    pub fn get_stack_imbalance(&self) -> Option<StackImbalance> {
+        let pc = self.get_pc();
+
+        match self.get_instruction(pc)? {
+            Op::Ret if self.get_sp() == 0 => Some(StackImbalance::UnmatchedReturn { pc }),
+            Op::Call(_) if self.get_sp() as usize == self.get_stack_size() => {
+                Some(StackImbalance::Overflow { pc })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::error::EmulationError;
+
+    fn cpu(rom: &[u8]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.read_file(&mut &rom[..]);
+        cpu
+    }
+
+    #[test]
+    fn recursive_calls_are_counted() {
+        let mut cpu = cpu(&[
+            0x22, 0x04, // 0x200: CALL 0x204
+            0x12, 0x02, // 0x202: JP 0x202
+            0x22, 0x08, // 0x204: CALL 0x208
+            0x00, 0xEE, // 0x206: RET
+            0x70, 0x01, // 0x208: ADD V0, 1
+            0x30, 0x03, // 0x20A: SE V0, 3
+            0x22, 0x08, // 0x20C: CALL 0x208
+            0x00, 0xEE, // 0x20E: RET
+        ]);
+        while cpu.get_v()[0] < 3 {
+            cpu.step().unwrap();
+        }
+
+        let frames = cpu.get_call_stack();
+        let summary: Vec<_> = frames
+            .iter()
+            .map(|frame| {
+                (
+                    frame.call_site,
+                    frame.entry,
+                    frame.return_address,
+                    frame.recursion,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Some(0x20C), Some(0x208), 0x20E, 2),
+                (Some(0x20C), Some(0x208), 0x20E, 1),
+                (Some(0x204), Some(0x208), 0x206, 0),
+                (Some(0x200), Some(0x204), 0x202, 0),
+            ]
+        );
+        assert_eq!(cpu.get_stack_imbalance(), None);
+    }
+
+    #[test]
+    fn return_addresses_not_after_a_call_have_no_call_site() {
+        let mut cpu = cpu(&[
+            0x22, 0x04, // 0x200: CALL 0x204
+            0x12, 0x02, // 0x202: JP 0x202
+            0x00, 0xEE, // 0x204: RET
+        ]);
+        cpu.step().unwrap();
+        // like a rom pushing an address with self modifying code, or an edit in the debugger
+        cpu.set_stack_entry(0, 0x300);
+
+        assert_eq!(
+            cpu.get_call_stack(),
+            vec![Frame {
+                call_site: None,
+                entry: None,
+                return_address: 0x300,
+                recursion: 0,
+            }]
+        );
+        // the frames without a call are not counted as recursion
+        cpu.set_sp(2);
+        cpu.set_stack_entry(1, 0x302);
+        let frames = cpu.get_call_stack();
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|frame| frame.recursion == 0));
+    }
+
+    #[test]
+    fn ret_with_an_empty_stack_is_found_before_it_runs() {
+        let mut cpu = cpu(&[
+            0x00, 0xEE, // 0x200: RET
+        ]);
+
+        assert_eq!(
+            cpu.get_stack_imbalance(),
+            Some(StackImbalance::UnmatchedReturn { pc: 0x200 })
+        );
+        assert!(cpu.get_call_stack().is_empty());
+        assert_eq!(
+            cpu.step(),
+            Err(EmulationError::StackUnderflow { pc: 0x200 })
+        );
+    }
+
+    #[test]
+    fn call_with_a_full_stack_is_found_before_it_runs() {
+        let mut cpu = cpu(&[
+            0x22, 0x00, // 0x200: CALL 0x200
+        ]);
+        for _ in 0..cpu.get_stack_size() {
+            assert_eq!(cpu.get_stack_imbalance(), None);
+            cpu.step().unwrap();
+        }
+
+        assert_eq!(
+            cpu.get_stack_imbalance(),
+            Some(StackImbalance::Overflow { pc: 0x200 })
+        );
+        assert_eq!(cpu.get_call_stack()[0].recursion, 15);
+        assert_eq!(cpu.step(), Err(EmulationError::StackOverflow { pc: 0x200 }));
+    }
+}
@@ -1,35 +1,173 @@
-use super::display::Display;
-use super::memory::Memory;
-use super::sound::Sound;
-use gdk::enums::key;
-use rand; // used for the RND instruction only.
-use std::cell::RefCell;
-use std::fs::File;
-use std::rc::Rc;
-use std::time::{Duration, SystemTime};
+use super::audio::{Audio, PATTERN_SIZE};
+use super::blocks::{self, Block, BlockCache, BlockInstruction, Handler};
+use super::breakpoint::{Access, Breakpoint, RunTarget};
+use super::display::{Display, PLANES_COUNT};
+use super::error::EmulationError;
+use super::memory::{Memory, MEMORY_SIZE};
+use super::op::{Op, Reg};
+use super::quirks::Quirks;
+use super::state::{SaveStateError, StateReader, StateWriter};
+use super::symbols::Symbols;
+use rand::rngs::StdRng; // used for the RND instruction only.
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
 
+/// The number of instructions executed in one 60Hz frame by default,
+/// this is close to the 1000 instructions/second the window used to run at
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 16;
+
+/// What to do when the CPU executes an invalid opcode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvalidOpcodePolicy {
+    /// do nothing, as if it was a no-op
+    Ignore,
+    /// print the opcode and its address, and continue
+    Log,
+    /// continue, but stop running and report it with `take_break`
+    /// (the window pauses in the debugger)
+    Break,
+    /// fail with `EmulationError::InvalidOpcode`
+    Fail,
+}
+
+/// The names of the policies that can be used in `InvalidOpcodePolicy::from_name`
+pub const INVALID_OPCODE_POLICY_NAMES: [&str; 4] = ["ignore", "log", "break", "fail"];
+
+impl InvalidOpcodePolicy {
+    /// get a policy by its name, the names are in `INVALID_OPCODE_POLICY_NAMES`
+    pub fn from_name(name: &str) -> Option<InvalidOpcodePolicy> {
+        match name {
+            "ignore" => Some(InvalidOpcodePolicy::Ignore),
+            "log" => Some(InvalidOpcodePolicy::Log),
+            "break" => Some(InvalidOpcodePolicy::Break),
+            "fail" => Some(InvalidOpcodePolicy::Fail),
+            _ => None,
+        }
+    }
+}
+
+/// How the CPU executes the instructions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    /// decode and execute one instruction at a time, with the decode cache
+    Interpreter,
+    /// compile the basic blocks into chains of closures (see `blocks`) and run
+    /// them, faster but gives the same results as `Interpreter`.
+    /// The interpreter is used while there are breakpoints, watchpoints or a `RunTarget`
+    Blocks,
+}
+
+/// The names of the backends that can be used in `Backend::from_name`
+pub const BACKEND_NAMES: [&str; 2] = ["interpreter", "blocks"];
+
+impl Backend {
+    /// get a backend by its name, the names are in `BACKEND_NAMES`
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "interpreter" => Some(Backend::Interpreter),
+            "blocks" => Some(Backend::Blocks),
+            _ => None,
+        }
+    }
+}
+
+/// Why the CPU stopped running and wants to be inspected in the debugger,
+/// running stops after the instruction that caused it
+#[derive(Clone, Debug, PartialEq)]
+pub enum BreakReason {
+    /// an invalid opcode was executed with the `Break` policy
+    InvalidOpcode { pc: u16, opcode: u16 },
+    /// a breakpoint was hit, the instruction at `pc` is not executed yet
+    Breakpoint { pc: u16, hits: u32 },
+    /// the `RunTarget` was reached, the instruction at `pc` is not executed yet
+    Reached { pc: u16 },
+    /// the instruction at `pc` accessed a watched byte, reads and writes stop
+    /// after the instruction, executes before it
+    Watchpoint {
+        pc: u16,
+        addr: u16,
+        access: Access,
+        old: u8,
+        new: u8,
+    },
+}
+
+impl fmt::Display for BreakReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            BreakReason::InvalidOpcode { pc, opcode } => {
+                write!(f, "invalid opcode {:04X} at {:04X}", opcode, pc)
+            }
+            BreakReason::Breakpoint { pc, hits } => {
+                write!(f, "breakpoint at {:04X} (hit {} times)", pc, hits)
+            }
+            BreakReason::Reached { pc } => write!(f, "reached {:04X}", pc),
+            BreakReason::Watchpoint {
+                pc,
+                addr,
+                access,
+                old,
+                new,
+            } => match access {
+                Access::Read => write!(f, "read of {:04X} ({:02X}) at {:04X}", addr, old, pc),
+                Access::Write => write!(
+                    f,
+                    "write to {:04X} ({:02X} -> {:02X}) at {:04X}",
+                    addr, old, new, pc
+                ),
+                Access::Execute => write!(f, "execution of {:04X} at {:04X}", addr, pc),
+            },
+        }
+    }
+}
+
+#[allow(non_snake_case)]
 pub struct CPU {
-    V: [u8; 16],             // 16 8-bit Vx register
-    I: u16,                  // I register
-    DT: u8,                  // Delay timer
-    ST: u8,                  // Sound timer
-    PC: u16,                 // Program counter
-    SP: u8,                  // Stack pointer
-    stack: [u16; 16],        // Internal stack of 16 16-bit values
-    memory: RefCell<Memory>, // Memory component
-    display: Display,        // Display component
-    beep_sound: Sound,       // The beep sound that should be played when counting down ST
-
-    single_stepping: Rc<RefCell<bool>>, // Is debugging single step enabled?
-    run_next: Rc<RefCell<bool>>, // can we run run the next instruction in the next loop? (only in single_stepping)
+    V: [u8; 16],          // 16 8-bit Vx register
+    I: u16,               // I register
+    DT: u8,               // Delay timer
+    ST: u8,               // Sound timer
+    PC: u16,              // Program counter
+    SP: u8,               // Stack pointer
+    stack: [u16; 16],     // Internal stack of 16 16-bit values
+    rpl_flags: [u8; 16],  // SUPER-CHIP RPL user flags, saved and loaded with Fx75/Fx85
+    memory: Memory,       // Memory component
+    display: Display,     // Display component (the framebuffer)
+    audio: Audio,         // Audio component (XO-CHIP audio pattern and pitch)
+    keyboard: [bool; 16], // The state of the 16 keys of the keypad
 
     wait_for_keypress_x: i8, // used to indicate the waiting for keypress for instruction Fx0A - LD Vx, K
-    last_update: SystemTime, // time of the last event loop (used to get 60Hz clock for the timers)
+    wait_for_vblank: bool, // used by DRW to wait for the next frame (only with `display_wait` quirk)
+    quirks: Quirks,        // the behaviour of the ambiguous instructions
+    exited: bool,          // has the SUPER-CHIP EXIT instruction been executed
+    fault: Option<EmulationError>, // the error that stopped the execution, if any
+    break_reason: Option<BreakReason>, // the reason to stop running, until taken by `take_break`
+    breakpoints: BTreeMap<u16, Breakpoint>, // the breakpoints by address
+    resumed_breakpoint: Option<u16>, // where running continues after a break (or `run_to`), not stopped at again until an instruction runs
+    run_target: Option<RunTarget>,   // where to stop running, set by `run_to`
+    symbols: Symbols,                // the names of the addresses shown by the debuggers
+    invalid_opcode_policy: InvalidOpcodePolicy, // what to do on invalid opcodes
+    instruction_address: u16, // the address of the instruction being executed (used in errors)
+    decode_cache: bool, // execute the instructions already decoded by the memory instead of decoding them again
+    backend: Backend,   // how `run_cycles` executes the instructions
+    blocks: BlockCache, // the compiled blocks of the `Blocks` backend
+
+    cycles: u64, // number of cycles executed since the start, used as the emulated time
+    instructions_per_frame: u32, // number of cycles in one 60Hz frame (the timers tick once per frame)
+    rng: StdRng,                 // random number generator for the RND instruction
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CPU {
     pub fn new() -> CPU {
-        let cpu = CPU {
+        CPU {
             V: [0u8; 16],
             I: 0,
             DT: 0,
@@ -37,450 +175,1692 @@ impl CPU {
             PC: 0x200, // start at 0x200 address
             SP: 0,
             stack: [0; 16],
-            memory: RefCell::new(Memory::new()),
-            display: Display::new(64, 32),
+            rpl_flags: [0; 16],
+            memory: Memory::new(),
+            display: Display::new(),
+            audio: Audio::new(),
+            keyboard: [false; 16],
             wait_for_keypress_x: -1,
+            wait_for_vblank: false,
+            quirks: Quirks::default(),
+            exited: false,
+            fault: None,
+            break_reason: None,
+            breakpoints: BTreeMap::new(),
+            resumed_breakpoint: None,
+            run_target: None,
+            symbols: Symbols::new(),
+            invalid_opcode_policy: InvalidOpcodePolicy::Ignore,
+            instruction_address: 0x200,
+            decode_cache: true,
+            backend: Backend::Interpreter,
+            blocks: BlockCache::new(),
 
-            single_stepping: Rc::new(RefCell::new(false)),
-            run_next: Rc::new(RefCell::new(false)),
-
-            last_update: SystemTime::now(),
-            beep_sound: Sound::new(300), // frequency of the sin wave
-        };
-        cpu.setup_keyboard();
-        cpu.display
-            .update_memory_debug(cpu.memory.borrow().get_memory(), true);
+            cycles: 0,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            rng: StdRng::from_entropy(),
+        }
+    }
 
-        cpu
+    pub fn read_file<R: Read>(&mut self, file: &mut R) {
+        self.memory.read_file(file);
     }
 
-    pub fn setup_keyboard(&self) {
-        let single_stepping_c = self.single_stepping.clone();
-        let run_next_c = self.run_next.clone();
+    /// snapshot the whole machine (registers, timers, memory, framebuffer,
+    /// audio, quirks, ...) in the save state format, see `load_state`.
+    ///
+    /// The keypad, the fault and the invalid opcode policy are not part of
+    /// the state. `RND` continues with a generator seeded from the state,
+    /// so loading the same state always gives the same random numbers.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
 
-        self.display.setup_keyboard(move |k| {
-            let mut single_stepping = single_stepping_c.borrow_mut();
-            let mut run_next = run_next_c.borrow_mut();
-            // Toggle debug with SPACE
-            // Step with N
-            if k == key::space {
-                *single_stepping = !*single_stepping;
-            }
-            if k == key::n {
-                *run_next = true;
-            }
-        });
+        writer.write_bytes(&self.V);
+        writer.write_u16(self.I);
+        writer.write_u8(self.DT);
+        writer.write_u8(self.ST);
+        writer.write_u16(self.PC);
+        writer.write_u8(self.SP);
+        for &address in self.stack.iter() {
+            writer.write_u16(address);
+        }
+        writer.write_bytes(&self.rpl_flags);
+        writer.write_u8(self.wait_for_keypress_x as u8);
+        writer.write_bool(self.wait_for_vblank);
+        writer.write_bool(self.exited);
+        writer.write_u64(self.cycles);
+        writer.write_u32(self.instructions_per_frame);
+        writer.write_u8(self.quirks.to_bits());
+        // don't change the generator of this CPU, use a copy for the seed
+        writer.write_u64(self.rng.clone().gen());
+
+        self.audio.save_state(&mut writer);
+        self.display.save_state(&mut writer);
+        self.memory.save_state(&mut writer);
+
+        writer.finish()
     }
 
-    pub fn run_display_application(self) {
-        let cpu_rc = Rc::new(RefCell::new(self));
-        let c_cpu = cpu_rc.clone();
+    /// restore the machine from a state created by `save_state`, if the state
+    /// is not valid (corrupted, different version, ...) nothing is changed.
+    /// Loading a state clears the fault.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(state)?;
 
-        const FPS: u32 = 1000;
+        let mut registers = [0; 16];
+        registers.copy_from_slice(reader.read_bytes(16)?);
+        let i = reader.read_u16()?;
+        let dt = reader.read_u8()?;
+        let st = reader.read_u8()?;
+        let pc = reader.read_u16()?;
+        let sp = reader.read_u8()?;
+        if sp as usize > self.stack.len() {
+            return Err(SaveStateError::InvalidData("stack pointer"));
+        }
+        let mut stack = [0; 16];
+        for address in stack.iter_mut() {
+            *address = reader.read_u16()?;
+        }
+        let mut rpl_flags = [0; 16];
+        rpl_flags.copy_from_slice(reader.read_bytes(16)?);
+        let wait_for_keypress_x = reader.read_u8()? as i8;
+        if !(-1..=0xF).contains(&wait_for_keypress_x) {
+            return Err(SaveStateError::InvalidData("waiting for keypress register"));
+        }
+        let wait_for_vblank = reader.read_bool()?;
+        let exited = reader.read_bool()?;
+        let cycles = reader.read_u64()?;
+        let instructions_per_frame = reader.read_u32()?;
+        if instructions_per_frame == 0 {
+            return Err(SaveStateError::InvalidData("instructions per frame"));
+        }
+        let quirks = Quirks::from_bits(reader.read_u8()?);
+        let seed = reader.read_u64()?;
 
-        cpu_rc.borrow().display.run_in_loop(1000 / FPS, move || {
-            let mut cpu = c_cpu.borrow_mut();
+        let mut audio = Audio::new();
+        audio.load_state(&mut reader)?;
+        let mut display = Display::new();
+        display.load_state(&mut reader)?;
 
+        // the memory is loaded in place, so it keeps its watchpoints and the
+        // compiled blocks are invalidated, check the rest of the state first
+        let mut rest = reader.clone();
+        rest.read_bytes(MEMORY_SIZE)?;
+        rest.finish()?;
+
+        // the whole state is valid, now it can be applied
+        self.memory.load_state(&mut reader)?;
+        reader.finish()?;
+        self.V = registers;
+        self.I = i;
+        self.DT = dt;
+        self.ST = st;
+        self.PC = pc;
+        self.SP = sp;
+        self.stack = stack;
+        self.rpl_flags = rpl_flags;
+        self.wait_for_keypress_x = wait_for_keypress_x;
+        self.wait_for_vblank = wait_for_vblank;
+        self.exited = exited;
+        self.cycles = cycles;
+        self.instructions_per_frame = instructions_per_frame;
+        self.quirks = quirks;
+        self.set_seed(seed);
+        self.audio = audio;
+        self.display = display;
+
+        self.fault = None;
+        self.break_reason = None;
+        self.resumed_breakpoint = None;
+        self.run_target = None;
+        self.instruction_address = pc;
+
+        Ok(())
+    }
+
+    /// executes one cycle, which is the instruction at PC, unless the CPU is
+    /// waiting for a key press from `LD Vx, K`, in that case it only checks the keypad.
+    /// After the rom exits (`EXIT`), nothing is executed.
+    ///
+    /// The timers are decremented once every `instructions_per_frame` cycles,
+    /// so the timing only depends on the number of executed cycles.
+    ///
+    /// If the instruction fails, the CPU becomes faulted, PC stays at the
+    /// failing instruction and every call returns the same error until
+    /// `clear_fault` is called.
+    pub fn step(&mut self) -> Result<(), EmulationError> {
+        if let Some(fault) = &self.fault {
+            return Err(fault.clone());
+        }
+
+        // nothing to do after EXIT
+        if self.exited {
+            return Ok(());
+        }
+
+        if self.wait_for_vblank {
+            // idle until the end of the frame
+        } else if self.wait_for_keypress_x > -1 {
             // cpu waiting for key press
-            if cpu.wait_for_keypress_x > -1 {
-                // get the keyboard press layout
-                let keyboard = cpu.display.get_keyboard_data_copy();
-                // if any key is being pressed, wait no more and assign
-                // the value of they key to the register Vx
-                match keyboard.iter().position(|&x| x) {
-                    Some(key) => {
-                        let x = cpu.wait_for_keypress_x as usize;
-                        cpu.V[x] = key as u8;
-                        cpu.wait_for_keypress_x = -1;
-                        cpu.display.update_current_instruction_debug(cpu.PC);
+            // if any key is being pressed, wait no more and assign
+            // the value of they key to the register Vx
+            if let Some(key) = self.keyboard.iter().position(|&x| x) {
+                let x = self.wait_for_keypress_x as usize;
+                self.V[x] = key as u8;
+                self.wait_for_keypress_x = -1;
+            }
+        } else {
+            let pc = self.PC;
+            self.instruction_address = pc;
+            self.resumed_breakpoint = None;
+
+            let result = match self.memory.get_decoded(pc) {
+                Some(op) => {
+                    // PC points to the next instruction during execution,
+                    // it was in memory when the instruction was decoded
+                    self.PC = pc + op.length();
+                    self.execute(op)
+                }
+                None => self.fetch_instruction().and_then(|instruction| {
+                    // PC points to the next instruction during execution
+                    self.PC = self.memory_address(pc, 2)?;
+                    match self.decode(instruction)? {
+                        Some(op) => {
+                            // cached before running it, in case it writes over itself
+                            if self.decode_cache {
+                                self.memory.set_decoded(pc, op);
+                            }
+                            self.execute(op)
+                        }
+                        None => self.invalid_opcode(instruction),
                     }
-                    None => {}
-                };
+                }),
+            };
 
-                return;
+            if let Err(error) = result {
+                self.PC = pc;
+                self.fault = Some(error.clone());
+                return Err(error);
             }
+        }
 
-            // if we are in stepping mode and can run the next instruction, run it
-            if *cpu.single_stepping.borrow() && !*cpu.run_next.borrow() {
-                return;
-            }
+        self.cycles += 1;
+        if self.cycles % self.instructions_per_frame as u64 == 0 {
+            self.decrement_timers();
+            self.wait_for_vblank = false;
+        }
 
-            // if we are in stepping mode, pause for the next time until the user clicks the button
-            *cpu.run_next.borrow_mut() = false;
+        Ok(())
+    }
 
-            let instruction = (cpu.memory.borrow().get(cpu.PC) as u16) << 8
-                | (cpu.memory.borrow().get(cpu.PC + 1) as u16);
-            cpu.run_instruction(instruction);
-            cpu.PC += 2;
+    /// executes `n` cycles, see `step`, stops at the first error or
+    /// when something requests a break (see `take_break`), like a breakpoint
+    /// before the instruction at PC or a watchpoint.
+    /// The `RunTarget` is cleared when it stops early for any reason
+    pub fn run_cycles(&mut self, n: u32) -> Result<(), EmulationError> {
+        let result = self.run_cycles_until_break(n);
+        if result.is_err() || self.break_reason.is_some() {
+            self.run_target = None;
+        }
+        result
+    }
 
-            // only show the location of the instruction in single stepping mode
-            if *cpu.single_stepping.borrow() && cpu.wait_for_keypress_x == -1 {
-                if cpu.display.get_disassembly_offset() % 2 == 1 && cpu.PC % 2 == 0 {
-                    // update even
-                    cpu.update_disassembly_debug_even();
-                } else if cpu.display.get_disassembly_offset() % 2 == 0 && cpu.PC % 2 == 1 {
-                    // update odd
-                    cpu.update_disassembly_debug_odd();
-                }
-                cpu.display.update_current_instruction_debug(cpu.PC);
+    fn run_cycles_until_break(&mut self, n: u32) -> Result<(), EmulationError> {
+        let mut remaining = n;
+        while remaining > 0 {
+            if self.hit_breakpoint() {
+                break;
             }
 
-            cpu.display
-                .update_registers_debug(&cpu.V, cpu.I, cpu.PC, cpu.DT, cpu.ST, cpu.SP);
+            remaining -= match self.backend {
+                Backend::Blocks if !self.is_debugging() => self.run_block(remaining)?,
+                _ => {
+                    self.step()?;
+                    1
+                }
+            };
 
-            match cpu.last_update.elapsed() {
-                Ok(duration) => {
-                    if duration > Duration::from_millis(1000 / 60) {
-                        if cpu.ST > 0 {
-                            cpu.ST -= 1;
+            if self.break_reason.is_some() {
+                break;
+            }
+        }
 
-                            cpu.play_beep();
-                        } else {
-                            cpu.stop_beep();
-                        }
+        Ok(())
+    }
 
-                        if cpu.DT > 0 {
-                            cpu.DT -= 1;
-                        }
+    /// execute the block at PC, at most `limit` cycles, returns the number of
+    /// executed cycles. Every instruction is executed like `step` does, the
+    /// interpreter is used when there is no block to run
+    fn run_block(&mut self, limit: u32) -> Result<u32, EmulationError> {
+        if self.fault.is_some()
+            || self.exited
+            || self.wait_for_vblank
+            || self.wait_for_keypress_x > -1
+        {
+            self.step()?;
+            return Ok(1);
+        }
 
-                        cpu.last_update = SystemTime::now();
-                    }
-                }
-                Err(e) => {
-                    println!("Error occurred in SystemTime::elapsed {}", e);
+        // the blocks that were written since the last run are compiled again
+        for addr in self.memory.take_code_writes() {
+            self.blocks.invalidate(addr);
+        }
+
+        let block = match self.blocks.get(self.PC) {
+            Some(block) => block,
+            None => match self.compile_block(self.PC) {
+                Some(block) => self.blocks.insert(block),
+                None => {
+                    // invalid instruction or the end of memory
+                    self.step()?;
+                    return Ok(1);
                 }
+            },
+        };
+
+        self.resumed_breakpoint = None;
+
+        let mut executed = 0;
+        for instruction in block.instructions.iter().take(limit as usize) {
+            let pc = instruction.address;
+            self.instruction_address = pc;
+            // PC points to the next instruction during execution
+            self.PC = instruction.next;
+
+            if let Err(error) = (instruction.handler)(self) {
+                self.PC = pc;
+                self.fault = Some(error.clone());
+                return Err(error);
             }
+
+            executed += 1;
+            self.cycles += 1;
+            if self.cycles % self.instructions_per_frame as u64 == 0 {
+                self.decrement_timers();
+                self.wait_for_vblank = false;
+            }
+        }
+
+        Ok(executed)
+    }
+
+    /// compile the block starting at `start`, `None` if there is no valid
+    /// instruction there
+    fn compile_block(&mut self, start: u16) -> Option<Block> {
+        let instructions = blocks::decode_block(&self.memory, start);
+        let &(last_address, last_op) = instructions.last()?;
+        let end = last_address + last_op.length();
+        self.memory.mark_code(start, end - start);
+
+        Some(Block::new(
+            instructions
+                .into_iter()
+                .map(|(address, op)| BlockInstruction {
+                    address,
+                    next: address + op.length(),
+                    handler: Self::compile_op(op),
+                })
+                .collect(),
+        ))
+    }
+
+    /// bind the operands of `op` in a closure, the most common instructions
+    /// are written directly, the rest go through `execute`
+    fn compile_op(op: Op) -> Handler {
+        match op {
+            Op::Ld(x, kk) => Box::new(move |cpu| {
+                cpu.V[x as usize] = kk;
+                Ok(())
+            }),
+            Op::Add(x, kk) => Box::new(move |cpu| {
+                cpu.V[x as usize] = cpu.V[x as usize].wrapping_add(kk);
+                Ok(())
+            }),
+            Op::LdReg(x, y) => Box::new(move |cpu| {
+                cpu.V[x as usize] = cpu.V[y as usize];
+                Ok(())
+            }),
+            Op::LdI(address) | Op::LdILong(address) => Box::new(move |cpu| {
+                cpu.I = address;
+                Ok(())
+            }),
+            Op::LdVxDt(x) => Box::new(move |cpu| {
+                cpu.V[x as usize] = cpu.DT;
+                Ok(())
+            }),
+            Op::LdDtVx(x) => Box::new(move |cpu| {
+                cpu.DT = cpu.V[x as usize];
+                Ok(())
+            }),
+            _ => Box::new(move |cpu| cpu.execute(op)),
+        }
+    }
+
+    /// executes cycles until the end of the current 60Hz frame, the timers
+    /// are decremented once at the end.
+    ///
+    /// if the CPU was stepped in the middle of a frame, only the rest of
+    /// that frame is executed
+    pub fn run_frame(&mut self) -> Result<(), EmulationError> {
+        let frame_cycles = self.instructions_per_frame as u64;
+        let remaining = frame_cycles - self.cycles % frame_cycles;
+
+        self.run_cycles(remaining as u32)
+    }
+
+    /// are there breakpoints, watchpoints or a run target to check
+    fn is_debugging(&self) -> bool {
+        !self.breakpoints.is_empty()
+            || !self.memory.get_watchpoints().is_empty()
+            || self.run_target.is_some()
+    }
+
+    /// check the run target, the breakpoint and the execute watchpoints at PC before
+    /// its instruction is fetched, returns true (and requests a break) if it stops running
+    fn hit_breakpoint(&mut self) -> bool {
+        let pc = self.PC;
+
+        // only when the instruction is going to be executed, not while waiting,
+        // and when continuing after the breakpoint stopped running
+        if !self.is_debugging()
+            || self.fault.is_some()
+            || self.exited
+            || self.wait_for_vblank
+            || self.wait_for_keypress_x > -1
+            || self.resumed_breakpoint == Some(pc)
+        {
+            return false;
+        }
+
+        let reached = match self.run_target {
+            Some(RunTarget::Address { address, max_sp }) => pc == address && self.SP <= max_sp,
+            Some(RunTarget::Return { sp }) => self.SP < sp,
+            None => false,
+        };
+        if reached {
+            self.run_target = None;
+            self.break_reason = Some(BreakReason::Reached { pc });
+            self.resumed_breakpoint = Some(pc);
+            return true;
+        }
+
+        if let Some(addr) = self.watched_instruction_byte(pc) {
+            let value = self.memory.get(addr);
+            self.break_reason = Some(BreakReason::Watchpoint {
+                pc,
+                addr,
+                access: Access::Execute,
+                old: value,
+                new: value,
+            });
+            self.resumed_breakpoint = Some(pc);
+            return true;
+        }
+
+        let condition = match self.breakpoints.get(&pc) {
+            Some(breakpoint) => breakpoint.condition,
+            None => return false,
+        };
+        if !condition.map_or(true, |condition| condition.evaluate(self)) {
+            return false;
+        }
+
+        let breakpoint = self.breakpoints.get_mut(&pc).unwrap();
+        breakpoint.hits += 1;
+        if breakpoint.hits <= breakpoint.ignore_count {
+            return false;
+        }
+
+        self.break_reason = Some(BreakReason::Breakpoint {
+            pc,
+            hits: breakpoint.hits,
         });
+        self.resumed_breakpoint = Some(pc);
+        true
+    }
 
-        // run the application
-        Display::run_application();
+    /// the first byte of the instruction at `pc` with an execute watchpoint
+    fn watched_instruction_byte(&self, pc: u16) -> Option<u16> {
+        if !self.memory.has_watchpoints(Access::Execute) {
+            return None;
+        }
+
+        // `LD I, long addr` (F000 nnnn) is 4 bytes long
+        let length = if self.read_word(pc).ok() == Some(0xF000) {
+            4
+        } else {
+            2
+        };
+
+        (pc as u32..pc as u32 + length)
+            .take_while(|&addr| addr < MEMORY_SIZE as u32)
+            .map(|addr| addr as u16)
+            .find(|&addr| self.memory.is_watched(addr, Access::Execute))
     }
 
-    fn update_disassembly_debug_even(&mut self) {
-        self.display
-            .update_disassembly_debug(&super::disassembler::disassemble(
-                &self.memory.borrow().get_memory(),
-                0,
-            ));
+    /// request a break for an access to a watched byte, only the first
+    /// access of an instruction is reported
+    fn hit_watchpoint(&mut self, addr: u16, access: Access, old: u8, new: u8) {
+        if self.break_reason.is_none() {
+            self.break_reason = Some(BreakReason::Watchpoint {
+                pc: self.instruction_address,
+                addr,
+                access,
+                old,
+                new,
+            });
+        }
     }
 
-    fn update_disassembly_debug_odd(&mut self) {
-        let offset: u16 = 1;
-        self.display
-            .update_disassembly_debug(&super::disassembler::disassemble(
-                &self.memory.borrow().get_memory()[(offset as usize)..],
-                offset,
-            ));
+    /// run (with `run_cycles`) until the target is reached, a breakpoint at PC
+    /// or the target itself are not stopped at before the instruction at PC runs
+    pub fn run_to(&mut self, target: RunTarget) {
+        self.run_target = Some(target);
+        self.resumed_breakpoint = Some(self.PC);
     }
 
-    pub fn read_file(&mut self, file: &mut File) {
-        self.memory.borrow_mut().read_file(file);
+    pub fn get_run_target(&self) -> Option<RunTarget> {
+        self.run_target
+    }
 
-        // default is even
-        self.update_disassembly_debug_even();
-        self.display.update_current_instruction_debug(self.PC);
+    pub fn clear_run_target(&mut self) {
+        self.run_target = None;
+    }
 
-        self.display
-            .update_memory_debug(self.memory.borrow().get_memory(), false);
+    /// the target to step over the instruction at PC, after a `CALL` returns
+    /// with the same SP, `None` if it is not a `CALL` (it can just be stepped)
+    pub fn get_step_over_target(&self) -> Option<RunTarget> {
+        match self.get_instruction(self.PC)? {
+            Op::Call(_) => Some(RunTarget::Address {
+                address: self.memory_address(self.PC, 2).ok()?,
+                max_sp: self.SP,
+            }),
+            _ => None,
+        }
     }
 
-    pub fn play_beep(&mut self) {
-        if self.beep_sound.is_paused() {
-            self.beep_sound.play();
+    /// the target to step out of the current subroutine, after its `RET`,
+    /// `None` if the stack is empty
+    pub fn get_step_out_target(&self) -> Option<RunTarget> {
+        if self.SP == 0 {
+            None
+        } else {
+            Some(RunTarget::Return { sp: self.SP })
         }
     }
 
-    pub fn stop_beep(&mut self) {
-        if !self.beep_sound.is_paused() {
-            self.beep_sound.stop();
+    /// the target to run until the instruction at `address`, at any depth of the stack
+    pub fn get_run_to_target(&self, address: u16) -> RunTarget {
+        RunTarget::Address {
+            address,
+            max_sp: self.stack.len() as u8,
         }
     }
 
-    pub fn run_instruction(&mut self, instruction: u16) {
-        // nibbles will have the values of the instruction as
-        // each four bytes of the instruction starting from the left as 0
-        //
-        // example: instruction = 0xfa12, nibbles = [0xf, 0xa, 0x1, 0x2]
-        let mut nibbles = [0u8; 4];
-        for i in 0..4 {
-            let offset = (3 - i) * 4;
-            nibbles[i] = ((instruction & (0xf << offset)) >> offset) as u8;
+    /// the instruction at `addr`, `None` if it is invalid or past the end of memory
+    pub fn get_instruction(&self, addr: u16) -> Option<Op> {
+        let instruction = self.read_word(addr).ok()?;
+        let next = self
+            .memory_address(addr, 2)
+            .and_then(|next| self.read_word(next))
+            .unwrap_or(0);
+
+        Op::decode(instruction, next)
+    }
+
+    /// add a breakpoint, it replaces the one at the same address
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.insert(breakpoint.address, breakpoint);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> Option<Breakpoint> {
+        self.breakpoints.remove(&address)
+    }
+
+    /// add a breakpoint at `address` if there is none, or remove it,
+    /// returns true if it was added
+    pub fn toggle_breakpoint(&mut self, address: u16) -> bool {
+        if self.remove_breakpoint(address).is_some() {
+            false
+        } else {
+            self.add_breakpoint(Breakpoint::new(address));
+            true
         }
+    }
 
-        // the lowest 12-bit value
-        let address = (nibbles[1] as u16) << 8 | (nibbles[2] as u16) << 4 | (nibbles[3] as u16);
-        // lowest 4-bit value in the high byte
-        let x = nibbles[1];
-        // highest 4-bit value in the low byte
-        let y = nibbles[2];
-        // the lowest 8-bit value
-        let kk = nibbles[2] << 4 | nibbles[3];
+    pub fn get_breakpoint(&self, address: u16) -> Option<&Breakpoint> {
+        self.breakpoints.get(&address)
+    }
 
-        match nibbles[0] {
-            0 => {
-                // CLS, RET, SYS
-                match nibbles[2] << 4 | nibbles[3] {
-                    0xE0 => {
-                        // CLS
-                        for i in 0..self.display.get_height() {
-                            for j in 0..self.display.get_width() {
-                                self.display.draw_pixel(j, i, false);
-                            }
-                        }
-                        self.display.redraw();
-                    }
-                    0xEE => {
-                        // RET
-                        self.SP -= 1;
-                        let return_address = self.stack[self.SP as usize];
-                        self.PC = return_address - 2;
-
-                        self.display
-                            .update_stack_debug(&self.stack[0..self.SP as usize]);
-                    }
-                    _ => {
-                        // SYS addr
-                        self.PC = address - 2;
-                    }
-                }
+    /// all the breakpoints, sorted by address
+    pub fn get_breakpoints(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.values()
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// the names of the addresses (from a symbol file) used by the debuggers
+    pub fn get_symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// returns (and clears) the reason why running stopped early, if any,
+    /// frontends should pause and show the debugger when this is not `None`
+    pub fn take_break(&mut self) -> Option<BreakReason> {
+        self.break_reason.take()
+    }
+
+    pub fn get_invalid_opcode_policy(&self) -> InvalidOpcodePolicy {
+        self.invalid_opcode_policy
+    }
+
+    pub fn set_invalid_opcode_policy(&mut self, policy: InvalidOpcodePolicy) {
+        self.invalid_opcode_policy = policy;
+    }
+
+    /// the error that stopped the CPU, if it is faulted
+    pub fn get_fault(&self) -> Option<&EmulationError> {
+        self.fault.as_ref()
+    }
+
+    /// allow the CPU to continue after a fault, the failing instruction
+    /// will be executed again by the next `step`
+    pub fn clear_fault(&mut self) {
+        self.fault = None;
+    }
+
+    pub fn is_decode_cache_enabled(&self) -> bool {
+        self.decode_cache
+    }
+
+    /// with the decode cache, every instruction is decoded once and kept
+    /// until its bytes are written, disabling it decodes on every step
+    pub fn set_decode_cache_enabled(&mut self, enabled: bool) {
+        self.decode_cache = enabled;
+        if !enabled {
+            self.memory.clear_decoded();
+        }
+    }
+
+    pub fn get_backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// change how `run_cycles` and `run_frame` execute the instructions,
+    /// `step` always uses the interpreter
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+        self.blocks.clear();
+        self.memory.take_code_writes();
+    }
+
+    /// the number of cycles executed since the CPU was created
+    pub fn get_cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn get_instructions_per_frame(&self) -> u32 {
+        self.instructions_per_frame
+    }
+
+    /// sets the speed of the CPU, in instructions per 60Hz frame
+    pub fn set_instructions_per_frame(&mut self, instructions_per_frame: u32) {
+        assert!(instructions_per_frame > 0);
+        self.instructions_per_frame = instructions_per_frame;
+    }
+
+    pub fn get_quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// seeds the random number generator used by `RND`, so that runs
+    /// can be reproduced
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// decrement DT and ST by one, this is done by `step` every frame
+    pub fn decrement_timers(&mut self) {
+        if self.ST > 0 {
+            self.ST -= 1;
+        }
+
+        if self.DT > 0 {
+            self.DT -= 1;
+        }
+    }
+
+    /// the sound (the audio pattern) should be played as long as ST is not zero
+    pub fn is_sound_playing(&self) -> bool {
+        self.ST > 0
+    }
+
+    /// has the rom exited with the SUPER-CHIP EXIT instruction
+    pub fn has_exited(&self) -> bool {
+        self.exited
+    }
+
+    pub fn is_waiting_for_keypress(&self) -> bool {
+        self.wait_for_keypress_x > -1
+    }
+
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        self.keyboard[key as usize] = pressed;
+    }
+
+    pub fn set_keyboard(&mut self, keyboard: [bool; 16]) {
+        self.keyboard = keyboard;
+    }
+
+    pub fn get_keyboard(&self) -> &[bool; 16] {
+        &self.keyboard
+    }
+
+    pub fn get_v(&self) -> &[u8; 16] {
+        &self.V
+    }
+
+    pub fn set_v(&mut self, x: u8, value: u8) {
+        self.V[x as usize] = value;
+    }
+
+    pub fn get_i(&self) -> u16 {
+        self.I
+    }
+
+    pub fn set_i(&mut self, value: u16) {
+        self.I = value;
+    }
+
+    pub fn get_dt(&self) -> u8 {
+        self.DT
+    }
+
+    pub fn set_dt(&mut self, value: u8) {
+        self.DT = value;
+    }
+
+    pub fn get_st(&self) -> u8 {
+        self.ST
+    }
+
+    pub fn set_st(&mut self, value: u8) {
+        self.ST = value;
+    }
+
+    pub fn get_pc(&self) -> u16 {
+        self.PC
+    }
+
+    /// continue from `value`, a breakpoint there stops running before its instruction
+    /// move PC, this also clears the fault so the CPU can continue from there
+    pub fn set_pc(&mut self, value: u16) {
+        self.PC = value;
+        self.resumed_breakpoint = None;
+        self.fault = None;
+    }
+
+    pub fn get_sp(&self) -> u8 {
+        self.SP
+    }
+
+    /// the number of used stack entries, at most `get_stack_size`
+    pub fn set_sp(&mut self, value: u8) {
+        assert!(value as usize <= self.stack.len());
+        self.SP = value;
+    }
+
+    /// the used part of the stack, from the bottom to the top
+    pub fn get_stack(&self) -> &[u16] {
+        &self.stack[0..self.SP as usize]
+    }
+
+    /// set the return address in the stack entry `index` (0 is the bottom)
+    pub fn set_stack_entry(&mut self, index: usize, address: u16) {
+        self.stack[index] = address;
+    }
+
+    /// the maximum number of stack entries
+    pub fn get_stack_size(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn get_memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn get_memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    pub fn get_audio(&self) -> &Audio {
+        &self.audio
+    }
+
+    pub fn get_display(&self) -> &Display {
+        &self.display
+    }
+
+    pub fn get_display_mut(&mut self) -> &mut Display {
+        &mut self.display
+    }
+
+    /// `base + offset` as a memory address, fails if it is past the end of the memory
+    fn memory_address(&self, base: u16, offset: u16) -> Result<u16, EmulationError> {
+        let addr = base as u32 + offset as u32;
+
+        if addr < MEMORY_SIZE as u32 {
+            Ok(addr as u16)
+        } else {
+            Err(EmulationError::MemoryOutOfBounds {
+                pc: self.instruction_address,
+                addr,
+            })
+        }
+    }
+
+    /// read the byte at `base + offset`, all the data reads of the instructions
+    /// go through here, so they can be watched
+    fn read_memory(&mut self, base: u16, offset: u16) -> Result<u8, EmulationError> {
+        let addr = self.memory_address(base, offset)?;
+        let value = self.memory.get(addr);
+
+        if self.memory.is_watched(addr, Access::Read) {
+            self.hit_watchpoint(addr, Access::Read, value, value);
+        }
+        Ok(value)
+    }
+
+    /// write the byte at `base + offset`, all the writes of the instructions
+    /// go through here, so they can be watched
+    fn write_memory(&mut self, base: u16, offset: u16, value: u8) -> Result<(), EmulationError> {
+        let addr = self.memory_address(base, offset)?;
+
+        if self.memory.is_watched(addr, Access::Write) {
+            self.hit_watchpoint(addr, Access::Write, self.memory.get(addr), value);
+        }
+        self.memory.store(addr, value);
+        Ok(())
+    }
+
+    /// the 16-bit word of an instruction at `addr`, the fetches are watched
+    /// before running the instruction (see `hit_breakpoint`)
+    fn read_word(&self, addr: u16) -> Result<u16, EmulationError> {
+        let byte = |offset| Ok(self.memory.get(self.memory_address(addr, offset)?) as u16);
+        Ok(byte(0)? << 8 | byte(1)?)
+    }
+
+    fn fetch_instruction(&self) -> Result<u16, EmulationError> {
+        self.read_word(self.PC)
+    }
+
+    /// skip the next instruction, used by the skip instructions,
+    /// `LD I, long addr` (F000 nnnn) is 4 bytes long so it is skipped entirely
+    fn skip_next_instruction(&mut self) -> Result<(), EmulationError> {
+        let length = if self.read_word(self.PC)? == 0xF000 {
+            4
+        } else {
+            2
+        };
+        self.PC = self.memory_address(self.PC, length)?;
+        Ok(())
+    }
+
+    /// handle an invalid opcode depending on the policy
+    fn invalid_opcode(&mut self, opcode: u16) -> Result<(), EmulationError> {
+        let pc = self.instruction_address;
+
+        match self.invalid_opcode_policy {
+            InvalidOpcodePolicy::Ignore => {}
+            InvalidOpcodePolicy::Log => {
+                eprintln!("invalid opcode {:04X} at {:04X}", opcode, pc);
             }
-            1 => {
-                // JMP addr
-                self.PC = address - 2;
+            InvalidOpcodePolicy::Break => {
+                self.break_reason = Some(BreakReason::InvalidOpcode { pc, opcode });
             }
-            2 => {
-                // CALL addr
-                self.stack[self.SP as usize] = self.PC + 2;
-                self.SP += 1;
-                self.PC = address - 2;
+            InvalidOpcodePolicy::Fail => {
+                return Err(EmulationError::InvalidOpcode { pc, opcode });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// the registers indices from `x` to `y`, in reverse if `x` is larger than `y`
+    fn register_range(x: u8, y: u8) -> Box<dyn Iterator<Item = usize>> {
+        if x <= y {
+            Box::new(x as usize..=y as usize)
+        } else {
+            Box::new((y as usize..=x as usize).rev())
+        }
+    }
+
+    /// executes `instruction`, PC should point to the next instruction
+    pub fn run_instruction(&mut self, instruction: u16) -> Result<(), EmulationError> {
+        match self.decode(instruction)? {
+            Some(op) => self.execute(op),
+            None => self.invalid_opcode(instruction),
+        }
+    }
+
+    /// decode `instruction`, PC should point to the next word, `LD I, long addr`
+    /// (F000 nnnn) takes its address from there and moves PC after it
+    fn decode(&mut self, instruction: u16) -> Result<Option<Op>, EmulationError> {
+        let next = if instruction == 0xF000 {
+            let next = self.read_word(self.PC)?;
+            self.PC = self.memory_address(self.PC, 2)?;
+            next
+        } else {
+            0
+        };
 
-                self.display
-                    .update_stack_debug(&self.stack[0..self.SP as usize]);
+        Ok(Op::decode(instruction, next))
+    }
+
+    /// executes a decoded instruction, PC should point to the next instruction
+    /// (after the 4 bytes of `LD I, long addr`)
+    pub fn execute(&mut self, op: Op) -> Result<(), EmulationError> {
+        match op {
+            Op::Cls => self.display.clear(),
+            Op::Ret => {
+                if self.SP == 0 {
+                    return Err(EmulationError::StackUnderflow {
+                        pc: self.instruction_address,
+                    });
+                }
+                self.SP -= 1;
+                self.PC = self.stack[self.SP as usize];
             }
-            3 => {
-                // SE Vx, byte
+            Op::Scd(n) => self.display.scroll_down(n as u16),
+            Op::Scu(n) => self.display.scroll_up(n as u16),
+            Op::Scr => self.display.scroll_right(4),
+            Op::Scl => self.display.scroll_left(4),
+            Op::Exit => self.exited = true,
+            Op::Low => self.display.set_hires(false),
+            Op::High => self.display.set_hires(true),
+            Op::Sys(address) | Op::Jp(address) => self.PC = address,
+            Op::Call(address) => {
+                if self.SP as usize == self.stack.len() {
+                    return Err(EmulationError::StackOverflow {
+                        pc: self.instruction_address,
+                    });
+                }
+                self.stack[self.SP as usize] = self.PC;
+                self.SP += 1;
+                self.PC = address;
+            }
+            Op::Se(x, kk) => {
                 if self.V[x as usize] == kk {
-                    self.PC += 2;
+                    self.skip_next_instruction()?;
                 }
             }
-            4 => {
-                // SNE Vx, byte
+            Op::Sne(x, kk) => {
                 if self.V[x as usize] != kk {
-                    self.PC += 2;
+                    self.skip_next_instruction()?;
                 }
             }
-            5 => {
-                // SE Vx, Vy
-                if nibbles[3] == 0 {
-                    if self.V[x as usize] == self.V[y as usize] {
-                        self.PC += 2;
-                    }
+            Op::SeReg(x, y) => {
+                if self.V[x as usize] == self.V[y as usize] {
+                    self.skip_next_instruction()?;
                 }
             }
-            6 => {
-                // LD Vx, byte
-                self.V[x as usize] = kk;
+            Op::Save(x, y) => {
+                // store the registers from Vx to Vy (can be in reverse order) in [I],
+                // I is not changed
+                for (i, register) in Self::register_range(x, y).enumerate() {
+                    self.write_memory(self.I, i as u16, self.V[register])?;
+                }
             }
-            7 => {
-                // ADD Vx, byte
-                self.V[x as usize] = (self.V[x as usize] as u16 + kk as u16) as u8;
+            Op::Load(x, y) => {
+                // load the registers from Vx to Vy (can be in reverse order) from [I],
+                // I is not changed
+                for (i, register) in Self::register_range(x, y).enumerate() {
+                    self.V[register] = self.read_memory(self.I, i as u16)?;
+                }
             }
-            8 => {
-                // LD, OR, AND, XOR, ADD, SUB, SHR, SUBN, SHL
-                match nibbles[3] {
-                    0 => {
-                        // LD Vx, Vy
-                        self.V[x as usize] = self.V[y as usize];
-                    }
-                    1 => {
-                        // OR Vx, Vy
-                        self.V[x as usize] |= self.V[y as usize];
-                    }
-                    2 => {
-                        // AND Vx, Vy
-                        self.V[x as usize] &= self.V[y as usize];
-                    }
-                    3 => {
-                        // XOR Vx, Vy
-                        self.V[x as usize] ^= self.V[y as usize];
-                    }
-                    4 => {
-                        // ADD Vx, Vy
-                        let result = (self.V[x as usize] as u16) + (self.V[y as usize] as u16);
-                        // store the result in Vx
-                        self.V[x as usize] = result as u8;
-                        // store the carry in Vf
-                        self.V[0xF] = if result > 255 { 1 } else { 0 };
-                    }
-                    5 => {
-                        // SUB Vx, Vy
-                        // store NOT BORROW
-                        self.V[0xF] = if self.V[x as usize] > self.V[y as usize] {
-                            1
-                        } else {
-                            0
-                        };
-                        self.V[x as usize] =
-                            (self.V[x as usize] as i16 - self.V[y as usize] as i16) as u8;
-                    }
-                    6 => {
-                        // SHR Vx {, Vy}
-                        // TODO: not sure if this can have empty y value and what does that mean
-                        self.V[0xF] = if self.V[x as usize] & 1 != 0 { 1 } else { 0 };
-                        self.V[x as usize] >>= 1;
-                    }
-                    7 => {
-                        // SUBN Vx, Vy
-                        // store NOT BORROW
-                        self.V[0xF] = if self.V[y as usize] > self.V[x as usize] {
-                            1
-                        } else {
-                            0
-                        };
-                        self.V[x as usize] = self.V[y as usize] - self.V[x as usize];
-                    }
-                    0xE => {
-                        // SHL Vx {, Vy}
-                        // TODO: not sure if this can have empty y value and what does that mean
-                        self.V[0xF] = if self.V[x as usize] & 0x80 != 0 { 1 } else { 0 };
-                        self.V[x as usize] <<= 1;
-                    }
-                    _ => {
-                        // invalid instruction
-                    }
+            Op::Ld(x, kk) => self.V[x as usize] = kk,
+            Op::Add(x, kk) => self.V[x as usize] = self.V[x as usize].wrapping_add(kk),
+            Op::LdReg(x, y) => self.V[x as usize] = self.V[y as usize],
+            Op::Or(x, y) => {
+                self.V[x as usize] |= self.V[y as usize];
+                if self.quirks.vf_reset {
+                    self.V[0xF] = 0;
                 }
             }
-            9 => {
-                // SNE Vx, Vy
-                if nibbles[3] == 0 {
-                    if self.V[x as usize] != self.V[y as usize] {
-                        self.PC += 2;
-                    }
+            Op::And(x, y) => {
+                self.V[x as usize] &= self.V[y as usize];
+                if self.quirks.vf_reset {
+                    self.V[0xF] = 0;
                 }
             }
-            0xA => {
-                // LD I, addr
-                self.I = address;
+            Op::Xor(x, y) => {
+                self.V[x as usize] ^= self.V[y as usize];
+                if self.quirks.vf_reset {
+                    self.V[0xF] = 0;
+                }
+            }
+            Op::AddReg(x, y) => {
+                let (result, carry) = self.V[x as usize].overflowing_add(self.V[y as usize]);
+                // store the result in Vx
+                self.V[x as usize] = result;
+                // store the carry in Vf, after the result so that
+                // it is not overwritten if x is F
+                self.V[0xF] = carry as u8;
+            }
+            Op::Sub(x, y) => {
+                let (result, borrow) = self.V[x as usize].overflowing_sub(self.V[y as usize]);
+                self.V[x as usize] = result;
+                // store NOT BORROW
+                self.V[0xF] = !borrow as u8;
+            }
+            Op::Shr(x, y) => {
+                let value = if self.quirks.shift_uses_vy {
+                    self.V[y as usize]
+                } else {
+                    self.V[x as usize]
+                };
+                self.V[x as usize] = value >> 1;
+                self.V[0xF] = value & 1;
+            }
+            Op::Subn(x, y) => {
+                let (result, borrow) = self.V[y as usize].overflowing_sub(self.V[x as usize]);
+                self.V[x as usize] = result;
+                // store NOT BORROW
+                self.V[0xF] = !borrow as u8;
+            }
+            Op::Shl(x, y) => {
+                let value = if self.quirks.shift_uses_vy {
+                    self.V[y as usize]
+                } else {
+                    self.V[x as usize]
+                };
+                self.V[x as usize] = value << 1;
+                self.V[0xF] = value >> 7;
+            }
+            Op::SneReg(x, y) => {
+                if self.V[x as usize] != self.V[y as usize] {
+                    self.skip_next_instruction()?;
+                }
             }
-            0xB => {
-                // JP V0, addr
-                self.PC = address + self.V[0] as u16 - 2;
+            Op::LdI(address) | Op::LdILong(address) => self.I = address,
+            Op::JpV0(address) => {
+                // or JP Vx, addr (with `jump_uses_vx` quirk), x is the highest nibble of addr
+                let offset = if self.quirks.jump_uses_vx {
+                    self.V[(address >> 8) as usize]
+                } else {
+                    self.V[0]
+                };
+                self.PC = address + offset as u16;
             }
-            0xC => {
-                // RND Vx, byte
-                let random = rand::random::<u8>();
+            Op::Rnd(x, kk) => {
+                let random = self.rng.gen::<u8>();
                 self.V[x as usize] = random & kk;
             }
-            0xD => {
-                // DRW Vx, Vy, nibble
-                let mut cur_row = self.V[y as usize];
-                let mut cur_col = self.V[x as usize];
-                let mut collision = false;
-                for i in 0..nibbles[3] {
-                    let row = self.memory.borrow().get(self.I + i as u16);
+            Op::Drw(x, y, n) => self.draw_sprite(x, y, n)?,
+            Op::Skp(x) => {
+                if self.keyboard[(self.V[x as usize] & 0xF) as usize] {
+                    self.skip_next_instruction()?;
+                }
+            }
+            Op::Sknp(x) => {
+                if !self.keyboard[(self.V[x as usize] & 0xF) as usize] {
+                    self.skip_next_instruction()?;
+                }
+            }
+            Op::Plane(n) => self.display.select_planes(n & 0x3),
+            Op::Audio => {
+                // load the 16 bytes audio pattern from [I]
+                let mut pattern = [0; PATTERN_SIZE];
+                for (i, byte) in pattern.iter_mut().enumerate() {
+                    *byte = self.read_memory(self.I, i as u16)?;
+                }
+                self.audio.set_pattern(&pattern);
+            }
+            Op::LdVxDt(x) => self.V[x as usize] = self.DT,
+            Op::LdVxK(x) => self.wait_for_keypress_x = x as i8,
+            Op::LdDtVx(x) => self.DT = self.V[x as usize],
+            Op::LdStVx(x) => self.ST = self.V[x as usize],
+            Op::AddI(x) => {
+                self.I = self.memory_address(self.I, self.V[x as usize] as u16)?;
+            }
+            Op::LdF(x) => {
+                self.I = self.memory.get_sprite_location(self.V[x as usize] & 0xF);
+            }
+            Op::LdHf(x) => {
+                self.I = self
+                    .memory
+                    .get_large_sprite_location(self.V[x as usize] & 0xF);
+            }
+            Op::LdB(x) => {
+                let value = self.V[x as usize];
+                self.write_memory(self.I, 0, value / 100)?;
+                self.write_memory(self.I, 1, (value % 100) / 10)?;
+                self.write_memory(self.I, 2, value % 10)?;
+            }
+            Op::LdMemVx(x) => {
+                for i in 0..=x {
+                    self.write_memory(self.I, i as u16, self.V[i as usize])?;
+                }
+                if self.quirks.load_store_increments_i {
+                    self.I = self.memory_address(self.I, x as u16 + 1)?;
+                }
+            }
+            Op::LdVxMem(x) => {
+                for i in 0..=x {
+                    self.V[i as usize] = self.read_memory(self.I, i as u16)?;
+                }
+                if self.quirks.load_store_increments_i {
+                    self.I = self.memory_address(self.I, x as u16 + 1)?;
+                }
+            }
+            Op::Pitch(x) => self.audio.set_pitch(self.V[x as usize]),
+            Op::LdRVx(x) => {
+                self.rpl_flags[..=x as usize].clone_from_slice(&self.V[..=x as usize]);
+            }
+            Op::LdVxR(x) => {
+                self.V[..=x as usize].clone_from_slice(&self.rpl_flags[..=x as usize]);
+            }
+        }
 
-                    // wrap around
-                    if cur_row >= (self.display.get_height() as u8) {
-                        cur_row = 0;
-                    }
-                    for j in 0..8 {
-                        // wrap around
-                        if cur_col >= (self.display.get_width() as u8) {
-                            cur_col = 0;
-                        }
+        Ok(())
+    }
 
-                        // XOR and check for colliding pixels
-                        collision |= self.display.xor_pixel(
-                            cur_col as u16,
-                            cur_row as u16,
-                            row & (1 << (8 - 1 - j)) != 0,
-                        );
-                        cur_col += 1;
-                    }
-                    cur_col = self.V[x as usize];
-                    cur_row += 1;
+    /// DRW Vx, Vy, nibble
+    /// with nibble = 0, draws a 16x16 sprite (SUPER-CHIP)
+    fn draw_sprite(&mut self, x: Reg, y: Reg, n: u8) -> Result<(), EmulationError> {
+        let (sprite_width, sprite_height) = if n == 0 { (16, 16) } else { (8, n as u16) };
+        let bytes_per_row = sprite_width / 8;
+
+        let width = self.display.get_width();
+        let height = self.display.get_height();
+        // the starting position always wraps around
+        let start_col = self.V[x as usize] as u16 % width;
+        let start_row = self.V[y as usize] as u16 % height;
+        let mut collision = false;
+        // the sprites of the selected planes are stored one after the other (XO-CHIP)
+        let mut sprite_address = self.I;
+        for plane in 0..PLANES_COUNT {
+            if self.display.get_selected_planes() & (1 << plane) == 0 {
+                continue;
+            }
+
+            for i in 0..sprite_height {
+                let mut row = 0u16;
+                for byte in 0..bytes_per_row {
+                    row = row << 8
+                        | self.read_memory(sprite_address, i * bytes_per_row + byte)? as u16;
                 }
-                self.display.redraw();
-                self.V[0xF] = collision as u8;
-            }
-            0xE => {
-                // SKP, SKNP
-                match nibbles[2] << 4 | nibbles[3] {
-                    0x9E => {
-                        // SKP Vx
-                        if self.display.get_keyboard_data()[self.V[x as usize] as usize] {
-                            self.PC += 2;
-                        }
+
+                let mut cur_row = start_row + i;
+                if cur_row >= height {
+                    if self.quirks.clip_sprites {
+                        break;
                     }
-                    0xA1 => {
-                        // SKNP Vx
-                        if !self.display.get_keyboard_data()[self.V[x as usize] as usize] {
-                            self.PC += 2;
+                    // wrap around
+                    cur_row %= height;
+                }
+
+                for j in 0..sprite_width {
+                    let mut cur_col = start_col + j;
+                    if cur_col >= width {
+                        if self.quirks.clip_sprites {
+                            break;
                         }
+                        // wrap around
+                        cur_col %= width;
                     }
-                    _ => {}
+
+                    // XOR and check for colliding pixels
+                    collision |= self.display.xor_pixel(
+                        plane,
+                        cur_col,
+                        cur_row,
+                        row & (1 << (sprite_width - 1 - j)) != 0,
+                    );
                 }
             }
-            0xF => {
-                // LD, ADD
-                match nibbles[2] << 4 | nibbles[3] {
-                    0x07 => {
-                        // LD Vx, DT
-                        self.V[x as usize] = self.DT;
-                    }
-                    0x0A => {
-                        // LD Vx, K
-                        self.wait_for_keypress_x = x as i8;
-                    }
-                    0x15 => {
-                        // LD DT, Vx
-                        self.DT = self.V[x as usize];
-                    }
-                    0x18 => {
-                        // LD ST, Vx
-                        self.ST = self.V[x as usize];
-                    }
-                    0x1E => {
-                        // ADD I, Vx
-                        self.I += self.V[x as usize] as u16;
-                    }
-                    0x29 => {
-                        // LD F, Vx
-                        self.I = self.memory.borrow().get_sprite_location(self.V[x as usize]);
-                    }
-                    0x33 => {
-                        // LD B, Vx
-                        let value = self.V[x as usize];
-                        let mut memory = self.memory.borrow_mut();
-                        memory.store(self.I, value / 100);
-                        memory.store(self.I + 1, (value % 100) / 10);
-                        memory.store(self.I + 2, value % 10);
-
-                        self.display.update_memory_debug(memory.get_memory(), false);
-                    }
-                    0x55 => {
-                        // LD [I], Vx
-                        for i in 0..x + 1 {
-                            self.memory.borrow_mut().store(self.I, self.V[i as usize]);
-                            self.I += 1;
-                        }
 
-                        self.display
-                            .update_memory_debug(self.memory.borrow().get_memory(), false);
-                    }
-                    0x65 => {
-                        // LD Vx, [I]
-                        for i in 0..x + 1 {
-                            self.V[i as usize] = self.memory.borrow().get(self.I);
-                            self.I += 1;
-                        }
-                    }
-                    _ => {
-                        // invalid instruction
-                    }
+            sprite_address = self.memory_address(sprite_address, sprite_height * bytes_per_row)?;
+        }
+        self.V[0xF] = collision as u8;
+
+        if self.quirks.display_wait {
+            self.wait_for_vblank = true;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::breakpoint::Watchpoint;
+
+    #[test]
+    fn watchpoints_survive_loading_a_state() {
+        let rom = [
+            0x60, 0x2A, // LD V0, 0x2A
+            0xA3, 0x00, // LD I, 0x300
+            0xF0, 0x55, // LD [I], V0
+            0x12, 0x00, // JP 0x200
+        ];
+        let mut cpu = CPU::new();
+        cpu.read_file(&mut &rom[..]);
+        let state = cpu.save_state();
+        cpu.get_memory_mut()
+            .add_watchpoint(Watchpoint::parse("0x300 w", &Symbols::new()).unwrap());
+
+        for _ in 0..2 {
+            cpu.run_cycles(10).unwrap();
+            assert_eq!(
+                cpu.take_break(),
+                Some(BreakReason::Watchpoint {
+                    pc: 0x204,
+                    addr: 0x300,
+                    access: Access::Write,
+                    old: 0,
+                    new: 0x2A,
+                })
+            );
+            assert_eq!(cpu.get_pc(), 0x206);
+
+            cpu.load_state(&state).unwrap();
+            assert_eq!(cpu.get_memory().get_watchpoints().len(), 1);
+            assert_eq!(cpu.get_memory().get(0x300), 0);
+        }
+    }
+
+    /// execute the first `steps` instructions of `rom` with the quirks
+    fn run(rom: &[u8], quirks: Quirks, steps: usize) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.set_quirks(quirks);
+        cpu.read_file(&mut &rom[..]);
+        for _ in 0..steps {
+            cpu.step().unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn shifts_use_vy_with_the_quirk() {
+        let rom = [
+            0x60, 0x02, // LD V0, 0x02
+            0x61, 0x81, // LD V1, 0x81
+            0x80, 0x16, // SHR V0, V1
+            0x62, 0x02, // LD V2, 0x02
+            0x82, 0x1E, // SHL V2, V1
+        ];
+
+        let cpu = run(&rom, Quirks::modern(), 5);
+        assert_eq!(cpu.get_v()[0], 0x01);
+        assert_eq!(cpu.get_v()[2], 0x04);
+        assert_eq!(cpu.get_v()[0xF], 0);
+
+        let cpu = run(&rom, Quirks::cosmac_vip(), 5);
+        assert_eq!(cpu.get_v()[0], 0x40);
+        assert_eq!(cpu.get_v()[2], 0x02);
+        assert_eq!(cpu.get_v()[0xF], 1);
+    }
+
+    #[test]
+    fn load_and_store_increment_i_with_the_quirk() {
+        let rom = [
+            0xA3, 0x00, // LD I, 0x300
+            0xF2, 0x55, // LD [I], V2
+            0xF1, 0x65, // LD V1, [I]
+        ];
+
+        let cpu = run(&rom, Quirks::modern(), 3);
+        assert_eq!(cpu.get_i(), 0x305);
+
+        let cpu = run(&rom, Quirks::super_chip(), 3);
+        assert_eq!(cpu.get_i(), 0x300);
+    }
+
+    #[test]
+    fn jump_with_offset_uses_vx_with_the_quirk() {
+        let rom = [
+            0x60, 0x04, // LD V0, 4
+            0x62, 0x08, // LD V2, 8
+            0xB2, 0x10, // JP V0, 0x210 (or JP V2, 0x210)
+        ];
+
+        let cpu = run(&rom, Quirks::modern(), 3);
+        assert_eq!(cpu.get_pc(), 0x214);
+
+        let cpu = run(&rom, Quirks::chip48(), 3);
+        assert_eq!(cpu.get_pc(), 0x218);
+    }
+
+    #[test]
+    fn logic_instructions_reset_vf_with_the_quirk() {
+        for op in [0x01, 0x02, 0x03] {
+            let rom = [
+                0x6F, 0x01, // LD VF, 1
+                0x80, op, // OR/AND/XOR V0, V0
+            ];
+
+            let cpu = run(&rom, Quirks::modern(), 2);
+            assert_eq!(cpu.get_v()[0xF], 1);
+
+            let cpu = run(&rom, Quirks::cosmac_vip(), 2);
+            assert_eq!(cpu.get_v()[0xF], 0);
+        }
+    }
+
+    #[test]
+    fn sprites_wrap_or_are_clipped_with_the_quirk() {
+        let rom = [
+            0x60, 60, // LD V0, 60
+            0x61, 30, // LD V1, 30
+            0xA2, 0x0A, // LD I, 0x20A
+            0xD0, 0x13, // DRW V0, V1, 3
+            0x12, 0x08, // JP 0x208
+            0xFF, 0xFF, 0xFF, // the sprite
+        ];
+
+        let cpu = run(&rom, Quirks::modern(), 4);
+        let display = cpu.get_display();
+        assert_eq!(display.get_pixel(63, 31), 1);
+        assert_eq!(display.get_pixel(3, 30), 1);
+        assert_eq!(display.get_pixel(60, 0), 1);
+        assert_eq!(display.get_pixel(3, 0), 1);
+
+        let cpu = run(&rom, Quirks::cosmac_vip(), 4);
+        let display = cpu.get_display();
+        assert_eq!(display.get_pixel(63, 31), 1);
+        assert_eq!(display.get_pixel(3, 30), 0);
+        assert_eq!(display.get_pixel(60, 0), 0);
+        assert_eq!(
+            display
+                .get_data()
+                .iter()
+                .filter(|&&pixel| pixel != 0)
+                .count(),
+            8
+        );
+    }
+
+    /// the lit pixels of the display
+    fn lit_pixels(cpu: &CPU) -> Vec<(u16, u16)> {
+        let display = cpu.get_display();
+        let mut pixels = Vec::new();
+        for y in 0..display.get_height() {
+            for x in 0..display.get_width() {
+                if display.get_pixel(x, y) != 0 {
+                    pixels.push((x, y));
                 }
             }
-            _ => {}
         }
+        pixels
+    }
+
+    #[test]
+    fn hires_draws_16x16_sprites_and_scrolls() {
+        let mut rom = vec![
+            0x00, 0xFF, // HIGH
+            0xA2, 0x10, // LD I, 0x210
+            0xD0, 0x00, // DRW V0, V0, 0
+            0x00, 0xC4, // SCD 4
+            0x00, 0xFB, // SCR
+            0x00, 0xFC, // SCL
+            0x00, 0xFE, // LOW
+            0x00, 0xFD, // EXIT
+        ];
+        // the first row of the 16x16 sprite has its first and last pixels
+        rom.extend_from_slice(&[0x80, 0x01]);
+        rom.extend_from_slice(&[0; 30]);
+
+        let mut cpu = run(&rom, Quirks::super_chip(), 3);
+        assert!(cpu.get_display().is_hires());
+        assert_eq!(cpu.get_display().get_width(), 128);
+        assert_eq!(cpu.get_display().get_height(), 64);
+        assert_eq!(lit_pixels(&cpu), vec![(0, 0), (15, 0)]);
+
+        cpu.step().unwrap();
+        assert_eq!(lit_pixels(&cpu), vec![(0, 4), (15, 4)]);
+        cpu.step().unwrap();
+        assert_eq!(lit_pixels(&cpu), vec![(4, 4), (19, 4)]);
+        cpu.step().unwrap();
+        assert_eq!(lit_pixels(&cpu), vec![(0, 4), (15, 4)]);
+
+        // back to 64x32, cleared
+        cpu.step().unwrap();
+        assert!(!cpu.get_display().is_hires());
+        assert_eq!(cpu.get_display().get_width(), 64);
+        assert_eq!(cpu.get_display().get_height(), 32);
+        assert!(lit_pixels(&cpu).is_empty());
+
+        cpu.step().unwrap();
+        assert!(cpu.has_exited());
+    }
+
+    #[test]
+    fn scrolled_out_pixels_are_lost() {
+        let rom = [
+            0xA2, 0x08, // LD I, 0x208
+            0xD0, 0x01, // DRW V0, V0, 1
+            0x00, 0xFC, // SCL
+            0x00, 0xFB, // SCR
+            0xF0, // the sprite, the first 4 pixels
+        ];
+
+        let cpu = run(&rom, Quirks::super_chip(), 4);
+        assert!(lit_pixels(&cpu).is_empty());
+    }
+
+    #[test]
+    fn skips_jump_over_the_4_bytes_of_long_loads() {
+        let rom = [
+            0x30, 0x00, // SE V0, 0
+            0xF0, 0x00, 0xFF, 0xFE, // LD I, long 0xFFFE
+            0x61, 0x01, // LD V1, 1
+            0x40, 0x00, // SNE V0, 0
+            0xF0, 0x00, 0xFF, 0xFD, // LD I, long 0xFFFD
+            0xF1, 0x55, // LD [I], V1
+        ];
+
+        let mut cpu = run(&rom, Quirks::xo_chip(), 2);
+        assert_eq!(cpu.get_pc(), 0x208);
+        assert_eq!(cpu.get_i(), 0);
+        assert_eq!(cpu.get_v()[1], 1);
+
+        // not skipped, the whole 64KiB can be used
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.get_pc(), 0x210);
+        assert_eq!(cpu.get_memory().get(0xFFFE), 1);
+        assert_eq!(cpu.get_i(), 0xFFFF);
+    }
+
+    #[test]
+    fn sprites_are_drawn_in_the_selected_planes() {
+        let rom = [
+            0xF2, 0x01, // PLANE 2
+            0xA2, 0x10, // LD I, 0x210
+            0xD0, 0x01, // DRW V0, V0, 1
+            0xF3, 0x01, // PLANE 3
+            0x61, 0x08, // LD V1, 8
+            0xD1, 0x01, // DRW V1, V0, 1, a row in each plane
+            0xF1, 0x01, // PLANE 1
+            0x00, 0xE0, // CLS
+            0x80, 0xC0, // the sprites
+        ];
+
+        let mut cpu = run(&rom, Quirks::xo_chip(), 6);
+        let display = cpu.get_display();
+        assert_eq!(display.get_pixel(0, 0), 2);
+        assert_eq!(display.get_pixel(1, 0), 0);
+        assert_eq!(display.get_pixel(8, 0), 3);
+        assert_eq!(display.get_pixel(9, 0), 2);
+
+        // only the selected plane is cleared
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let display = cpu.get_display();
+        assert_eq!(display.get_pixel(0, 0), 2);
+        assert_eq!(display.get_pixel(8, 0), 2);
+        assert_eq!(display.get_pixel(9, 0), 2);
+    }
+
+    // counts V0 down to 0 with recursive calls
+    const RECURSION: [u8; 16] = [
+        0x60, 0x03, // 0x200: LD V0, 3
+        0x22, 0x08, // 0x202: CALL 0x208
+        0x61, 0x01, // 0x204: LD V1, 1
+        0x12, 0x06, // 0x206: JP 0x206
+        0x70, 0xFF, // 0x208: ADD V0, 0xFF
+        0x30, 0x00, // 0x20A: SE V0, 0
+        0x22, 0x08, // 0x20C: CALL 0x208
+        0x00, 0xEE, // 0x20E: RET
+    ];
+
+    fn recursion_cpu() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.read_file(&mut &RECURSION[..]);
+        cpu
+    }
+
+    /// run to the target, returns where it stopped
+    fn run_to(cpu: &mut CPU, target: RunTarget) -> Option<BreakReason> {
+        cpu.run_to(target);
+        cpu.run_cycles(1000).unwrap();
+        cpu.take_break()
+    }
+
+    #[test]
+    fn step_over_stops_after_the_call_at_the_same_depth() {
+        let mut cpu = recursion_cpu();
+        assert_eq!(cpu.get_step_over_target(), None);
+        cpu.step().unwrap();
+
+        let target = cpu.get_step_over_target().unwrap();
+        assert_eq!(
+            target,
+            RunTarget::Address {
+                address: 0x204,
+                max_sp: 0
+            }
+        );
+        assert_eq!(
+            run_to(&mut cpu, target),
+            Some(BreakReason::Reached { pc: 0x204 })
+        );
+        assert_eq!(cpu.get_sp(), 0);
+        assert_eq!(cpu.get_v()[0], 0);
+        // the instruction after the call is not executed yet
+        assert_eq!(cpu.get_v()[1], 0);
+        assert_eq!(cpu.get_run_target(), None);
+    }
+
+    #[test]
+    fn step_over_a_recursive_call_skips_the_inner_frames() {
+        let mut cpu = recursion_cpu();
+        // to the recursive CALL in the first frame
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!((cpu.get_pc(), cpu.get_sp()), (0x20C, 1));
+
+        // the inner frames reach 0x20E first, with more entries in the stack
+        let target = cpu.get_step_over_target().unwrap();
+        assert_eq!(
+            run_to(&mut cpu, target),
+            Some(BreakReason::Reached { pc: 0x20E })
+        );
+        assert_eq!(cpu.get_sp(), 1);
+        assert_eq!(cpu.get_v()[0], 0);
+    }
+
+    #[test]
+    fn step_out_stops_after_a_ret_below_the_current_depth() {
+        let mut cpu = recursion_cpu();
+        assert_eq!(cpu.get_step_out_target(), None);
+        // into the second frame
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        assert_eq!((cpu.get_pc(), cpu.get_sp()), (0x208, 2));
+
+        // the third frame returns to 0x20E in the second frame first
+        let target = cpu.get_step_out_target().unwrap();
+        assert_eq!(target, RunTarget::Return { sp: 2 });
+        assert_eq!(
+            run_to(&mut cpu, target),
+            Some(BreakReason::Reached { pc: 0x20E })
+        );
+        assert_eq!(cpu.get_sp(), 1);
+    }
+
+    #[test]
+    fn run_to_stops_at_any_depth() {
+        let mut cpu = recursion_cpu();
+
+        let target = cpu.get_run_to_target(0x20E);
+        assert_eq!(
+            run_to(&mut cpu, target),
+            Some(BreakReason::Reached { pc: 0x20E })
+        );
+        assert_eq!(cpu.get_sp(), 3);
+
+        let target = cpu.get_run_to_target(0x206);
+        assert_eq!(
+            run_to(&mut cpu, target),
+            Some(BreakReason::Reached { pc: 0x206 })
+        );
+        assert_eq!(cpu.get_v()[1], 1);
+    }
+
+    #[test]
+    fn errors_fault_the_cpu_at_the_failing_instruction() {
+        let cases: [(&[u8], usize, EmulationError); 3] = [
+            (
+                &[
+                    0x22, 0x00, // CALL 0x200
+                ],
+                16,
+                EmulationError::StackOverflow { pc: 0x200 },
+            ),
+            (
+                &[
+                    0x60, 0x01, // LD V0, 1
+                    0x00, 0xEE, // RET
+                ],
+                1,
+                EmulationError::StackUnderflow { pc: 0x202 },
+            ),
+            (
+                &[
+                    0xF0, 0x00, 0xFF, 0xFF, // LD I, long 0xFFFF
+                    0xF1, 0x55, // LD [I], V1
+                ],
+                1,
+                EmulationError::MemoryOutOfBounds {
+                    pc: 0x204,
+                    addr: 0x10000,
+                },
+            ),
+        ];
+
+        for (rom, steps, error) in cases.iter().cloned() {
+            let mut cpu = run(rom, Quirks::xo_chip(), steps);
+            let (v, stack) = (*cpu.get_v(), cpu.get_stack().to_vec());
+
+            assert_eq!(cpu.step(), Err(error.clone()));
+            assert_eq!(cpu.get_fault(), Some(&error));
+            assert_eq!(cpu.get_pc(), error.get_pc());
+
+            // nothing runs until the fault is cleared, then it fails again
+            assert_eq!(cpu.run_frame(), Err(error.clone()));
+            assert_eq!(cpu.get_pc(), error.get_pc());
+            cpu.clear_fault();
+            assert_eq!(cpu.step(), Err(error.clone()));
+
+            // the failing instruction did not change the registers
+            assert_eq!(*cpu.get_v(), v);
+            assert_eq!(cpu.get_stack(), &stack[..]);
+        }
+    }
+
+    #[test]
+    fn errors_describe_the_failing_instruction() {
+        assert_eq!(
+            EmulationError::StackOverflow { pc: 0x200 }.to_string(),
+            "stack overflow at 0200"
+        );
+        assert_eq!(
+            EmulationError::StackUnderflow { pc: 0x202 }.to_string(),
+            "stack underflow at 0202"
+        );
+        let error = EmulationError::MemoryOutOfBounds {
+            pc: 0x204,
+            addr: 0x10000,
+        };
+        assert_eq!(
+            error.to_string(),
+            "memory address 10000 out of bounds at 0204"
+        );
+        let error = EmulationError::InvalidOpcode {
+            pc: 0x206,
+            opcode: 0x5121,
+        };
+        assert_eq!(error.to_string(), "invalid opcode 5121 at 0206");
+        assert_eq!(error.get_pc(), 0x206);
+    }
+
+    #[test]
+    fn invalid_opcodes_follow_the_policy() {
+        for &opcode in &[0x801F, 0x5121] {
+            let [high, low] = u16::to_be_bytes(opcode);
+            let rom = [
+                high, low, // invalid
+                0x61, 0x01, // LD V1, 1
+                0x12, 0x04, // JP 0x204
+            ];
+            let new_cpu = |policy| {
+                let mut cpu = CPU::new();
+                cpu.set_invalid_opcode_policy(policy);
+                cpu.read_file(&mut &rom[..]);
+                cpu
+            };
+
+            // a no-op
+            for policy in [InvalidOpcodePolicy::Ignore, InvalidOpcodePolicy::Log] {
+                let mut cpu = new_cpu(policy);
+                cpu.run_cycles(10).unwrap();
+                assert_eq!(cpu.take_break(), None);
+                assert_eq!(cpu.get_v()[1], 1);
+            }
+
+            // stops after it
+            let mut cpu = new_cpu(InvalidOpcodePolicy::Break);
+            cpu.run_cycles(10).unwrap();
+            assert_eq!(
+                cpu.take_break(),
+                Some(BreakReason::InvalidOpcode { pc: 0x200, opcode })
+            );
+            assert_eq!(cpu.get_pc(), 0x202);
+            assert_eq!(cpu.get_v()[1], 0);
+
+            // faults before it
+            let mut cpu = new_cpu(InvalidOpcodePolicy::Fail);
+            let error = EmulationError::InvalidOpcode { pc: 0x200, opcode };
+            assert_eq!(cpu.run_cycles(10), Err(error.clone()));
+            assert_eq!(cpu.get_fault(), Some(&error));
+            assert_eq!(cpu.get_pc(), 0x200);
+        }
+    }
+
+    #[test]
+    fn setting_pc_clears_the_fault() {
+        let rom = [
+            0x00, 0xEE, // RET with an empty stack
+            0x60, 0x05, // LD V0, 5
+        ];
+        let mut cpu = CPU::new();
+        cpu.read_file(&mut &rom[..]);
+
+        assert_eq!(
+            cpu.step(),
+            Err(EmulationError::StackUnderflow { pc: 0x200 })
+        );
+        assert!(cpu.step().is_err());
+
+        cpu.set_pc(0x202);
+        assert_eq!(cpu.get_fault(), None);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_v()[0], 5);
     }
 }
@@ -1,219 +1,459 @@
+use super::op::Op;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The first address of the roms, where the flow analysis starts
+pub const PROGRAM_START: u16 = 0x200;
+
+// the number of data bytes shown in one `db` line
+const DATA_BYTES_PER_LINE: usize = 8;
+// the maximum number of entries in a jump table used with `JP V0, addr`,
+// V0 can only go up to 0xFF
+const MAX_JUMP_TABLE_ENTRIES: u16 = 0x80;
+
 pub struct Instruction {
-    pub bytes: u16,
+    pub bytes: u16, // the first 16-bit word, for data it is the first byte
     pub address: u16,
+    pub length: u16, // the number of bytes, 4 for `LD I, long addr`
     pub opcode: String,
+    pub op: Option<Op>, // the decoded instruction, `None` for data and invalid instructions
 }
 
-fn generate_instruction_string(instruction: u16) -> String {
-    const INVALID_INSTRUCTION: &str = "??";
-    // nibbles will have the values of the instruction as
-    // each four bytes of the instruction starting from the left as 0
-    //
-    // example: instruction = 0xfa12, nibbles = [0xf, 0xa, 0x1, 0x2]
-    let mut nibbles = [0u8; 4];
-    for i in 0..4 {
-        let offset = (3 - i) * 4;
-        nibbles[i] = ((instruction & (0xf << offset)) >> offset) as u8;
-    }
-
-    // the lowest 12-bit value
-    let address = (nibbles[1] as u16) << 8 | (nibbles[2] as u16) << 4 | (nibbles[3] as u16);
-    // lowest 4-bit value in the high byte
-    let x = nibbles[1];
-    // highest 4-bit value in the low byte
-    let y = nibbles[2];
-    // the lowest 8-bit value
-    let kk = nibbles[2] << 4 | nibbles[3];
-
-    match nibbles[0] {
-        0 => {
-            // CLS, RET, SYS
-            match nibbles[2] << 4 | nibbles[3] {
-                0xE0 => {
-                    // CLS
-                    "CLS".to_string()
-                }
-                0xEE => {
-                    // RET
-                    "RET".to_string()
-                }
-                _ => {
-                    // SYS addr
-                    format!("SYS 0x{:03X}", address)
-                }
-            }
-        }
-        1 => {
-            // JMP addr
-            format!("JMP 0x{:03X}", address)
-        }
-        2 => {
-            // CALL addr
-            format!("CALL 0x{:03X}", address)
-        }
-        3 => {
-            // SE Vx, byte
-            format!("SE V{:01X}, 0x{:02X}", x, kk)
-        }
-        4 => {
-            // SNE Vx, byte
-            format!("SNE V{:01X}, 0x{:02X}", x, kk)
-        }
-        5 => {
-            // SE Vx, Vy
-            format!("SE V{:01X}, V{:01X}", x, y)
+/// What a byte was found to be by `analyze`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteKind {
+    /// not reached by the flow of the program, data or unused
+    Data,
+    /// the first byte of an instruction that can be executed
+    Instruction,
+    /// the rest of the bytes of an instruction
+    Operand,
+}
+
+/// The result of following the flow of a program, see `analyze`
+pub struct Analysis {
+    base: u16,                           // the address of the first byte
+    kinds: Vec<ByteKind>,                // the kind of every byte starting from `base`
+    jumps: BTreeMap<u16, BTreeSet<u16>>, // the targets of `JP` (and jump tables) with the addresses jumping to them
+    calls: BTreeSet<u16>,                // the targets of `CALL`
+    data_references: BTreeSet<u16>, // the addresses loaded into I by `LD I, addr` that are not code
+}
+
+impl Analysis {
+    pub fn get_kind(&self, address: u16) -> ByteKind {
+        address
+            .checked_sub(self.base)
+            .and_then(|index| self.kinds.get(index as usize))
+            .copied()
+            .unwrap_or(ByteKind::Data)
+    }
+
+    pub fn is_instruction(&self, address: u16) -> bool {
+        self.get_kind(address) == ByteKind::Instruction
+    }
+
+    /// the targets of `JP` instructions, each with the addresses of the
+    /// instructions that jump to it
+    pub fn get_jumps(&self) -> &BTreeMap<u16, BTreeSet<u16>> {
+        &self.jumps
+    }
+
+    pub fn get_calls(&self) -> &BTreeSet<u16> {
+        &self.calls
+    }
+
+    /// the addresses used with `LD I, addr` that point to data
+    pub fn get_data_references(&self) -> &BTreeSet<u16> {
+        &self.data_references
+    }
+}
+
+/// the 16-bit word at `index`, the missing bytes after the end are 0
+fn read_word(data: &[u8], index: usize) -> u16 {
+    let high = data.get(index).copied().unwrap_or(0) as u16;
+    let low = data.get(index + 1).copied().unwrap_or(0) as u16;
+    high << 8 | low
+}
+
+/// the part of `memory` (which starts at `base`) inside the 16-bit address space
+fn addressable(memory: &[u8], base: u16) -> &[u8] {
+    &memory[..memory.len().min(0x10000 - base as usize)]
+}
+
+/// decode the instruction at `index`, `None` if it is invalid
+fn decode_instruction(data: &[u8], index: usize) -> Option<Op> {
+    Op::decode(read_word(data, index), read_word(data, index + 2))
+}
+
+/// the text of a decoded instruction, invalid instructions are shown as `??`
+fn instruction_text(op: Option<Op>) -> String {
+    match op {
+        Some(op) => op.to_string(),
+        None => "??".to_string(),
+    }
+}
+
+/// follow the flow of the program in `memory` (which starts at the address
+/// `base`) from all the `entry_points`, through jumps, calls, skips and returns,
+/// to know which bytes are instructions and which are data.
+///
+/// `SYS addr` is followed like `JP addr`, as the CPU executes it.
+/// `JP V0, addr` can't be followed without running the program, the target
+/// is treated as a jump table: `addr` and all the `JP` instructions right
+/// after it are followed.
+pub fn analyze(memory: &[u8], base: u16, entry_points: &[u16]) -> Analysis {
+    let memory = addressable(memory, base);
+    let mut analysis = Analysis {
+        base,
+        kinds: vec![ByteKind::Data; memory.len()],
+        jumps: BTreeMap::new(),
+        calls: BTreeSet::new(),
+        data_references: BTreeSet::new(),
+    };
+
+    let end = base as usize + memory.len();
+    let mut pending = entry_points.to_vec();
+
+    while let Some(address) = pending.pop() {
+        if (address as usize) < base as usize || address as usize + 1 >= end {
+            continue;
         }
-        6 => {
-            // LD Vx, byte
-            format!("LD V{:01X}, 0x{:02X}", x, kk)
+        let index = (address - base) as usize;
+        if analysis.kinds[index] == ByteKind::Instruction {
+            // already followed
+            continue;
         }
-        7 => {
-            // ADD Vx, byte
-            format!("ADD V{:01X}, 0x{:02X}", x, kk)
+
+        let op = match decode_instruction(memory, index) {
+            Some(op) => op,
+            None => continue,
+        };
+
+        let length = op.length();
+        if address as usize + length as usize > end {
+            continue;
         }
-        8 => {
-            // LD, OR, AND, XOR, ADD, SUB, SHR, SUBN, SHL
-            match nibbles[3] {
-                0 => {
-                    // LD Vx, Vy
-                    format!("LD V{:01X}, V{:01X}", x, y)
-                }
-                1 => {
-                    // OR Vx, Vy
-                    format!("OR V{:01X}, V{:01X}", x, y)
-                }
-                2 => {
-                    // AND Vx, Vy
-                    format!("AND V{:01X}, V{:01X}", x, y)
-                }
-                3 => {
-                    // XOR Vx, Vy
-                    format!("XOR V{:01X}, V{:01X}", x, y)
-                }
-                4 => {
-                    // ADD Vx, Vy
-                    format!("ADD V{:01X}, V{:01X}", x, y)
-                }
-                5 => {
-                    // SUB Vx, Vy
-                    format!("SUB V{:01X}, V{:01X}", x, y)
-                }
-                6 => {
-                    // SHR Vx {, Vy}
-                    format!("SHR V{:01X}", x)
-                }
-                7 => {
-                    // SUBN Vx, Vy
-                    format!("SUBN V{:01X}, V{:01X}", x, y)
-                }
-                0xE => {
-                    // SHL Vx {, Vy}
-                    format!("SHL V{:01X}", x)
-                }
-                _ => {
-                    // invalid instruction
-                    INVALID_INSTRUCTION.to_string()
-                }
+        analysis.kinds[index] = ByteKind::Instruction;
+        for operand in analysis.kinds[index + 1..]
+            .iter_mut()
+            .take(length as usize - 1)
+        {
+            if *operand == ByteKind::Data {
+                *operand = ByteKind::Operand;
             }
         }
-        9 => {
-            // SNE Vx, Vy
-            format!("SNE V{:01X}, V{:01X}", x, y)
-        }
-        0xA => {
-            // LD I, addr
-            format!("LD I, 0x{:03X}", address)
-        }
-        0xB => {
-            // JP V0, addr
-            format!("JP V0, 0x{:03X}", address)
-        }
-        0xC => {
-            // RND Vx, byte
-            format!("RND V{:01X}, 0x{:02X}", x, kk)
-        }
-        0xD => {
-            // DRW Vx, Vy, nibble
-            format!("DRW V{:01X}, V{:01X}, 0x{:01x}", x, y, nibbles[3])
-        }
-        0xE => {
-            // SKP, SKNP
-            match nibbles[2] << 4 | nibbles[3] {
-                0x9E => {
-                    // SKP Vx
-                    format!("SKP V{:01X}", x)
-                }
-                0xA1 => {
-                    // SKNP Vx
-                    format!("SKNP V{:01X}", x)
+
+        let next = address.wrapping_add(length);
+
+        match op {
+            Op::Ret | Op::Exit => {
+                // the flow ends here
+            }
+            Op::Jp(target) | Op::Sys(target) => {
+                analysis.jumps.entry(target).or_default().insert(address);
+                pending.push(target);
+            }
+            Op::Call(target) => {
+                analysis.calls.insert(target);
+                pending.push(target);
+                pending.push(next);
+            }
+            _ if op.is_skip() => {
+                // both the next instruction and the one after it can run
+                let next_index = index + length as usize;
+                let skipped = decode_instruction(memory, next_index).map_or(2, |op| op.length());
+                pending.push(next);
+                pending.push(next.wrapping_add(skipped));
+            }
+            Op::LdI(target) | Op::LdILong(target) => {
+                analysis.data_references.insert(target);
+                pending.push(next);
+            }
+            Op::JpV0(target) => {
+                // follow the jump table at `addr`
+                analysis.jumps.entry(target).or_default().insert(address);
+                pending.push(target);
+
+                let mut entry = target;
+                for _ in 0..MAX_JUMP_TABLE_ENTRIES {
+                    if entry < base || entry as usize + 1 >= end {
+                        break;
+                    }
+                    match decode_instruction(memory, (entry - base) as usize) {
+                        Some(Op::Jp(_)) => {}
+                        _ => break,
+                    }
+                    analysis.jumps.entry(entry).or_default().insert(address);
+                    pending.push(entry);
+                    entry = match entry.checked_add(2) {
+                        Some(next_entry) => next_entry,
+                        None => break,
+                    };
                 }
-                _ => INVALID_INSTRUCTION.to_string(),
             }
+            _ => pending.push(next),
         }
-        0xF => {
-            // LD, ADD
-            match nibbles[2] << 4 | nibbles[3] {
-                0x07 => {
-                    // LD Vx, DT
-                    format!("LD V{:01X}, DT", x)
-                }
-                0x0A => {
-                    // LD Vx, K
-                    format!("LD V{:01X}, K", x)
-                }
-                0x15 => {
-                    // LD DT, Vx
-                    format!("LD DT, V{:01X}", x)
-                }
-                0x18 => {
-                    // LD ST, Vx
-                    format!("LD ST, V{:01X}", x)
-                }
-                0x1E => {
-                    // ADD I, Vx
-                    format!("ADD I, V{:01X}", x)
-                }
-                0x29 => {
-                    // LD F, Vx
-                    format!("LD F, V{:01X}", x)
-                }
-                0x33 => {
-                    // LD B, Vx
-                    format!("LD B, V{:01X}", x)
-                }
-                0x55 => {
-                    // LD [I], Vx
-                    format!("LD [I], V{:01X}", x)
-                }
-                0x65 => {
-                    // LD Vx, [I]
-                    format!("LD V{:01X}, [I]", x)
-                }
-                _ => {
-                    // invalid instruction
-                    INVALID_INSTRUCTION.to_string()
-                }
+    }
+
+    // only keep the references that are not code (like self modifying code)
+    let data_references = analysis
+        .data_references
+        .iter()
+        .copied()
+        .filter(|&address| !analysis.is_instruction(address))
+        .collect();
+    analysis.data_references = data_references;
+
+    analysis
+}
+
+/// disassemble `memory` (which starts at `base`) with the result of `analyze`,
+/// the instructions are decoded at their real addresses (even if they are odd)
+/// and the rest is shown as `db` data
+pub fn disassemble_flow(memory: &[u8], base: u16, analysis: &Analysis) -> Vec<Instruction> {
+    let memory = addressable(memory, base);
+    let mut result = Vec::<Instruction>::new();
+
+    let mut index = 0;
+    while index < memory.len() {
+        let address = base + index as u16;
+
+        if analysis.is_instruction(address) {
+            let op = decode_instruction(memory, index);
+            let length = op.map_or(2, |op| op.length());
+
+            result.push(Instruction {
+                address,
+                bytes: read_word(memory, index),
+                length,
+                opcode: instruction_text(op),
+                op,
+            });
+            index += length as usize;
+        } else {
+            // a run of data, until the next instruction or referenced address
+            let start = index;
+            index += 1;
+            while index < memory.len()
+                && index - start < DATA_BYTES_PER_LINE
+                && analysis.get_kind(base + index as u16) == ByteKind::Data
+                && !analysis.data_references.contains(&(base + index as u16))
+                && !analysis.jumps.contains_key(&(base + index as u16))
+            {
+                index += 1;
             }
+
+            let data = &memory[start..index];
+            result.push(Instruction {
+                address,
+                bytes: data[0] as u16,
+                length: data.len() as u16,
+                opcode: format!(
+                    "db {}",
+                    data.iter()
+                        .map(|byte| format!("0x{:02X}", byte))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+                op: None,
+            });
         }
-        _ => INVALID_INSTRUCTION.to_string(),
     }
+
+    result
 }
 
 pub fn disassemble(instructions: &[u8], offset: u16) -> Vec<Instruction> {
+    let instructions = addressable(instructions, offset);
     let mut result = Vec::<Instruction>::with_capacity(instructions.len() / 2);
 
     for i in (0..instructions.len()).step_by(2) {
-        let instruction = if i + 1 >= instructions.len() {
-            (instructions[i] as u16) << 8
-        } else {
-            ((instructions[i] as u16) << 8) | (instructions[i + 1] as u16)
-        };
+        let op = decode_instruction(instructions, i);
         result.push(Instruction {
             address: i as u16 + offset,
-            bytes: instruction,
-            opcode: generate_instruction_string(instruction),
+            bytes: read_word(instructions, i),
+            length: 2,
+            opcode: instruction_text(op),
+            op,
         });
     }
 
     result
 }
+
+/// the name of the label at `address`, or `None` if nothing refers to it
+fn label_name(analysis: &Analysis, address: u16) -> Option<String> {
+    if analysis.calls.contains(&address) {
+        Some(format!("sub_{:03X}", address))
+    } else if let Some(sources) = analysis.jumps.get(&address) {
+        // jumped to from after it, most likely a loop
+        if sources.iter().any(|&source| source >= address) {
+            Some(format!("loop_{:03X}", address))
+        } else {
+            Some(format!("label_{:03X}", address))
+        }
+    } else if analysis.data_references.contains(&address) {
+        Some(format!("data_{:03X}", address))
+    } else {
+        None
+    }
+}
+
+/// disassemble a rom (loaded at `PROGRAM_START`) into assembler source that
+/// assembles back to the same bytes, the targets of jumps and calls, and the
+/// data used with `LD I, addr` get labels, and the data is written with `db`
+pub fn export_source(rom: &[u8]) -> String {
+    let analysis = analyze(rom, PROGRAM_START, &[PROGRAM_START]);
+    let listing = disassemble_flow(rom, PROGRAM_START, &analysis);
+
+    // labels can only be put at the start of a line
+    let labels: BTreeMap<u16, String> = listing
+        .iter()
+        .filter_map(|line| label_name(&analysis, line.address).map(|name| (line.address, name)))
+        .collect();
+    let target = |address: u16, digits: usize| match labels.get(&address) {
+        Some(name) => name.clone(),
+        None => format!("0x{:0digits$X}", address, digits = digits),
+    };
+
+    let mut result = String::new();
+    for line in listing.iter() {
+        if let Some(name) = labels.get(&line.address) {
+            if line.address != PROGRAM_START {
+                result.push('\n');
+            }
+            result.push_str(&format!("{}:\n", name));
+        }
+
+        let text = match line.op {
+            Some(Op::Sys(address)) => format!("SYS {}", target(address, 3)),
+            Some(Op::Jp(address)) => format!("JP {}", target(address, 3)),
+            Some(Op::Call(address)) => format!("CALL {}", target(address, 3)),
+            Some(Op::LdI(address)) => format!("LD I, {}", target(address, 3)),
+            Some(Op::JpV0(address)) => format!("JP V0, {}", target(address, 3)),
+            Some(Op::LdILong(address)) => format!("LD I, long {}", target(address, 4)),
+            _ => line.opcode.clone(),
+        };
+
+        result.push_str(&format!("    {}\n", text));
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::assembler::assemble;
+
+    /// assemble the exported source of `rom`, it must give the same bytes
+    fn assert_round_trip(rom: &[u8]) {
+        let source = export_source(rom);
+        match assemble(&source, "exported.asm") {
+            Ok(assembled) => assert_eq!(assembled, rom, "source:\n{}", source),
+            Err(error) => panic!("{}\nsource:\n{}", error, source),
+        }
+    }
+
+    #[test]
+    fn export_round_trips_code_and_data() {
+        assert_round_trip(&[
+            0x00, 0xE0, // CLS
+            0xA2, 0x0E, // LD I, sprite
+            0x60, 0x00, // LD V0, 0
+            0xD0, 0x05, // DRW V0, V0, 5
+            0x22, 0x12, // CALL sub
+            0x12, 0x08, // JP loop
+            0x00, 0x00, // data, never executed
+            0xF0, 0x90, 0xF0, 0x90, // sprite (odd length)
+            0x70, 0x01, // sub: ADD V0, 1
+            0x30, 0x05, // SE V0, 5
+            0x00, 0xEE, // RET
+            0x8A, 0xB6, // SHR VA, VB
+            0x00, 0xEE, // RET
+            0xAB, // a trailing byte
+        ]);
+    }
+
+    #[test]
+    fn export_round_trips_long_loads_past_0xfff() {
+        let mut rom = vec![0; 0x1000];
+        rom[..10].copy_from_slice(&[
+            0xF0, 0x00, 0x11, 0x00, // LD I, long 0x1100 (data past 0xFFF)
+            0xF0, 0x00, 0x02, 0x08, // LD I, long 0x208, a short address
+            0x12, 0x0A, // JP 0x20A, where SYS 0x000 ends the flow
+        ]);
+        rom[0x1100 - 0x200..0x1104 - 0x200].copy_from_slice(&[1, 2, 3, 4]);
+        assert_round_trip(&rom);
+    }
+
+    #[test]
+    fn export_round_trips_odd_addresses_and_jump_tables() {
+        assert_round_trip(&[
+            0x60, 0x02, // LD V0, 2
+            0xB2, 0x07, // JP V0, table
+            0x00, 0x00, // data
+            0xAA, // one byte of data, the table is at an odd address
+            0x12, 0x0D, // table: JP 0x20D
+            0x12, 0x0F, // JP 0x20F
+            0x00, 0xFD, // EXIT
+            0x00, 0xFD, // EXIT
+        ]);
+    }
+
+    #[test]
+    fn export_round_trips_any_bytes() {
+        // the bytes of a simple random generator, mostly valid instructions
+        let mut seed = 0x1234_5678u32;
+        for length in [2, 31, 256, 4096] {
+            let rom: Vec<u8> = (0..length)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (seed >> 16) as u8
+                })
+                .collect();
+            assert_round_trip(&rom);
+        }
+    }
+
+    #[test]
+    fn sys_is_followed_like_jp() {
+        let rom = [
+            0x02, 0x06, // SYS 0x206
+            0x12, 0x34, // not reached
+            0x12, 0x34, // not reached
+            0x00, 0xEE, // RET
+        ];
+        let analysis = analyze(&rom, PROGRAM_START, &[PROGRAM_START]);
+
+        assert!(analysis.is_instruction(0x206));
+        assert_eq!(analysis.get_kind(0x202), ByteKind::Data);
+        assert!(analysis.get_jumps()[&0x206].contains(&0x200));
+    }
+
+    #[test]
+    fn jump_table_is_followed() {
+        let mut memory = vec![0; 0x1004];
+        memory[0x200..0x202].copy_from_slice(&[0xBF, 0xFC]); // JP V0, 0xFFC
+        memory[0xFFC..0xFFE].copy_from_slice(&[0x12, 0x00]); // JP 0x200
+        memory[0xFFE..0x1000].copy_from_slice(&[0x12, 0x04]); // JP 0x204
+        memory[0x1000..0x1002].copy_from_slice(&[0x00, 0xEE]); // RET, the end of the table
+        memory[0x204..0x206].copy_from_slice(&[0x00, 0xFD]); // EXIT
+
+        let analysis = analyze(&memory, 0, &[0x200]);
+        assert!(analysis.is_instruction(0xFFC));
+        assert!(analysis.is_instruction(0xFFE));
+        assert!(analysis.is_instruction(0x204));
+        assert!(!analysis.is_instruction(0x1000));
+    }
+
+    #[test]
+    fn memory_past_the_address_space_is_ignored() {
+        let rom = vec![0x12; 0x10000];
+        let analysis = analyze(&rom, PROGRAM_START, &[PROGRAM_START]);
+        let listing = disassemble_flow(&rom, PROGRAM_START, &analysis);
+
+        let last = listing.last().unwrap();
+        assert_eq!(last.address as usize + last.length as usize, 0x10000);
+        assert_eq!(disassemble(&rom, PROGRAM_START).len(), 0xFE00 / 2);
+    }
+}
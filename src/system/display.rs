@@ -1,459 +1,212 @@
-use super::disassembler::Instruction;
-use gdk::enums::key;
-use gdk::keyval_to_upper;
-use gio::prelude::*;
-use gtk::prelude::*;
-use gtk::{Application, Builder, DrawingArea, Grid, ListStore, TextBuffer, TreeView, Window};
-use std::cell::{Ref, RefCell};
-use std::rc::Rc;
-
-const APPLICATION_ID: Option<&str> = Some("com.amjad.chip-8");
-pub static mut APPLICATION: Option<Application> = None;
-pub const DEFAULT_PIXEL_SIZE: u16 = 10;
-
-const KEYBOARD_MAPPING: [u32; 16] = [
-    key::X,
-    key::_1,
-    key::_2,
-    key::_3,
-    key::Q,
-    key::W,
-    key::E,
-    key::A,
-    key::S,
-    key::D,
-    key::Z,
-    key::C,
-    key::_4,
-    key::R,
-    key::F,
-    key::V,
-];
-
-const KEYPAD_GRID_MAPPING: [u8; 16] = [13, 0, 1, 2, 4, 5, 6, 8, 9, 10, 12, 14, 3, 7, 11, 15];
+use super::state::{SaveStateError, StateReader, StateWriter};
+
+/// The size of the screen in the normal (low resolution) mode
+pub const LORES_WIDTH: u16 = 64;
+pub const LORES_HEIGHT: u16 = 32;
+/// The size of the screen in the SUPER-CHIP high resolution mode
+pub const HIRES_WIDTH: u16 = 128;
+pub const HIRES_HEIGHT: u16 = 64;
+/// The number of bitplanes (XO-CHIP), each pixel has one bit in every plane
+/// which gives 4 colours
+pub const PLANES_COUNT: u8 = 2;
 
 pub struct Display {
-    window: Rc<RefCell<Window>>,
-    area: DrawingArea,
-    disassembly_list_store: ListStore,
-    disassembly_view: TreeView,
-    disassembly_start_offset: u16,
-    registers_buffer: TextBuffer,
-    stack_buffer: TextBuffer,
-    memory_list_store: ListStore,
-    keypad_grid: Rc<RefCell<Grid>>,
     width: u16,
     height: u16,
-    data: Rc<RefCell<Vec<bool>>>,
-    keyboard: Rc<RefCell<[bool; 16]>>,
+    hires: bool, // is the SUPER-CHIP high resolution mode enabled
+    // every pixel holds the bits of all the planes, bit 0 for plane 0 and so on,
+    // which is the index of the colour of the pixel in the palette
+    data: Vec<u8>,
+    selected_planes: u8, // mask of the planes affected by drawing/clearing/scrolling (XO-CHIP)
+    updated: bool,       // has the framebuffer changed since the last `take_updated`
 }
 
-impl Display {
-    fn build_layout(
-        width: i32,
-        height: i32,
-    ) -> (
-        Window,
-        DrawingArea,
-        ListStore,
-        TreeView,
-        TextBuffer,
-        TextBuffer,
-        ListStore,
-        Grid,
-    ) {
-        let glade_src = include_str!("../../layout.glade");
-
-        let builder = Builder::new_from_string(glade_src);
-        let window: Window = builder.get_object("main_application_window").unwrap();
-        let area: DrawingArea = builder.get_object("canvas").unwrap();
-
-        let disassembly_list_store: ListStore =
-            builder.get_object("disassemblyViewListStore").unwrap();
-        let disassembly_view: TreeView = builder.get_object("disassemblyView").unwrap();
-
-        let registers_buffer: TextBuffer = builder.get_object("registersBuffer").unwrap();
-        let stack_buffer: TextBuffer = builder.get_object("stackBuffer").unwrap();
-        let memory_list_store: ListStore = builder.get_object("memoryViewListStore").unwrap();
-        let keypad_grid: Grid = builder.get_object("keypad").unwrap();
-
-        // assign CSS
-        let provider = gtk::CssProvider::new();
-
-        provider
-            .load_from_data(include_bytes!("../../style.css"))
-            .expect("Failed to load CSS");
-
-        gtk::StyleContext::add_provider_for_screen(
-            &gdk::Screen::get_default().expect("Error initializing gtk css provider."),
-            &provider,
-            gtk::STYLE_PROVIDER_PRIORITY_APPLICATION,
-        );
-
-        area.set_size_request(width, height);
-
-        window.show_all();
-
-        (
-            window,
-            area,
-            disassembly_list_store,
-            disassembly_view,
-            registers_buffer,
-            stack_buffer,
-            memory_list_store,
-            keypad_grid,
-        )
+impl Default for Display {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    pub fn new(width: u16, height: u16) -> Display {
-        if gtk::init().is_err() {
-            println!("Failed to initialize GTK.");
+impl Display {
+    /// creates a display in the low resolution mode
+    pub fn new() -> Display {
+        Display {
+            width: LORES_WIDTH,
+            height: LORES_HEIGHT,
+            hires: false,
+            data: vec![0; (LORES_WIDTH * LORES_HEIGHT) as usize],
+            selected_planes: 1,
+            updated: true,
         }
+    }
 
-        let _application = Application::new(APPLICATION_ID, Default::default())
-            .expect("failed to initialize GTK application");
-
-        // will be added to application before run
-        let (
-            window,
-            area,
-            disassembly_list_store,
-            disassembly_view,
-            registers_buffer,
-            stack_buffer,
-            memory_list_store,
-            keypad_grid,
-        ) = Display::build_layout(
-            (width * DEFAULT_PIXEL_SIZE) as i32,
-            (height * DEFAULT_PIXEL_SIZE) as i32,
-        );
-
-        let display = Display {
-            window: Rc::new(RefCell::new(window)),
-            area: area,
-            disassembly_list_store: disassembly_list_store,
-            disassembly_view: disassembly_view,
-            disassembly_start_offset: 0,
-            registers_buffer: registers_buffer,
-            stack_buffer: stack_buffer,
-            memory_list_store: memory_list_store,
-            keypad_grid: Rc::new(RefCell::new(keypad_grid)),
-            width: width,
-            height: height,
-            data: Rc::new(RefCell::new(vec![false; (width * height) as usize])),
-            keyboard: Rc::new(RefCell::new([false; 16])),
+    /// switch between the low resolution (64x32) and the high resolution
+    /// (128x64) modes, the screen is cleared when switching
+    pub fn set_hires(&mut self, hires: bool) {
+        let (width, height) = if hires {
+            (HIRES_WIDTH, HIRES_HEIGHT)
+        } else {
+            (LORES_WIDTH, LORES_HEIGHT)
         };
 
-        let c_window = display.window.clone();
-
-        _application.connect_activate(move |app: &Application| {
-            let window = &*c_window.borrow();
-            app.add_window(window);
-        });
-        display.setup_drawing();
-
-        unsafe {
-            APPLICATION = Some(_application);
-        }
-
-        display
+        self.hires = hires;
+        self.width = width;
+        self.height = height;
+        self.data = vec![0; (width * height) as usize];
+        self.updated = true;
     }
 
-    fn setup_drawing(&self) {
-        let height = self.height;
-        let width = self.width;
-        let c_data = self.data.clone();
-        self.area.connect_draw(move |_, cr| {
-            for i in 0..height {
-                for j in 0..width {
-                    // black if true, white if false
-                    let color = if c_data.borrow()[(i * width + j) as usize] {
-                        0.
-                    } else {
-                        1.
-                    };
-                    // if its 0, it will result in #000(white)
-                    // if its 1, it will result in #fff(black)
-                    cr.set_source_rgb(color, color, color);
-                    cr.rectangle(
-                        (j * DEFAULT_PIXEL_SIZE) as f64,
-                        (i * DEFAULT_PIXEL_SIZE) as f64,
-                        DEFAULT_PIXEL_SIZE as f64,
-                        DEFAULT_PIXEL_SIZE as f64,
-                    );
-                    cr.fill();
-                }
-            }
-            Inhibit(false)
-        });
+    pub fn is_hires(&self) -> bool {
+        self.hires
     }
 
-    // FIXME: fix to support dynamic instructions building
-    pub fn update_current_instruction_debug(&self, address: u16) {
-        // because we disassemble everything
-        let index = (address / 2) as i32;
-        let iter = self
-            .disassembly_list_store
-            .iter_nth_child(None, index) // parent = None, meaning root children
-            .unwrap();
-        let path = self.disassembly_list_store.get_path(&iter).unwrap();
-
-        self.disassembly_view.get_selection().select_iter(&iter);
-        self.disassembly_view.scroll_to_cell::<gtk::TreeViewColumn>(
-            Some(&path),
-            None,
-            false, // ignore alignment
-            0.5, // align rows to half, but because it will jump to the center, even if it only scrolls one row, it has been disabled
-            0.,  // col_align is not used as this is a single list
-        );
+    pub fn get_height(&self) -> u16 {
+        self.height
     }
 
-    pub fn update_disassembly_debug(&mut self, disassembly: &Vec<Instruction>) {
-        self.disassembly_list_store.clear();
-        if disassembly.len() > 0 {
-            self.disassembly_start_offset = disassembly[0].address;
-        }
-
-        for instruction in disassembly {
-            let current_item = self.disassembly_list_store.append();
-
-            self.disassembly_list_store.set(
-                &current_item,
-                &[0, 1],
-                &[&format!("{:04X}", instruction.address), &instruction.opcode],
-            );
-        }
+    pub fn get_width(&self) -> u16 {
+        self.width
     }
 
-    pub fn update_stack_debug(&self, stack: &[u16]) {
-        let result = stack
-            .iter() // iterate over the stack
-            .rev() // in the reverse order
-            .map(|x| format!("{:04x}", x)) // convert it to a string representation
-            .collect::<Vec<String>>()
-            .join("\n"); // join by newline
-
-        self.stack_buffer.set_text(&result);
-        self.stack_buffer.set_modified(true)
+    /// select the planes that are affected by the next drawing operations,
+    /// bit 0 for plane 0 and bit 1 for plane 1
+    pub fn select_planes(&mut self, planes: u8) {
+        assert!(planes < 1 << PLANES_COUNT);
+        self.selected_planes = planes;
     }
 
-    pub fn update_registers_debug(&self, V: &[u8; 16], I: u16, PC: u16, DT: u8, ST: u8, SP: u8) {
-        let mut result: String = "".to_owned();
-        // V registers
-        for i in 0..V.len() {
-            result.push_str(&format!("V{:1X}: {:02x}  ", i, V[i]));
-            if i % 4 == 3 {
-                result.push('\n');
-            }
-        }
-        result.push('\n');
-
-        // I
-        result.push_str(&format!("I: {:04x}\n\n", I));
-
-        // PC
-        result.push_str(&format!("PC: {:04x}\n\n", PC));
-
-        // DT, ST
-        result.push_str(&format!("DT: {:02x}\n", DT));
-        result.push_str(&format!("ST: {:02x}\n\n", ST));
-
-        // SP
-        result.push_str(&format!("SP: {:02x}", SP));
-
-        self.registers_buffer.set_text(&result);
-        self.registers_buffer.set_modified(true);
+    pub fn get_selected_planes(&self) -> u8 {
+        self.selected_planes
     }
 
-    fn get_hex_string(bytes: &[u8]) -> String {
-        let mut result: String = "".to_owned();
-
-        for byte in bytes {
-            result.push_str(&format!("{:02x} ", byte));
-        }
-
-        result
+    /// the framebuffer in row major order, every pixel is the index of its
+    /// colour (the bits of its planes), `0` means the pixel is off
+    pub fn get_data(&self) -> &[u8] {
+        &self.data
     }
 
-    fn get_ascii_string(bytes: &[u8]) -> String {
-        let mut result: String = "".to_owned();
-
-        for &byte in bytes {
-            let c = byte as char;
-
-            result.push(if c.is_ascii() && !c.is_control() {
-                c
-            } else {
-                '.'
-            });
-        }
-
-        result
+    /// the colour index of the pixel, `0` means the pixel is off
+    pub fn get_pixel(&self, x: u16, y: u16) -> u8 {
+        assert!(x < self.width && y < self.height);
+        self.data[(y * self.width + x) as usize]
     }
 
-    pub fn update_memory_debug(&self, memory: &[u8], first_time: bool) {
-        if first_time {
-            self.memory_list_store.clear();
-        }
-
-        let mut current_item = if first_time {
-            self.memory_list_store.append()
-        } else {
-            self.memory_list_store.get_iter_first().unwrap()
-        };
-
-        for i in (0..memory.len()).step_by(16) {
-            let address = format!("{:04x}", i);
-            let hex = Display::get_hex_string(&memory[i..i + 16]);
-            let ascii = Display::get_ascii_string(&memory[i..i + 16]);
-
-            self.memory_list_store
-                .set(&current_item, &[0, 1, 2], &[&address, &hex, &ascii]);
-
-            // prepare for the next loop
-            if first_time {
-                current_item = self.memory_list_store.append();
-            } else {
-                // in the next loop, this should set current_item to invalid
-                // but should not be used
-                self.memory_list_store.iter_next(&current_item);
-            }
-        }
+    /// returns true if the framebuffer has changed since the last call,
+    /// used by frontends to know when to redraw
+    pub fn take_updated(&mut self) -> bool {
+        let updated = self.updated;
+        self.updated = false;
+        updated
     }
 
-    pub fn redraw(&self) {
-        let window = self.window.borrow();
-        window.queue_draw_area(
-            0,
-            0,
-            window.get_allocated_width(),
-            window.get_allocated_height(),
-        );
+    /// clear the selected planes
+    pub fn clear(&mut self) {
+        for pixel in self.data.iter_mut() {
+            *pixel &= !self.selected_planes;
+        }
+        self.updated = true;
     }
 
-    fn update_keyboard(keyval: u32, keyboard: &mut [bool; 16], value: bool) {
-        let keyval = keyval_to_upper(keyval);
-
-        match KEYBOARD_MAPPING.iter().position(|&x| x == keyval) {
-            Some(index) => {
-                keyboard[index] = value;
-            }
-            None => {}
-        };
-    }
+    /// move the content of the selected planes by (`dx`, `dy`) pixels,
+    /// the pixels moved from outside the screen are empty
+    fn scroll(&mut self, dx: i32, dy: i32) {
+        let width = self.width as i32;
+        let height = self.height as i32;
+        let planes = self.selected_planes;
+        let old_data = self.data.clone();
 
-    fn update_keypad_debug(keypad_grid: &Grid, keyboard: &[bool; 16]) {
-        for (i, &value) in keyboard.iter().enumerate() {
-            let index = KEYPAD_GRID_MAPPING[i as usize];
-            let row = (index / 4) as i32;
-            let col = (index % 4) as i32;
+        for y in 0..height {
+            for x in 0..width {
+                let source_x = x - dx;
+                let source_y = y - dy;
 
-            let child = keypad_grid.get_child_at(col, row).unwrap();
-            let style_context = child.get_style_context();
+                let source =
+                    if source_x >= 0 && source_x < width && source_y >= 0 && source_y < height {
+                        old_data[(source_y * width + source_x) as usize]
+                    } else {
+                        0
+                    };
 
-            if value {
-                style_context.add_class("pressed");
-            } else {
-                style_context.remove_class("pressed");
+                let pixel = &mut self.data[(y * width + x) as usize];
+                *pixel = (*pixel & !planes) | (source & planes);
             }
         }
+        self.updated = true;
     }
 
-    pub fn setup_keyboard<F: 'static>(&self, press_handler: F)
-    where
-        F: Fn(u32) -> (),
-    {
-        let window = self.window.borrow();
-        // FIXME: is there a better way to do this?
-        let keyboard_clone_press = self.keyboard.clone();
-        let keyboard_clone_release = self.keyboard.clone();
-        let keypad_grid_clone_press = self.keypad_grid.clone();
-        let keypad_grid_clone_release = self.keypad_grid.clone();
-
-        window.connect_key_press_event(move |_, event| {
-            let mut keyboard = keyboard_clone_press.borrow_mut();
-            let keypad_grid = keypad_grid_clone_press.borrow();
-
-            Display::update_keyboard(event.get_keyval(), &mut *keyboard, true);
-            Display::update_keypad_debug(&*keypad_grid, &keyboard);
-
-            press_handler(event.get_keyval());
-
-            Inhibit(false)
-        });
-
-        window.connect_key_release_event(move |_, event| {
-            let mut keyboard = keyboard_clone_release.borrow_mut();
-            let keypad_grid = keypad_grid_clone_release.borrow();
-
-            Display::update_keyboard(event.get_keyval(), &mut *keyboard, false);
-            Display::update_keypad_debug(&*keypad_grid, &keyboard);
-
-            Inhibit(false)
-        });
+    /// scroll the screen down by `n` pixels, the top `n` rows become empty
+    pub fn scroll_down(&mut self, n: u16) {
+        self.scroll(0, n as i32);
     }
 
-    pub fn run_in_loop<F: 'static>(&self, interval: u32, func: F)
-    where
-        F: Fn() -> (),
-    {
-        timeout_add(interval, move || {
-            func();
-            Continue(true)
-        });
+    /// scroll the screen up by `n` pixels, the bottom `n` rows become empty
+    pub fn scroll_up(&mut self, n: u16) {
+        self.scroll(0, -(n as i32));
     }
 
-    pub fn run_application() {
-        unsafe {
-            match &APPLICATION {
-                Some(app) => {
-                    app.run(&[]);
-                }
-                None => {
-                    // if there is no application, don't run
-                }
-            }
-        }
+    /// scroll the screen right by `n` pixels, the left `n` columns become empty
+    pub fn scroll_right(&mut self, n: u16) {
+        self.scroll(n as i32, 0);
     }
 
-    pub fn get_height(&self) -> u16 {
-        self.height
+    /// scroll the screen left by `n` pixels, the right `n` columns become empty
+    pub fn scroll_left(&mut self, n: u16) {
+        self.scroll(-(n as i32), 0);
     }
 
-    pub fn get_width(&self) -> u16 {
-        self.width
+    /// write the mode, the selected planes and the framebuffer to a save state
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.hires);
+        writer.write_u8(self.selected_planes);
+        writer.write_bytes(&self.data);
     }
 
-    pub fn get_keyboard_data(&self) -> Ref<[bool; 16]> {
-        self.keyboard.borrow()
-    }
+    /// read the mode, the selected planes and the framebuffer from a save state
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let hires = reader.read_bool()?;
+        let selected_planes = reader.read_u8()?;
+        if selected_planes >= 1 << PLANES_COUNT {
+            return Err(SaveStateError::InvalidData("selected planes"));
+        }
 
-    pub fn get_keyboard_data_copy(&self) -> [bool; 16] {
-        let mut x = [false; 16];
-        for (i, &xx) in self.keyboard.borrow().iter().enumerate() {
-            x[i] = xx;
+        self.set_hires(hires);
+        let data = reader.read_bytes(self.data.len())?;
+        if data.iter().any(|&pixel| pixel >= 1 << PLANES_COUNT) {
+            return Err(SaveStateError::InvalidData("framebuffer"));
         }
-        x
-    }
 
-    pub fn get_disassembly_offset(&self) -> u16 {
-        self.disassembly_start_offset
+        self.data.copy_from_slice(data);
+        self.selected_planes = selected_planes;
+        self.updated = true;
+        Ok(())
     }
 
+    /// set the pixel in all the selected planes
     pub fn draw_pixel(&mut self, x: u16, y: u16, value: bool) {
-        assert_eq!(y * self.width + x < self.width * self.height, true);
-        self.data.borrow_mut()[(y * self.width + x) as usize] = value;
+        assert!(x < self.width && y < self.height);
+        let pixel = &mut self.data[(y * self.width + x) as usize];
+        if value {
+            *pixel |= self.selected_planes;
+        } else {
+            *pixel &= !self.selected_planes;
+        }
+        self.updated = true;
     }
 
-    pub fn xor_pixel(&mut self, x: u16, y: u16, value: bool) -> bool {
-        assert_eq!(y * self.width + x < self.width * self.height, true);
+    /// XOR the pixel in `plane` (the index of the plane, not a mask)
+    pub fn xor_pixel(&mut self, plane: u8, x: u16, y: u16, value: bool) -> bool {
+        assert!(plane < PLANES_COUNT);
+        assert!(x < self.width && y < self.height);
+        let mask = 1 << plane;
         // get a pointer to the value to change
-        let data_ref = &mut self.data.borrow_mut()[(y * self.width + x) as usize];
+        let data_ref = &mut self.data[(y * self.width + x) as usize];
         // collide if both are 1, meaning when XORing, the pixel in the screen
         // will be erased
-        let collision = *data_ref & value;
-        *data_ref ^= value;
-        return collision;
+        let collision = *data_ref & mask != 0 && value;
+        if value {
+            *data_ref ^= mask;
+        }
+        self.updated = true;
+        collision
     }
 }
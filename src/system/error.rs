@@ -0,0 +1,45 @@
+use std::error::Error;
+use std::fmt;
+
+/// An error that stops the execution of the rom, after it happens the CPU
+/// is faulted and does not execute anything until the fault is cleared
+#[derive(Clone, Debug, PartialEq)]
+pub enum EmulationError {
+    /// `CALL` with all the 16 stack entries used
+    StackOverflow { pc: u16 },
+    /// `RET` with an empty stack
+    StackUnderflow { pc: u16 },
+    /// an access (or `I`/`PC`) past the end of the 64KiB memory
+    MemoryOutOfBounds { pc: u16, addr: u32 },
+    /// an instruction that is not part of any of the supported instruction sets
+    InvalidOpcode { pc: u16, opcode: u16 },
+}
+
+impl EmulationError {
+    /// the address of the instruction that caused the error
+    pub fn get_pc(&self) -> u16 {
+        match *self {
+            EmulationError::StackOverflow { pc }
+            | EmulationError::StackUnderflow { pc }
+            | EmulationError::MemoryOutOfBounds { pc, .. }
+            | EmulationError::InvalidOpcode { pc, .. } => pc,
+        }
+    }
+}
+
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            EmulationError::StackOverflow { pc } => write!(f, "stack overflow at {:04X}", pc),
+            EmulationError::StackUnderflow { pc } => write!(f, "stack underflow at {:04X}", pc),
+            EmulationError::MemoryOutOfBounds { pc, addr } => {
+                write!(f, "memory address {:05X} out of bounds at {:04X}", addr, pc)
+            }
+            EmulationError::InvalidOpcode { pc, opcode } => {
+                write!(f, "invalid opcode {:04X} at {:04X}", opcode, pc)
+            }
+        }
+    }
+}
+
+impl Error for EmulationError {}
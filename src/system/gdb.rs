@@ -0,0 +1,619 @@
+use super::breakpoint::{Access, Breakpoint, Operand, Watchpoint};
+use super::cpu::{BreakReason, CPU};
+use super::error::EmulationError;
+use super::memory::MEMORY_SIZE;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// the registers in the order of the `g` packet and the target description,
+// I and PC are 2 bytes (little endian), the others 1 byte
+const REGISTERS: [(&str, Operand); 21] = [
+    ("v0", Operand::V(0x0)),
+    ("v1", Operand::V(0x1)),
+    ("v2", Operand::V(0x2)),
+    ("v3", Operand::V(0x3)),
+    ("v4", Operand::V(0x4)),
+    ("v5", Operand::V(0x5)),
+    ("v6", Operand::V(0x6)),
+    ("v7", Operand::V(0x7)),
+    ("v8", Operand::V(0x8)),
+    ("v9", Operand::V(0x9)),
+    ("va", Operand::V(0xA)),
+    ("vb", Operand::V(0xB)),
+    ("vc", Operand::V(0xC)),
+    ("vd", Operand::V(0xD)),
+    ("ve", Operand::V(0xE)),
+    ("vf", Operand::V(0xF)),
+    ("i", Operand::I),
+    ("pc", Operand::PC),
+    ("sp", Operand::SP),
+    ("dt", Operand::DT),
+    ("st", Operand::ST),
+];
+
+// the size of the packets we accept, told to gdb in `qSupported`
+const PACKET_SIZE: usize = 0x1000;
+
+// the frames run by `continue` between the checks for an interrupt (Ctrl-C) from gdb
+const FRAME_DURATION: Duration = Duration::from_micros(1_000_000 / 60);
+
+// the signals reported to gdb in the stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// the register sizes in bytes
+fn register_size(register: Operand) -> usize {
+    match register {
+        Operand::I | Operand::PC => 2,
+        _ => 1,
+    }
+}
+
+/// the target description sent to gdb (`qXfer:features:read:target.xml`)
+fn target_description() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n  \
+         <feature name=\"org.chip8.core\">\n",
+    );
+
+    for (name, register) in REGISTERS.iter() {
+        let size = register_size(*register) * 8;
+        let kind = match register {
+            Operand::I => "data_ptr".to_owned(),
+            Operand::PC => "code_ptr".to_owned(),
+            _ => format!("uint{}", size),
+        };
+        xml.push_str(&format!(
+            "    <reg name=\"{}\" bitsize=\"{}\" type=\"{}\"/>\n",
+            name, size, kind
+        ));
+    }
+
+    xml.push_str("  </feature>\n</target>\n");
+    xml
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// parse `<hex address>,<hex length>`
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let (address, length) = text.split_at(text.find(',')?);
+    Some((
+        u32::from_str_radix(address, 16).ok()?,
+        u32::from_str_radix(&length[1..], 16).ok()?,
+    ))
+}
+
+/// did the connection fail because gdb went away
+fn is_disconnection(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+    )
+}
+
+/// A packet (or an interrupt) received from gdb
+enum Command {
+    Packet(String),
+    Interrupt, // Ctrl-C, 0x03 outside of a packet
+}
+
+/// What to do after answering a packet
+enum Action {
+    Reply(String),
+    Continue,
+    Step,
+    Detach,
+    Kill,
+}
+
+/// The connection with gdb over the remote serial protocol
+struct Connection {
+    stream: TcpStream,
+    input: Vec<u8>, // the received bytes that are not part of a handled packet yet
+    no_ack: bool,   // gdb asked to stop sending `+` for every packet (`QStartNoAckMode`)
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Connection {
+        Connection {
+            stream,
+            input: Vec::new(),
+            no_ack: false,
+        }
+    }
+
+    /// read more bytes, returns false if the connection was closed
+    /// (or nothing was received in non blocking mode)
+    fn fill_input(&mut self) -> io::Result<bool> {
+        let mut buffer = [0; 1024];
+        match self.stream.read(&mut buffer) {
+            Ok(0) => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed",
+            )),
+            Ok(length) => {
+                self.input.extend_from_slice(&buffer[..length]);
+                Ok(true)
+            }
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// the next packet or interrupt in the input, the packets with a wrong
+    /// checksum are asked again
+    fn parse_command(&mut self) -> io::Result<Option<Command>> {
+        loop {
+            match self.input.first() {
+                None => return Ok(None),
+                Some(0x03) => {
+                    self.input.remove(0);
+                    return Ok(Some(Command::Interrupt));
+                }
+                Some(b'$') => {}
+                // acks and noise between packets
+                Some(_) => {
+                    self.input.remove(0);
+                    continue;
+                }
+            }
+
+            let end = match self.input.iter().position(|&byte| byte == b'#') {
+                // the checksum is 2 hex digits after `#`
+                Some(end) if end + 2 < self.input.len() => end,
+                _ => return Ok(None),
+            };
+
+            let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+            let data = &packet[1..end];
+            let checksum = std::str::from_utf8(&packet[end + 1..])
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+
+            if !self.no_ack {
+                let valid = checksum == Some(data.iter().fold(0u8, |sum, &x| sum.wrapping_add(x)));
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+                if !valid {
+                    continue;
+                }
+            }
+
+            return Ok(Some(Command::Packet(
+                String::from_utf8_lossy(data).into_owned(),
+            )));
+        }
+    }
+
+    /// wait for the next packet or interrupt
+    fn receive(&mut self) -> io::Result<Command> {
+        loop {
+            if let Some(command) = self.parse_command()? {
+                return Ok(command);
+            }
+            self.fill_input()?;
+        }
+    }
+
+    /// has gdb sent an interrupt, without waiting
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let result = self.fill_input();
+        self.stream.set_nonblocking(false)?;
+        result?;
+
+        // only the interrupts are expected while running, the rest waits
+        match self.input.iter().position(|&byte| byte == 0x03) {
+            Some(position) => {
+                self.input.remove(position);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        // `#`, `$`, `}` and `*` are escaped as `}` followed by the byte xor 0x20
+        for &byte in data.as_bytes() {
+            if let b'#' | b'$' | b'}' | b'*' = byte {
+                packet.push(b'}');
+                packet.push(byte ^ 0x20);
+            } else {
+                packet.push(byte);
+            }
+        }
+        let checksum = packet[1..].iter().fold(0u8, |sum, &x| sum.wrapping_add(x));
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+
+        self.stream.write_all(&packet)
+    }
+}
+
+/// A GDB remote serial protocol stub controlling the CPU, see `CPU::run_gdb_server`
+struct GdbStub {
+    cpu: CPU,
+    stop_reply: String, // the reason of the last stop, sent again for `?`
+}
+
+impl GdbStub {
+    fn new(cpu: CPU) -> GdbStub {
+        GdbStub {
+            cpu,
+            stop_reply: format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    /// serve one gdb connection until it detaches, returns true if gdb killed the target
+    fn serve(&mut self, stream: TcpStream) -> io::Result<bool> {
+        stream.set_nodelay(true)?;
+        let mut connection = Connection::new(stream);
+
+        loop {
+            let packet = match connection.receive()? {
+                Command::Packet(packet) => packet,
+                // not running, the CPU is already stopped
+                Command::Interrupt => {
+                    connection.send(&self.stop_reply)?;
+                    continue;
+                }
+            };
+
+            if packet == "QStartNoAckMode" {
+                connection.send("OK")?;
+                connection.no_ack = true;
+                continue;
+            }
+
+            match self.handle_packet(&packet) {
+                Action::Reply(reply) => connection.send(&reply)?,
+                Action::Step => {
+                    let result = self.cpu.step();
+                    self.stop_reply = self.get_stop_reply(result);
+                    connection.send(&self.stop_reply)?;
+                }
+                Action::Continue => {
+                    self.stop_reply = self.continue_until_stop(&mut connection)?;
+                    connection.send(&self.stop_reply)?;
+                }
+                Action::Detach => {
+                    connection.send("OK")?;
+                    return Ok(false);
+                }
+                Action::Kill => return Ok(true),
+            }
+        }
+    }
+
+    /// run the CPU at the normal speed until it stops or gdb interrupts it,
+    /// returns the stop reply
+    fn continue_until_stop(&mut self, connection: &mut Connection) -> io::Result<String> {
+        let mut next_frame = Instant::now();
+
+        loop {
+            let result = self.cpu.run_frame();
+            if result.is_err() || self.cpu.has_exited() || self.cpu.get_fault().is_some() {
+                return Ok(self.get_stop_reply(result));
+            }
+            if let Some(reason) = self.cpu.take_break() {
+                return Ok(self.get_break_reply(&reason));
+            }
+
+            if connection.poll_interrupt()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+
+            next_frame += FRAME_DURATION;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            } else {
+                // too slow, do not try to catch up
+                next_frame = now;
+            }
+        }
+    }
+
+    /// the stop reply after running or stepping
+    fn get_stop_reply(&mut self, result: Result<(), EmulationError>) -> String {
+        if self.cpu.has_exited() {
+            return "W00".to_owned();
+        }
+
+        match result {
+            Ok(()) => match self.cpu.take_break() {
+                Some(reason) => self.get_break_reply(&reason),
+                None => format!("S{:02x}", SIGTRAP),
+            },
+            Err(EmulationError::InvalidOpcode { .. }) => format!("S{:02x}", SIGILL),
+            Err(_) => format!("S{:02x}", SIGSEGV),
+        }
+    }
+
+    fn get_break_reply(&self, reason: &BreakReason) -> String {
+        match *reason {
+            BreakReason::InvalidOpcode { .. } => format!("S{:02x}", SIGILL),
+            BreakReason::Breakpoint { .. } => format!("T{:02x}swbreak:;", SIGTRAP),
+            BreakReason::Watchpoint {
+                addr,
+                access: Access::Read,
+                ..
+            } => format!("T{:02x}rwatch:{:x};", SIGTRAP, addr),
+            BreakReason::Watchpoint {
+                addr,
+                access: Access::Write,
+                ..
+            } => format!("T{:02x}watch:{:x};", SIGTRAP, addr),
+            BreakReason::Watchpoint { .. } | BreakReason::Reached { .. } => {
+                format!("S{:02x}", SIGTRAP)
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, packet: &str) -> Action {
+        let (command, arguments) = packet.split_at(packet.len().min(1));
+
+        let reply = match command {
+            "?" => Some(self.stop_reply.clone()),
+            "g" => Some(self.read_registers()),
+            "G" => self.write_registers(arguments),
+            "p" => self.read_register(arguments),
+            "P" => self.write_register(arguments),
+            "m" => self.read_memory(arguments),
+            "M" => self.write_memory(arguments),
+            "Z" => self.set_breakpoint(arguments, true),
+            "z" => self.set_breakpoint(arguments, false),
+            "s" => return Action::Step,
+            "c" => return Action::Continue,
+            "D" => return Action::Detach,
+            "k" => return Action::Kill,
+            "H" => Some("OK".to_owned()),
+            "q" => self.query(arguments),
+            // anything else is not supported, gdb uses the packets above instead
+            _ => Some(String::new()),
+        };
+
+        Action::Reply(reply.unwrap_or_else(|| "E01".to_owned()))
+    }
+
+    fn query(&self, query: &str) -> Option<String> {
+        if query.starts_with("Supported") {
+            return Some(format!(
+                "PacketSize={:x};QStartNoAckMode+;qXfer:features:read+;swbreak+",
+                PACKET_SIZE
+            ));
+        }
+
+        if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let xml = target_description();
+            let (offset, length) = parse_range(range)?;
+            let start = (offset as usize).min(xml.len());
+            let end = (start + length as usize).min(xml.len());
+            // `m` if there is more to read, `l` for the last part
+            let marker = if end < xml.len() { 'm' } else { 'l' };
+            return Some(format!("{}{}", marker, &xml[start..end]));
+        }
+
+        Some(
+            match query {
+                "Attached" => "1",
+                "C" => "QC1",
+                "fThreadInfo" => "m1",
+                "sThreadInfo" => "l",
+                _ => "",
+            }
+            .to_owned(),
+        )
+    }
+
+    fn get_register_bytes(&self, register: Operand) -> Vec<u8> {
+        let value = register.get_value(&self.cpu).to_le_bytes();
+        value[..register_size(register)].to_vec()
+    }
+
+    fn set_register_bytes(&mut self, register: Operand, bytes: &[u8]) -> Option<()> {
+        if bytes.len() != register_size(register) {
+            return None;
+        }
+
+        let value = bytes
+            .iter()
+            .rev()
+            .fold(0u16, |value, &byte| value << 8 | byte as u16);
+        // writing PC also clears a fault (see `CPU::set_pc`)
+        register.set_value(&mut self.cpu, value).ok()
+    }
+
+    fn read_registers(&self) -> String {
+        REGISTERS
+            .iter()
+            .map(|&(_, register)| to_hex(&self.get_register_bytes(register)))
+            .collect()
+    }
+
+    fn write_registers(&mut self, arguments: &str) -> Option<String> {
+        let mut bytes = &from_hex(arguments)?[..];
+
+        for &(_, register) in REGISTERS.iter() {
+            let size = register_size(register);
+            if bytes.len() < size {
+                return None;
+            }
+            self.set_register_bytes(register, &bytes[..size])?;
+            bytes = &bytes[size..];
+        }
+
+        Some("OK".to_owned())
+    }
+
+    fn read_register(&self, arguments: &str) -> Option<String> {
+        let (_, register) = REGISTERS.get(usize::from_str_radix(arguments, 16).ok()?)?;
+        Some(to_hex(&self.get_register_bytes(*register)))
+    }
+
+    fn write_register(&mut self, arguments: &str) -> Option<String> {
+        let (number, value) = arguments.split_at(arguments.find('=')?);
+        let (_, register) = REGISTERS.get(usize::from_str_radix(number, 16).ok()?)?;
+
+        self.set_register_bytes(*register, &from_hex(&value[1..])?)?;
+        Some("OK".to_owned())
+    }
+
+    fn read_memory(&self, arguments: &str) -> Option<String> {
+        let (address, length) = parse_range(arguments)?;
+        if address as usize >= MEMORY_SIZE {
+            return None;
+        }
+
+        // only what fits in the memory and in a packet is read
+        let end = (address as usize + length as usize)
+            .min(MEMORY_SIZE)
+            .min(address as usize + PACKET_SIZE / 2);
+        Some(to_hex(
+            &self.cpu.get_memory().get_memory()[address as usize..end],
+        ))
+    }
+
+    fn write_memory(&mut self, arguments: &str) -> Option<String> {
+        let (range, data) = arguments.split_at(arguments.find(':')?);
+        let (address, length) = parse_range(range)?;
+        let bytes = from_hex(&data[1..])?;
+
+        if bytes.len() != length as usize || address as usize + bytes.len() > MEMORY_SIZE {
+            return None;
+        }
+
+        let memory = self.cpu.get_memory_mut();
+        for (offset, &byte) in bytes.iter().enumerate() {
+            memory.store((address as usize + offset) as u16, byte);
+        }
+        Some("OK".to_owned())
+    }
+
+    /// `Z<type>,<address>,<kind>` sets and `z...` removes a breakpoint (type 0 and 1)
+    /// or a write (2), read (3) or access (4) watchpoint of `kind` bytes
+    fn set_breakpoint(&mut self, arguments: &str, insert: bool) -> Option<String> {
+        let mut parts = arguments.split(',');
+        let kind = parts.next()?;
+        let address = u16::from_str_radix(parts.next()?, 16).ok()?;
+        let length = u16::from_str_radix(parts.next()?.split(';').next()?, 16).ok()?;
+
+        match kind {
+            "0" | "1" => {
+                if insert {
+                    self.cpu.add_breakpoint(Breakpoint::new(address));
+                } else {
+                    self.cpu.remove_breakpoint(address);
+                }
+            }
+            "2" | "3" | "4" => {
+                let mut watchpoint =
+                    Watchpoint::new(address, address.checked_add(length.max(1) - 1)?);
+                watchpoint.write = kind != "3";
+                watchpoint.read = kind != "2";
+
+                let memory = self.cpu.get_memory_mut();
+                if insert {
+                    memory.add_watchpoint(watchpoint);
+                } else {
+                    memory.remove_watchpoint(&watchpoint);
+                }
+            }
+            // not supported
+            _ => return Some(String::new()),
+        }
+
+        Some("OK".to_owned())
+    }
+}
+
+impl CPU {
+    /// let gdb (or another debugger speaking the GDB remote serial protocol)
+    /// control the CPU, without a window, from `127.0.0.1:<port>`.
+    ///
+    /// The registers are V0..VF, I, PC, SP, DT and ST (see the target description),
+    /// gdb can read and write them and the memory, set breakpoints and
+    /// watchpoints, step and continue (which runs at the normal speed until
+    /// it is interrupted with Ctrl-C). The connections are served one after
+    /// the other, also when gdb goes away without detaching, until gdb kills
+    /// the target. The other errors of the connections and the listener are
+    /// returned.
+    ///
+    /// After a fault, writing PC (even to the same address) clears it.
+    pub fn run_gdb_server(self, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let mut stub = GdbStub::new(self);
+
+        loop {
+            eprintln!("Waiting for gdb on 127.0.0.1:{}", port);
+            let (stream, address) = listener.accept()?;
+            eprintln!("gdb connected from {}", address);
+
+            match stub.serve(stream) {
+                Ok(true) => return Ok(()),
+                Ok(false) => eprintln!("gdb detached"),
+                // gdb went away without detaching, wait for the next one
+                Err(error) if is_disconnection(&error) => {
+                    eprintln!("gdb disconnected: {}", error)
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writing_pc_clears_the_fault() {
+        let rom = [
+            0x00, 0xEE, // RET with an empty stack
+            0x60, 0x05, // LD V0, 5
+        ];
+        let mut cpu = CPU::new();
+        cpu.read_file(&mut &rom[..]);
+        let mut stub = GdbStub::new(cpu);
+
+        let result = stub.cpu.step();
+        assert_eq!(stub.get_stop_reply(result), format!("S{:02x}", SIGSEGV));
+        // stepping again fails the same way
+        let result = stub.cpu.step();
+        assert_eq!(stub.get_stop_reply(result), format!("S{:02x}", SIGSEGV));
+
+        // PC is register 0x11, little endian
+        match stub.handle_packet("P11=0202") {
+            Action::Reply(reply) => assert_eq!(reply, "OK"),
+            _ => panic!("expected a reply"),
+        }
+        assert_eq!(stub.cpu.get_fault(), None);
+
+        let result = stub.cpu.step();
+        assert_eq!(stub.get_stop_reply(result), format!("S{:02x}", SIGTRAP));
+        assert_eq!(stub.cpu.get_v()[0], 5);
+    }
+}
@@ -0,0 +1,1422 @@
+use super::breakpoint::Operand;
+use super::callstack::{Frame, StackImbalance};
+use super::cpu::{BreakReason, CPU};
+use super::disassembler::{self, Analysis, Instruction, PROGRAM_START};
+use super::display::{LORES_HEIGHT, LORES_WIDTH};
+use super::error::EmulationError;
+use super::rewind::{Rewind, DEFAULT_REWIND_MEMORY};
+use super::sound::Sound;
+use super::symbols::Symbols;
+use gdk::enums::key;
+use gdk::keyval_to_upper;
+use gio::prelude::*;
+use gtk::prelude::*;
+use gtk::{
+    Application, Builder, CellRendererText, DrawingArea, Grid, Label, ListStore, TextBuffer,
+    TextTag, TextView, TreeView, Window,
+};
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::rc::Rc;
+
+const APPLICATION_ID: Option<&str> = Some("com.amjad.chip-8");
+thread_local! {
+    // the application of the window, created by `Gui::new`
+    pub static APPLICATION: RefCell<Option<Application>> = const { RefCell::new(None) };
+}
+pub const DEFAULT_PIXEL_SIZE: u16 = 10;
+
+// the background of the registers, stack entries and memory rows changed by the last step
+const CHANGED_COLOR: &str = "#FFD54F";
+const CHANGED_TAG: &str = "changed";
+
+/// The colours of the pixels (RGB), indexed by the planes bits of the pixel,
+/// the first two are the normal CHIP-8 colours and the other two are used
+/// by XO-CHIP for the second plane
+const PALETTE: [(f64, f64, f64); 4] = [
+    (1., 1., 1.),    // white (off)
+    (0., 0., 0.),    // black (plane 0)
+    (0.6, 0.6, 0.6), // light gray (plane 1)
+    (0.3, 0.3, 0.3), // dark gray (both planes)
+];
+
+const KEYBOARD_MAPPING: [u32; 16] = [
+    key::X,
+    key::_1,
+    key::_2,
+    key::_3,
+    key::Q,
+    key::W,
+    key::E,
+    key::A,
+    key::S,
+    key::D,
+    key::Z,
+    key::C,
+    key::_4,
+    key::R,
+    key::F,
+    key::V,
+];
+
+const KEYPAD_GRID_MAPPING: [u8; 16] = [13, 0, 1, 2, 4, 5, 6, 8, 9, 10, 12, 14, 3, 7, 11, 15];
+
+/// The GTK window, it shows the screen of the CPU and the debugger views
+pub struct Gui {
+    window: Rc<RefCell<Window>>,
+    area: DrawingArea,
+    disassembly_list_store: ListStore,
+    disassembly_view: TreeView,
+    disassembly_addresses: Rc<RefCell<Vec<u16>>>, // the address of every row of the disassembly
+    shown_disassembly: Vec<(String, String)>, // the text of every row of the disassembly, to only update the rows that changed
+    registers_view: TextView,
+    registers_buffer: TextBuffer,
+    stack_view: TextView,
+    stack_buffer: TextBuffer,
+    memory_list_store: ListStore,
+    memory_hex_renderer: CellRendererText,
+    shown_memory: Vec<u8>, // the memory shown in the memory view, to only update the changed rows
+    highlighted_rows: Vec<usize>, // the memory rows highlighted by the last update
+    shown_registers: Vec<u16>, // V0..VF, I, PC, DT, ST and SP as shown in the registers view
+    shown_stack: Vec<u16>, // the stack shown in the stack view
+    call_stack_list_store: ListStore,
+    call_stack_view: TreeView,
+    call_stack_addresses: Rc<RefCell<Vec<u16>>>, // where the disassembly jumps for every row of the call stack
+    call_stack_warning: Label,
+    shown_call_stack: (Vec<Frame>, Option<StackImbalance>), // the call stack shown, to only update it when it changes
+    keypad_grid: Rc<RefCell<Grid>>,
+    width: u16,
+    screen: Rc<RefCell<Vec<u8>>>,
+    screen_size: Rc<Cell<(u16, u16)>>, // the resolution of `screen`, it changes in SUPER-CHIP hires mode
+    keyboard: Rc<RefCell<[bool; 16]>>,
+}
+
+impl Default for Gui {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gui {
+    fn build_layout(
+        width: i32,
+        height: i32,
+    ) -> (
+        Window,
+        DrawingArea,
+        ListStore,
+        TreeView,
+        TextView,
+        TextView,
+        ListStore,
+        CellRendererText,
+        ListStore,
+        TreeView,
+        Label,
+        Grid,
+    ) {
+        let glade_src = include_str!("../../layout.glade");
+
+        let builder = Builder::new_from_string(glade_src);
+        let window: Window = builder.get_object("main_application_window").unwrap();
+        let area: DrawingArea = builder.get_object("canvas").unwrap();
+
+        let disassembly_list_store: ListStore =
+            builder.get_object("disassemblyViewListStore").unwrap();
+        let disassembly_view: TreeView = builder.get_object("disassemblyView").unwrap();
+
+        let registers_view: TextView = builder.get_object("registersTextView").unwrap();
+        let stack_view: TextView = builder.get_object("stackTextView").unwrap();
+        let memory_list_store: ListStore = builder.get_object("memoryViewListStore").unwrap();
+        let memory_hex_renderer: CellRendererText =
+            builder.get_object("hexColumnRenderer").unwrap();
+        // only shown on the rows with the `changed` column set
+        for id in &[
+            "addressColumnRenderer",
+            "hexColumnRenderer",
+            "asciiColumnRenderer",
+        ] {
+            let renderer: CellRendererText = builder.get_object(id).unwrap();
+            renderer.set_property_cell_background(Some(CHANGED_COLOR));
+        }
+        let call_stack_list_store: ListStore = builder.get_object("callStackListStore").unwrap();
+        let call_stack_view: TreeView = builder.get_object("callStackView").unwrap();
+        let call_stack_warning: Label = builder.get_object("callStackWarningLabel").unwrap();
+        let keypad_grid: Grid = builder.get_object("keypad").unwrap();
+
+        // assign CSS
+        let provider = gtk::CssProvider::new();
+
+        provider
+            .load_from_data(include_bytes!("../../style.css"))
+            .expect("Failed to load CSS");
+
+        gtk::StyleContext::add_provider_for_screen(
+            &gdk::Screen::get_default().expect("Error initializing gtk css provider."),
+            &provider,
+            gtk::STYLE_PROVIDER_PRIORITY_APPLICATION,
+        );
+
+        area.set_size_request(width, height);
+
+        window.show_all();
+
+        (
+            window,
+            area,
+            disassembly_list_store,
+            disassembly_view,
+            registers_view,
+            stack_view,
+            memory_list_store,
+            memory_hex_renderer,
+            call_stack_list_store,
+            call_stack_view,
+            call_stack_warning,
+            keypad_grid,
+        )
+    }
+
+    /// creates the window, the canvas has the size of the low resolution screen
+    /// and the high resolution screen is drawn with smaller pixels
+    pub fn new() -> Gui {
+        let width = LORES_WIDTH;
+        let height = LORES_HEIGHT;
+
+        if gtk::init().is_err() {
+            println!("Failed to initialize GTK.");
+        }
+
+        let _application = Application::new(APPLICATION_ID, Default::default())
+            .expect("failed to initialize GTK application");
+
+        // will be added to application before run
+        let (
+            window,
+            area,
+            disassembly_list_store,
+            disassembly_view,
+            registers_view,
+            stack_view,
+            memory_list_store,
+            memory_hex_renderer,
+            call_stack_list_store,
+            call_stack_view,
+            call_stack_warning,
+            keypad_grid,
+        ) = Gui::build_layout(
+            (width * DEFAULT_PIXEL_SIZE) as i32,
+            (height * DEFAULT_PIXEL_SIZE) as i32,
+        );
+
+        let gui = Gui {
+            window: Rc::new(RefCell::new(window)),
+            area,
+            disassembly_list_store,
+            disassembly_view,
+            disassembly_addresses: Rc::new(RefCell::new(Vec::new())),
+            shown_disassembly: Vec::new(),
+            registers_buffer: registers_view.get_buffer().unwrap(),
+            registers_view,
+            stack_buffer: stack_view.get_buffer().unwrap(),
+            stack_view,
+            memory_list_store,
+            memory_hex_renderer,
+            shown_memory: Vec::new(),
+            highlighted_rows: Vec::new(),
+            shown_registers: Vec::new(),
+            shown_stack: Vec::new(),
+            call_stack_list_store,
+            call_stack_view,
+            call_stack_addresses: Rc::new(RefCell::new(Vec::new())),
+            call_stack_warning,
+            shown_call_stack: (Vec::new(), None),
+            keypad_grid: Rc::new(RefCell::new(keypad_grid)),
+            width,
+            screen: Rc::new(RefCell::new(vec![0; (width * height) as usize])),
+            screen_size: Rc::new(Cell::new((width, height))),
+            keyboard: Rc::new(RefCell::new([false; 16])),
+        };
+
+        Gui::add_changed_tag(&gui.registers_buffer);
+        Gui::add_changed_tag(&gui.stack_buffer);
+
+        let c_window = gui.window.clone();
+
+        _application.connect_activate(move |app: &Application| {
+            let window = &*c_window.borrow();
+            app.add_window(window);
+        });
+        gui.setup_drawing();
+
+        APPLICATION.with(|application| *application.borrow_mut() = Some(_application));
+
+        gui
+    }
+
+    fn setup_drawing(&self) {
+        let canvas_width = self.width * DEFAULT_PIXEL_SIZE;
+        let c_screen = self.screen.clone();
+        let c_screen_size = self.screen_size.clone();
+        self.area.connect_draw(move |_, cr| {
+            let (width, height) = c_screen_size.get();
+            // the canvas has the same size in both resolutions
+            let pixel_size = canvas_width as f64 / width as f64;
+
+            for i in 0..height {
+                for j in 0..width {
+                    let (r, g, b) = PALETTE[c_screen.borrow()[(i * width + j) as usize] as usize];
+                    cr.set_source_rgb(r, g, b);
+                    cr.rectangle(
+                        j as f64 * pixel_size,
+                        i as f64 * pixel_size,
+                        pixel_size,
+                        pixel_size,
+                    );
+                    cr.fill();
+                }
+            }
+            Inhibit(false)
+        });
+    }
+
+    /// copy the framebuffer of the CPU display to be drawn in the next redraw
+    pub fn update_screen(&self, data: &[u8], width: u16, height: u16) {
+        let mut screen = self.screen.borrow_mut();
+        screen.clear();
+        screen.extend_from_slice(data);
+        self.screen_size.set((width, height));
+
+        self.redraw();
+    }
+
+    pub fn update_current_instruction_debug(&self, address: u16) {
+        Gui::select_disassembly_address(
+            &self.disassembly_view,
+            &self.disassembly_list_store,
+            &self.disassembly_addresses.borrow(),
+            address,
+        );
+    }
+
+    /// select and scroll to the row of the disassembly containing `address`
+    fn select_disassembly_address(
+        disassembly_view: &TreeView,
+        disassembly_list_store: &ListStore,
+        addresses: &[u16],
+        address: u16,
+    ) {
+        // the row containing the address
+        let index = match addresses.binary_search(&address) {
+            Ok(index) => index,
+            Err(0) => return,
+            Err(index) => index - 1,
+        } as i32;
+        let iter = disassembly_list_store
+            .iter_nth_child(None, index) // parent = None, meaning root children
+            .unwrap();
+        let path = disassembly_list_store.get_path(&iter).unwrap();
+
+        disassembly_view.get_selection().select_iter(&iter);
+        disassembly_view.scroll_to_cell::<gtk::TreeViewColumn>(
+            Some(&path),
+            None,
+            false, // ignore alignment
+            0.5, // align rows to half, but because it will jump to the center, even if it only scrolls one row, it has been disabled
+            0.,  // col_align is not used as this is a single list
+        );
+    }
+
+    /// show the instructions, the ones with a breakpoint (`breakpoints` is sorted) are marked,
+    /// the addresses used by the instructions and the ones of the rows are shown with their symbols.
+    ///
+    /// Only the rows between the first and the last one that changed are
+    /// replaced, toggling a breakpoint or editing a byte changes a few rows
+    pub fn update_disassembly_debug(
+        &mut self,
+        disassembly: &[Instruction],
+        breakpoints: &[u16],
+        symbols: &Symbols,
+    ) {
+        let mut rows = Vec::with_capacity(disassembly.len());
+        for instruction in disassembly {
+            let marker = if breakpoints.binary_search(&instruction.address).is_ok() {
+                "\u{25CF}"
+            } else {
+                " "
+            };
+            let address = match symbols.get_name(instruction.address) {
+                Some(name) => format!("{} {:04X} {}", marker, instruction.address, name),
+                None => format!("{} {:04X}", marker, instruction.address),
+            };
+            let opcode = match instruction.op {
+                Some(op) => symbols.format_op(&op),
+                None => instruction.opcode.clone(),
+            };
+            rows.push((address, opcode));
+        }
+
+        // the rows that are the same at the start and at the end
+        let shown = &self.shown_disassembly;
+        let unchanged_start = shown
+            .iter()
+            .zip(rows.iter())
+            .take_while(|(shown, row)| shown == row)
+            .count();
+        let unchanged_end = shown[unchanged_start..]
+            .iter()
+            .rev()
+            .zip(rows[unchanged_start..].iter().rev())
+            .take_while(|(shown, row)| shown == row)
+            .count();
+
+        let removed = shown.len() - unchanged_start - unchanged_end;
+        if let Some(iter) = self
+            .disassembly_list_store
+            .iter_nth_child(None, unchanged_start as i32)
+        {
+            // `remove` moves the iterator to the next row
+            for _ in 0..removed {
+                self.disassembly_list_store.remove(&iter);
+            }
+        }
+        for (i, (address, opcode)) in rows[unchanged_start..rows.len() - unchanged_end]
+            .iter()
+            .enumerate()
+        {
+            let current_item = self
+                .disassembly_list_store
+                .insert((unchanged_start + i) as i32);
+            self.disassembly_list_store
+                .set(&current_item, &[0, 1], &[address, opcode]);
+        }
+
+        *self.disassembly_addresses.borrow_mut() = disassembly
+            .iter()
+            .map(|instruction| instruction.address)
+            .collect();
+        self.shown_disassembly = rows;
+    }
+
+    /// call `handler` with the address of the instruction when its address is
+    /// clicked in the disassembly, clicking the instruction only selects it
+    pub fn setup_disassembly_click<F>(&self, handler: F)
+    where
+        F: Fn(u16) + 'static,
+    {
+        let addresses = self.disassembly_addresses.clone();
+
+        self.disassembly_view.set_activate_on_single_click(true);
+        self.disassembly_view
+            .connect_row_activated(move |view, path, column| {
+                if view.get_column(0).as_ref() != Some(column) {
+                    return;
+                }
+
+                let index = path.get_indices().first().copied().unwrap_or(-1);
+                if let Some(&address) = addresses.borrow().get(index as usize) {
+                    handler(address);
+                }
+            });
+    }
+
+    /// show the calls in the stack (the innermost first) with their symbols,
+    /// and the imbalance the next instruction would cause
+    pub fn update_call_stack_debug(
+        &mut self,
+        frames: &[Frame],
+        imbalance: Option<StackImbalance>,
+        symbols: &Symbols,
+    ) {
+        if self.shown_call_stack.0 == frames && self.shown_call_stack.1 == imbalance {
+            return;
+        }
+
+        let format_address = |address: u16| match symbols.describe(address) {
+            Some(name) => format!("{:04x} <{}>", address, name),
+            None => format!("{:04x}", address),
+        };
+
+        let mut addresses = self.call_stack_addresses.borrow_mut();
+        self.call_stack_list_store.clear();
+        addresses.clear();
+
+        for frame in frames {
+            let mut notes = Vec::new();
+            if frame.call_site.is_none() {
+                notes.push("not after a CALL".to_owned());
+            }
+            if frame.recursion > 0 {
+                notes.push(format!("recursion {} deep", frame.recursion + 1));
+            }
+
+            // without a call site the frame is shown where it returns
+            addresses.push(frame.call_site.unwrap_or(frame.return_address));
+
+            let current_item = self.call_stack_list_store.append();
+            self.call_stack_list_store.set(
+                &current_item,
+                &[0, 1, 2, 3],
+                &[
+                    &frame.entry.map_or_else(|| "?".to_owned(), format_address),
+                    &frame
+                        .call_site
+                        .map_or_else(|| "?".to_owned(), format_address),
+                    &format_address(frame.return_address),
+                    &notes.join(", "),
+                ],
+            );
+        }
+
+        match imbalance {
+            Some(imbalance) => {
+                self.call_stack_warning
+                    .set_text(&format!("\u{26A0} {}", imbalance));
+                self.call_stack_warning.show();
+            }
+            None => self.call_stack_warning.hide(),
+        }
+
+        self.shown_call_stack = (frames.to_vec(), imbalance);
+    }
+
+    /// double-clicking a frame of the call stack shows its call site in the disassembly
+    pub fn setup_call_stack_click(&self) {
+        let addresses = self.call_stack_addresses.clone();
+        let disassembly_view = self.disassembly_view.clone();
+        let disassembly_list_store = self.disassembly_list_store.clone();
+        let disassembly_addresses = self.disassembly_addresses.clone();
+
+        self.call_stack_view
+            .connect_row_activated(move |_, path, _| {
+                let index = path.get_indices().first().copied().unwrap_or(-1);
+                if let Some(&address) = addresses.borrow().get(index as usize) {
+                    Gui::select_disassembly_address(
+                        &disassembly_view,
+                        &disassembly_list_store,
+                        &disassembly_addresses.borrow(),
+                        address,
+                    );
+                }
+            });
+    }
+
+    /// the address of the instruction selected in the disassembly
+    pub fn get_selected_disassembly_address(&self) -> Option<u16> {
+        let (model, iter) = self.disassembly_view.get_selection().get_selected()?;
+        let index = *model.get_path(&iter)?.get_indices().first()?;
+
+        self.disassembly_addresses
+            .borrow()
+            .get(index as usize)
+            .copied()
+    }
+
+    /// the tag of the values changed by the last step in the registers and stack views
+    fn add_changed_tag(buffer: &TextBuffer) {
+        let tag = TextTag::new(Some(CHANGED_TAG));
+        tag.set_property_background(Some(CHANGED_COLOR));
+        buffer.get_tag_table().unwrap().add(&tag);
+    }
+
+    /// set the text of the buffer, the `changed` ranges (byte indices in `text`) are highlighted
+    fn set_highlighted_text(buffer: &TextBuffer, text: &str, changed: &[(usize, usize)]) {
+        // the buffer counts in characters, the symbols can have non-ASCII names
+        let offset = |index: usize| text[..index].chars().count() as i32;
+
+        buffer.set_text(text);
+        for &(start, end) in changed {
+            buffer.apply_tag_by_name(
+                CHANGED_TAG,
+                &buffer.get_iter_at_offset(offset(start)),
+                &buffer.get_iter_at_offset(offset(end)),
+            );
+        }
+        buffer.set_modified(true);
+    }
+
+    /// show the stack, the top first (with the symbols of the return addresses),
+    /// with `highlight` the entries that changed since the last update are highlighted
+    pub fn update_stack_debug(&mut self, stack: &[u16], symbols: &Symbols, highlight: bool) {
+        let mut result = String::new();
+        let mut changed = Vec::new();
+
+        for (i, &entry) in stack.iter().enumerate().rev() {
+            if !result.is_empty() {
+                result.push('\n');
+            }
+            let start = result.len();
+            result.push_str(&format!("{:04x}", entry));
+            if let Some(name) = symbols.describe(entry) {
+                result.push_str(&format!(" <{}>", name));
+            }
+
+            if highlight && self.shown_stack.get(i) != Some(&entry) {
+                changed.push((start, result.len()));
+            }
+        }
+
+        Gui::set_highlighted_text(&self.stack_buffer, &result, &changed);
+        self.shown_stack = stack.to_vec();
+    }
+
+    /// show the registers and the reason of the last fault or break, with
+    /// `highlight` the registers that changed since the last update are highlighted
+    #[allow(non_snake_case, clippy::too_many_arguments)]
+    pub fn update_registers_debug(
+        &mut self,
+        V: &[u8; 16],
+        I: u16,
+        PC: u16,
+        DT: u8,
+        ST: u8,
+        SP: u8,
+        fault: Option<&EmulationError>,
+        break_reason: Option<&BreakReason>,
+        highlight: bool,
+    ) {
+        // every register with the text that follows it
+        let mut registers: Vec<(u16, String, &str)> = V
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| {
+                let separator = if i % 4 == 3 { "  \n" } else { "  " };
+                (v as u16, format!("V{:1X}: {:02x}", i, v), separator)
+            })
+            .collect();
+        registers[15].2 = "  \n\n";
+        registers.push((I, format!("I: {:04x}", I), "\n\n"));
+        registers.push((PC, format!("PC: {:04x}", PC), "\n\n"));
+        registers.push((DT as u16, format!("DT: {:02x}", DT), "\n"));
+        registers.push((ST as u16, format!("ST: {:02x}", ST), "\n\n"));
+        registers.push((SP as u16, format!("SP: {:02x}", SP), ""));
+
+        let mut result = String::new();
+        let mut changed = Vec::new();
+        for (i, (value, text, separator)) in registers.iter().enumerate() {
+            if highlight
+                && self
+                    .shown_registers
+                    .get(i)
+                    .is_some_and(|shown| shown != value)
+            {
+                changed.push((result.len(), result.len() + text.len()));
+            }
+            result.push_str(text);
+            result.push_str(separator);
+        }
+
+        if let Some(fault) = fault {
+            result.push_str(&format!("\n\nFAULT: {}", fault));
+        } else if let Some(break_reason) = break_reason {
+            result.push_str(&format!("\n\nBREAK: {}", break_reason));
+        }
+
+        Gui::set_highlighted_text(&self.registers_buffer, &result, &changed);
+        self.shown_registers = registers.iter().map(|&(value, _, _)| value).collect();
+    }
+
+    fn get_hex_string(bytes: &[u8]) -> String {
+        let mut result: String = "".to_owned();
+
+        for byte in bytes {
+            result.push_str(&format!("{:02x} ", byte));
+        }
+
+        result
+    }
+
+    fn get_ascii_string(bytes: &[u8]) -> String {
+        let mut result: String = "".to_owned();
+
+        for &byte in bytes {
+            let c = byte as char;
+
+            result.push(if c.is_ascii() && !c.is_control() {
+                c
+            } else {
+                '.'
+            });
+        }
+
+        result
+    }
+
+    fn set_memory_row(&self, iter: &gtk::TreeIter, address: usize, bytes: &[u8]) {
+        let address = format!("{:04x}", address);
+        let hex = Gui::get_hex_string(bytes);
+        let ascii = Gui::get_ascii_string(bytes);
+
+        self.memory_list_store
+            .set(iter, &[0, 1, 2], &[&address, &hex, &ascii]);
+    }
+
+    /// show the memory, with `highlight` the rows that changed since the last
+    /// update are highlighted (the ones highlighted before are not anymore)
+    pub fn update_memory_debug(&mut self, memory: &[u8], first_time: bool, highlight: bool) {
+        for row in self.highlighted_rows.drain(..) {
+            if let Some(iter) = self.memory_list_store.iter_nth_child(None, row as i32) {
+                self.memory_list_store.set(&iter, &[3], &[&false]);
+            }
+        }
+
+        if first_time {
+            self.memory_list_store.clear();
+
+            for i in (0..memory.len()).step_by(16) {
+                let current_item = self.memory_list_store.append();
+                self.set_memory_row(&current_item, i, &memory[i..i + 16]);
+            }
+        } else {
+            // the memory is large (64KiB), so only update the rows that changed
+            for i in (0..memory.len()).step_by(16) {
+                if memory[i..i + 16] == self.shown_memory[i..i + 16] {
+                    continue;
+                }
+
+                if let Some(current_item) =
+                    self.memory_list_store.iter_nth_child(None, (i / 16) as i32)
+                {
+                    self.set_memory_row(&current_item, i, &memory[i..i + 16]);
+
+                    if highlight {
+                        self.memory_list_store.set(&current_item, &[3], &[&true]);
+                        self.highlighted_rows.push(i / 16);
+                    }
+                }
+            }
+        }
+
+        self.shown_memory.clear();
+        self.shown_memory.extend_from_slice(memory);
+    }
+
+    /// are there memory rows highlighted, they stay until the next `update_memory_debug`
+    pub fn has_memory_highlights(&self) -> bool {
+        !self.highlighted_rows.is_empty()
+    }
+
+    /// allow editing the registers, the stack and the memory (only while paused)
+    pub fn set_debug_editable(&self, editable: bool) {
+        for view in &[&self.registers_view, &self.stack_view] {
+            view.set_editable(editable);
+            view.set_cursor_visible(editable);
+        }
+        self.memory_hex_renderer.set_property_editable(editable);
+    }
+
+    /// call `handler` with the edits of the registers and stack views (applied
+    /// with `<ENTER>`) and of the memory rows
+    pub fn setup_debug_editing<F>(&self, handler: F)
+    where
+        F: Fn(DebugEdit) + 'static,
+    {
+        let handler = Rc::new(handler);
+
+        for &(view, is_stack) in &[(&self.registers_view, false), (&self.stack_view, true)] {
+            let handler = handler.clone();
+            view.connect_key_press_event(move |view, event| {
+                let keyval = event.get_keyval();
+                if !view.get_editable() || (keyval != key::Return && keyval != key::KP_Enter) {
+                    return Inhibit(false);
+                }
+
+                let buffer = view.get_buffer().unwrap();
+                let (start, end) = buffer.get_bounds();
+                let text = buffer
+                    .get_text(&start, &end, false)
+                    .map(|text| text.to_string())
+                    .unwrap_or_default();
+
+                handler(if is_stack {
+                    DebugEdit::Stack(text)
+                } else {
+                    DebugEdit::Registers(text)
+                });
+
+                // give the keyboard back to the CPU and the debugger keys
+                if let Ok(window) = view.get_toplevel().unwrap().downcast::<Window>() {
+                    window.set_focus(None::<&gtk::Widget>);
+                }
+                Inhibit(true)
+            });
+        }
+
+        self.memory_hex_renderer
+            .connect_edited(move |_, path, text| {
+                if let Some(&row) = path.get_indices().first() {
+                    handler(DebugEdit::Memory(row as u16 * 16, text.to_owned()));
+                }
+            });
+    }
+
+    /// is the user typing in one of the debugger views
+    fn is_editing(window: &Window) -> bool {
+        match window.get_focus() {
+            // the cells of the memory view are edited in an entry
+            Some(widget) => {
+                widget.is::<gtk::Entry>()
+                    || widget
+                        .downcast::<TextView>()
+                        .is_ok_and(|view| view.get_editable())
+            }
+            None => false,
+        }
+    }
+
+    pub fn redraw(&self) {
+        let window = self.window.borrow();
+        window.queue_draw_area(
+            0,
+            0,
+            window.get_allocated_width(),
+            window.get_allocated_height(),
+        );
+    }
+
+    fn update_keyboard(keyval: u32, keyboard: &mut [bool; 16], value: bool) {
+        let keyval = keyval_to_upper(keyval);
+
+        if let Some(index) = KEYBOARD_MAPPING.iter().position(|&x| x == keyval) {
+            keyboard[index] = value;
+        }
+    }
+
+    fn update_keypad_debug(keypad_grid: &Grid, keyboard: &[bool; 16]) {
+        for (i, &value) in keyboard.iter().enumerate() {
+            let index = KEYPAD_GRID_MAPPING[i];
+            let row = (index / 4) as i32;
+            let col = (index % 4) as i32;
+
+            let child = keypad_grid.get_child_at(col, row).unwrap();
+            let style_context = child.get_style_context();
+
+            if value {
+                style_context.add_class("pressed");
+            } else {
+                style_context.remove_class("pressed");
+            }
+        }
+    }
+
+    pub fn setup_keyboard<F, G>(&self, press_handler: F, release_handler: G)
+    where
+        F: Fn(u32) + 'static,
+        G: Fn(u32) + 'static,
+    {
+        let window = self.window.borrow();
+        // FIXME: is there a better way to do this?
+        let keyboard_clone_press = self.keyboard.clone();
+        let keyboard_clone_release = self.keyboard.clone();
+        let keypad_grid_clone_press = self.keypad_grid.clone();
+        let keypad_grid_clone_release = self.keypad_grid.clone();
+
+        window.connect_key_press_event(move |window, event| {
+            // the keys typed in the debugger views are not for the CPU
+            if Gui::is_editing(window) {
+                return Inhibit(false);
+            }
+
+            let mut keyboard = keyboard_clone_press.borrow_mut();
+            let keypad_grid = keypad_grid_clone_press.borrow();
+
+            Gui::update_keyboard(event.get_keyval(), &mut keyboard, true);
+            Gui::update_keypad_debug(&keypad_grid, &keyboard);
+
+            press_handler(event.get_keyval());
+
+            Inhibit(false)
+        });
+
+        window.connect_key_release_event(move |_, event| {
+            let mut keyboard = keyboard_clone_release.borrow_mut();
+            let keypad_grid = keypad_grid_clone_release.borrow();
+
+            Gui::update_keyboard(event.get_keyval(), &mut keyboard, false);
+            Gui::update_keypad_debug(&keypad_grid, &keyboard);
+
+            release_handler(event.get_keyval());
+
+            Inhibit(false)
+        });
+    }
+
+    pub fn run_in_loop<F>(&self, interval: u32, func: F)
+    where
+        F: Fn() + 'static,
+    {
+        timeout_add(interval, move || {
+            func();
+            Continue(true)
+        });
+    }
+
+    pub fn run_application() {
+        // not borrowed while running, the handlers may need it
+        let application = APPLICATION.with(|application| application.borrow().clone());
+        match application {
+            Some(app) => {
+                app.run(&[]);
+            }
+            None => {
+                // if there is no application, don't run
+            }
+        }
+    }
+
+    pub fn get_keyboard_data_copy(&self) -> [bool; 16] {
+        *self.keyboard.borrow()
+    }
+}
+
+/// A debugger command from the keyboard that runs until a `RunTarget`, done in the next loop
+#[derive(Clone, Copy, PartialEq)]
+enum RunCommand {
+    StepOver,    // run the `CALL` at PC until it returns, other instructions are stepped
+    StepOut,     // run until the current subroutine returns
+    RunToCursor, // run until the instruction selected in the disassembly
+}
+
+/// A change typed by the user in a debugger view, applied to the CPU in the next loop
+pub enum DebugEdit {
+    Registers(String),   // the text of the registers view
+    Stack(String),       // the text of the stack view
+    Memory(u16, String), // the hex text of the memory row starting at the address
+}
+
+/// The options of the GTK frontend, see `CPU::run_display_application_with_options`
+pub struct GuiOptions {
+    /// the save state slot `n` is stored in `<state_prefix>.<n>.state`
+    pub state_prefix: String,
+    /// the memory limit of the rewind buffer in bytes, `0` disables rewinding
+    pub rewind_memory: usize,
+}
+
+impl Default for GuiOptions {
+    fn default() -> Self {
+        GuiOptions {
+            state_prefix: "chip_8".to_owned(),
+            rewind_memory: DEFAULT_REWIND_MEMORY,
+        }
+    }
+}
+
+/// A save state action requested with the keyboard, done in the next loop
+#[derive(Clone, Copy)]
+enum StateRequest {
+    Save(u8), // save to the slot number
+    Load(u8), // load from the slot number
+}
+
+// the number of save state slots, saved with F1..F4 and loaded with F5..F8
+const STATE_SLOTS: u8 = 4;
+
+/// The state of the GTK frontend that is shared with the event loop
+struct Frontend {
+    cpu: CPU,
+    gui: Gui,
+    sound: Sound, // The sound output, plays the audio pattern of the CPU while ST is not zero
+
+    single_stepping: Rc<RefCell<bool>>, // Is debugging single step enabled?
+    run_next: Rc<RefCell<bool>>, // can we run run the next instruction in the next loop? (only in single_stepping)
+    last_break: Option<BreakReason>, // the reason of the last break, shown until the next step
+
+    state_prefix: String, // the save state slot `n` is stored in `<state_prefix>.<n>.state`
+    state_request: Rc<Cell<Option<StateRequest>>>, // save state action from the keyboard
+
+    rewind: Rewind, // snapshots before every frame (or step in single_stepping)
+    rewinding: Rc<Cell<bool>>, // is the rewind key held? go back one snapshot every loop
+    step_back: Rc<Cell<bool>>, // go back one snapshot in the next loop
+    run_command: Rc<Cell<Option<RunCommand>>>, // step over/out or run to cursor, only in single_stepping
+    breakpoint_toggle: Rc<Cell<Option<u16>>>, // the address clicked in the disassembly, toggled in the next loop
+    debug_edits: Rc<RefCell<Vec<DebugEdit>>>, // the edits of the debugger views, applied in the next loop
+
+    entry_points: Vec<u16>, // where the flow of the program is followed from to disassemble it
+    disassembly_analysis: Option<Analysis>, // the analysis of the shown disassembly
+}
+
+impl Frontend {
+    fn new(cpu: CPU, options: GuiOptions) -> Frontend {
+        let gui = Gui::new();
+
+        let mut frontend = Frontend {
+            cpu,
+            gui,
+            sound: Sound::new(),
+            single_stepping: Rc::new(RefCell::new(false)),
+            run_next: Rc::new(RefCell::new(false)),
+            last_break: None,
+            state_prefix: options.state_prefix,
+            state_request: Rc::new(Cell::new(None)),
+            rewind: Rewind::new(options.rewind_memory),
+            rewinding: Rc::new(Cell::new(false)),
+            step_back: Rc::new(Cell::new(false)),
+            run_command: Rc::new(Cell::new(None)),
+            breakpoint_toggle: Rc::new(Cell::new(None)),
+            debug_edits: Rc::new(RefCell::new(Vec::new())),
+            entry_points: vec![PROGRAM_START],
+            disassembly_analysis: None,
+        };
+
+        frontend.setup_keyboard();
+
+        let breakpoint_toggle_c = frontend.breakpoint_toggle.clone();
+        frontend
+            .gui
+            .setup_disassembly_click(move |address| breakpoint_toggle_c.set(Some(address)));
+
+        let debug_edits_c = frontend.debug_edits.clone();
+        frontend
+            .gui
+            .setup_debug_editing(move |edit| debug_edits_c.borrow_mut().push(edit));
+        frontend.gui.setup_call_stack_click();
+
+        frontend
+            .gui
+            .update_memory_debug(frontend.cpu.get_memory().get_memory(), true, false);
+        frontend.cpu.get_memory_mut().take_modified();
+
+        frontend.update_disassembly_debug();
+        frontend
+            .gui
+            .update_current_instruction_debug(frontend.cpu.get_pc());
+
+        frontend
+    }
+
+    fn setup_keyboard(&self) {
+        let single_stepping_c = self.single_stepping.clone();
+        let run_next_c = self.run_next.clone();
+        let state_request_c = self.state_request.clone();
+        let rewinding_press_c = self.rewinding.clone();
+        let rewinding_release_c = self.rewinding.clone();
+        let step_back_c = self.step_back.clone();
+        let run_command_c = self.run_command.clone();
+
+        let press_handler = move |k| {
+            let mut single_stepping = single_stepping_c.borrow_mut();
+            let mut run_next = run_next_c.borrow_mut();
+            // Toggle debug with SPACE
+            // Step with N
+            if k == key::space {
+                *single_stepping = !*single_stepping;
+            }
+            if k == key::n {
+                *run_next = true;
+            }
+            // Step over with O
+            // Step out with U
+            // Run to the selected instruction with G
+            if k == key::o {
+                run_command_c.set(Some(RunCommand::StepOver));
+            }
+            if k == key::u {
+                run_command_c.set(Some(RunCommand::StepOut));
+            }
+            if k == key::g {
+                run_command_c.set(Some(RunCommand::RunToCursor));
+            }
+            // Save state slots with F1..F4
+            // Load state slots with F5..F8
+            if k >= key::F1 && k < key::F1 + STATE_SLOTS as u32 {
+                state_request_c.set(Some(StateRequest::Save((k - key::F1) as u8 + 1)));
+            }
+            if k >= key::F5 && k < key::F5 + STATE_SLOTS as u32 {
+                state_request_c.set(Some(StateRequest::Load((k - key::F5) as u8 + 1)));
+            }
+            // Rewind while BACKSPACE is held
+            // Step back with B
+            if k == key::BackSpace {
+                rewinding_press_c.set(true);
+            }
+            if k == key::b {
+                step_back_c.set(true);
+            }
+        };
+
+        let release_handler = move |k| {
+            if k == key::BackSpace {
+                rewinding_release_c.set(false);
+            }
+        };
+
+        self.gui.setup_keyboard(press_handler, release_handler);
+    }
+
+    /// disassemble the memory by following the flow of the program from the entry points
+    fn update_disassembly_debug(&mut self) {
+        let memory = self.cpu.get_memory().get_memory();
+        let analysis = disassembler::analyze(memory, 0, &self.entry_points);
+        let breakpoints: Vec<u16> = self
+            .cpu
+            .get_breakpoints()
+            .map(|breakpoint| breakpoint.address)
+            .collect();
+
+        self.gui.update_disassembly_debug(
+            &disassembler::disassemble_flow(memory, 0, &analysis),
+            &breakpoints,
+            self.cpu.get_symbols(),
+        );
+        self.disassembly_analysis = Some(analysis);
+    }
+
+    fn get_state_path(&self, slot: u8) -> String {
+        format!("{}.{}.state", self.state_prefix, slot)
+    }
+
+    /// save or load the state requested with the keyboard, if any
+    fn handle_state_request(&mut self) {
+        match self.state_request.take() {
+            Some(StateRequest::Save(slot)) => {
+                let path = self.get_state_path(slot);
+                match fs::write(&path, self.cpu.save_state()) {
+                    Ok(()) => println!("Saved state to `{}`", path),
+                    Err(error) => eprintln!("Could not save state to `{}`: {}", path, error),
+                }
+            }
+            Some(StateRequest::Load(slot)) => {
+                let path = self.get_state_path(slot);
+                // loading a slot can be undone with rewind
+                let current_state = self.cpu.save_state();
+                let result = fs::read(&path)
+                    .map_err(|error| error.to_string())
+                    .and_then(|state| {
+                        self.cpu
+                            .load_state(&state)
+                            .map_err(|error| error.to_string())
+                    });
+
+                match result {
+                    Ok(()) => {
+                        println!("Loaded state from `{}`", path);
+                        self.rewind.push(current_state);
+                        self.last_break = None;
+                        // the loop does not get to them while paused
+                        self.update_disassembly_debug();
+                        self.gui.update_current_instruction_debug(self.cpu.get_pc());
+                        self.update_debug_views();
+                        self.update_screen();
+                    }
+                    Err(error) => eprintln!("Could not load state from `{}`: {}", path, error),
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// show the display in the window if it changed
+    fn update_screen(&mut self) {
+        if self.cpu.get_display_mut().take_updated() {
+            let display = self.cpu.get_display();
+            self.gui.update_screen(
+                display.get_data(),
+                display.get_width(),
+                display.get_height(),
+            );
+        }
+    }
+
+    /// snapshot the CPU in the rewind buffer, the state is not even saved
+    /// when rewinding is disabled
+    fn push_rewind_state(&mut self) {
+        if self.rewind.get_max_memory() > 0 {
+            self.rewind.push(self.cpu.save_state());
+        }
+    }
+
+    /// go back to the last snapshot in the rewind buffer, if any
+    fn rewind_state(&mut self) {
+        if let Some(state) = self.rewind.pop() {
+            match self.cpu.load_state(&state) {
+                Ok(()) => self.last_break = None,
+                Err(error) => {
+                    eprintln!("Could not rewind: {}", error);
+                    // the older states can't be trusted either
+                    self.rewind.clear();
+                }
+            }
+        }
+    }
+
+    /// apply the edits of the debugger views, the views show the new values
+    /// (or the old ones if an edit is invalid) even while paused
+    fn apply_debug_edits(&mut self) {
+        let edits: Vec<DebugEdit> = self.debug_edits.borrow_mut().drain(..).collect();
+        if edits.is_empty() {
+            return;
+        }
+
+        for edit in edits {
+            let result = match edit {
+                DebugEdit::Registers(text) => self.edit_registers(&text),
+                DebugEdit::Stack(text) => self.edit_stack(&text),
+                DebugEdit::Memory(address, text) => self.edit_memory(address, &text),
+            };
+
+            if let Err(error) = result {
+                eprintln!("Invalid edit: {}", error);
+            }
+        }
+
+        // the instructions may have been changed
+        self.update_disassembly_debug();
+        self.gui.update_current_instruction_debug(self.cpu.get_pc());
+        self.update_debug_views();
+    }
+
+    /// set the registers from the text of the registers view, `<name>: <hex value>` pairs
+    fn edit_registers(&mut self, text: &str) -> Result<(), String> {
+        let mut registers = Vec::new();
+
+        for line in text.lines() {
+            // the reason of the break is not a register
+            if line.starts_with("FAULT:") || line.starts_with("BREAK:") {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            while let Some(name) = words.next() {
+                let name = name
+                    .strip_suffix(':')
+                    .ok_or_else(|| format!("expected `<register>:` instead of `{}`", name))?;
+                let register = Operand::parse(name, self.cpu.get_symbols())?;
+                if let Operand::Value(_) = register {
+                    return Err(format!("`{}` is not a register", name));
+                }
+
+                let value = words.next().unwrap_or("");
+                let value = u16::from_str_radix(value, 16)
+                    .map_err(|_| format!("invalid value `{}` for {}", value, register))?;
+                registers.push((register, value));
+            }
+        }
+
+        for (register, value) in registers {
+            register.set_value(&mut self.cpu, value)?;
+        }
+        Ok(())
+    }
+
+    /// set the stack entries from the text of the stack view, the top entry first
+    fn edit_stack(&mut self, text: &str) -> Result<(), String> {
+        // one entry per line, the address in hex or a symbol, the symbol shown after it is ignored
+        let symbols = self.cpu.get_symbols();
+        let entries = text
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .rev()
+            .map(|entry| {
+                u16::from_str_radix(entry, 16)
+                    .ok()
+                    .or_else(|| symbols.get_address(entry))
+                    .ok_or_else(|| format!("invalid address `{}`", entry))
+            })
+            .collect::<Result<Vec<u16>, String>>()?;
+
+        if entries.len() != self.cpu.get_stack().len() {
+            return Err(format!(
+                "the stack has {} entries, change SP to add or remove entries",
+                self.cpu.get_stack().len()
+            ));
+        }
+
+        for (index, &address) in entries.iter().enumerate() {
+            self.cpu.set_stack_entry(index, address);
+        }
+        Ok(())
+    }
+
+    /// write the 16 bytes of the memory row starting at `address` from its hex text
+    fn edit_memory(&mut self, address: u16, text: &str) -> Result<(), String> {
+        let bytes = text
+            .split_whitespace()
+            .map(|byte| {
+                u8::from_str_radix(byte, 16).map_err(|_| format!("invalid byte `{}`", byte))
+            })
+            .collect::<Result<Vec<u8>, String>>()?;
+
+        if bytes.len() != 16 {
+            return Err(format!("expected 16 bytes in the row, not {}", bytes.len()));
+        }
+
+        let memory = self.cpu.get_memory_mut();
+        for (offset, &byte) in bytes.iter().enumerate() {
+            memory.store(address + offset as u16, byte);
+        }
+        Ok(())
+    }
+
+    /// show the memory, the stack and the registers, while paused what
+    /// changed since the last update (the last step or edit) is highlighted
+    fn update_debug_views(&mut self) {
+        let highlight = *self.single_stepping.borrow();
+
+        if self.cpu.get_memory_mut().take_modified() || self.gui.has_memory_highlights() {
+            self.gui
+                .update_memory_debug(self.cpu.get_memory().get_memory(), false, highlight);
+        }
+
+        self.gui
+            .update_stack_debug(self.cpu.get_stack(), self.cpu.get_symbols(), highlight);
+        self.gui.update_call_stack_debug(
+            &self.cpu.get_call_stack(),
+            self.cpu.get_stack_imbalance(),
+            self.cpu.get_symbols(),
+        );
+        self.gui.update_registers_debug(
+            self.cpu.get_v(),
+            self.cpu.get_i(),
+            self.cpu.get_pc(),
+            self.cpu.get_dt(),
+            self.cpu.get_st(),
+            self.cpu.get_sp(),
+            self.cpu.get_fault(),
+            self.last_break.as_ref(),
+            highlight,
+        );
+    }
+
+    /// start running to the target of the command, the CPU breaks into the
+    /// debugger when it is reached
+    fn run_command(&mut self, command: RunCommand) {
+        let target = match command {
+            RunCommand::StepOver => self.cpu.get_step_over_target(),
+            RunCommand::StepOut => self.cpu.get_step_out_target(),
+            RunCommand::RunToCursor => self
+                .gui
+                .get_selected_disassembly_address()
+                .map(|address| self.cpu.get_run_to_target(address)),
+        };
+
+        match target {
+            Some(target) => {
+                self.cpu.run_to(target);
+                *self.single_stepping.borrow_mut() = false;
+                self.last_break = None;
+            }
+            // not a `CALL`, step over it like any other instruction
+            None if command == RunCommand::StepOver => *self.run_next.borrow_mut() = true,
+            None => {}
+        }
+    }
+
+    /// runs once every 60Hz frame
+    fn run_loop_iteration(&mut self) {
+        self.handle_state_request();
+
+        // the values can only be changed while paused
+        self.gui.set_debug_editable(*self.single_stepping.borrow());
+        self.apply_debug_edits();
+
+        if let Some(command) = self.run_command.take() {
+            if *self.single_stepping.borrow() {
+                self.run_command(command);
+            }
+        }
+        // pausing cancels the step over/out or run to cursor
+        if *self.single_stepping.borrow() {
+            self.cpu.clear_run_target();
+        }
+
+        if let Some(address) = self.breakpoint_toggle.take() {
+            self.cpu.toggle_breakpoint(address);
+            self.update_disassembly_debug();
+            if *self.single_stepping.borrow() {
+                self.gui.update_current_instruction_debug(self.cpu.get_pc());
+            }
+        }
+
+        self.cpu.set_keyboard(self.gui.get_keyboard_data_copy());
+
+        let result = if self.rewinding.get() || self.step_back.replace(false) {
+            self.rewind_state();
+            Ok(())
+        } else if *self.single_stepping.borrow() {
+            if self.cpu.is_waiting_for_keypress() {
+                // keep checking the keypad even if the user did not step
+                if self.cpu.step().is_ok() && !self.cpu.is_waiting_for_keypress() {
+                    self.gui.update_current_instruction_debug(self.cpu.get_pc());
+                }
+                return;
+            }
+
+            // if we are in stepping mode and can run the next instruction, run it
+            if !*self.run_next.borrow() {
+                return;
+            }
+
+            // pause for the next time until the user clicks the button
+            *self.run_next.borrow_mut() = false;
+            self.last_break = None;
+
+            self.push_rewind_state();
+            self.cpu.step()
+        } else {
+            self.push_rewind_state();
+            self.cpu.run_frame()
+        };
+
+        if let Err(error) = result {
+            // halt into the debugger, the fault is shown in the registers view
+            if !*self.single_stepping.borrow() {
+                eprintln!("CPU fault: {}", error);
+            }
+            *self.single_stepping.borrow_mut() = true;
+        }
+
+        if let Some(break_reason) = self.cpu.take_break() {
+            // halt into the debugger, the reason is shown in the registers view
+            *self.single_stepping.borrow_mut() = true;
+            self.last_break = Some(break_reason);
+        }
+
+        let pc = self.cpu.get_pc();
+
+        // only show the location of the instruction in single stepping mode
+        if *self.single_stepping.borrow() && !self.cpu.is_waiting_for_keypress() {
+            let is_known_instruction = self
+                .disassembly_analysis
+                .as_ref()
+                .is_some_and(|analysis| analysis.is_instruction(pc));
+
+            // the flow analysis did not reach this instruction (computed jumps,
+            // self modifying code, ...), follow the program from here too
+            if !is_known_instruction && !self.entry_points.contains(&pc) {
+                self.entry_points.push(pc);
+                self.update_disassembly_debug();
+            }
+            self.gui.update_current_instruction_debug(pc);
+        }
+
+        self.update_screen();
+        self.update_debug_views();
+
+        self.sound
+            .update(self.cpu.get_audio(), self.cpu.is_sound_playing());
+    }
+}
+
+impl CPU {
+    /// run the CPU inside the GTK window until the window is closed,
+    /// with the default options (see `GuiOptions`)
+    pub fn run_display_application(self) {
+        self.run_display_application_with_options(GuiOptions::default());
+    }
+
+    /// same as `run_display_application`, but with the given options
+    pub fn run_display_application_with_options(self, options: GuiOptions) {
+        let frontend_rc = Rc::new(RefCell::new(Frontend::new(self, options)));
+        let c_frontend = frontend_rc.clone();
+
+        // the CPU runs one frame worth of instructions every loop
+        const FPS: u32 = 60;
+
+        frontend_rc.borrow().gui.run_in_loop(1000 / FPS, move || {
+            c_frontend.borrow_mut().run_loop_iteration();
+        });
+
+        // run the application
+        Gui::run_application();
+    }
+}
@@ -1,17 +1,37 @@
-use std::fs::File;
-use std::io::{Read};
+use super::breakpoint::{Access, Watchpoint};
+use super::op::Op;
+use super::state::{SaveStateError, StateReader, StateWriter};
+use std::io::Read;
+
+/// The size of the memory, the whole XO-CHIP 16-bit address space,
+/// programs for the original CHIP-8 only use the first 4KiB of it
+pub const MEMORY_SIZE: usize = 0x10000;
+
+// where the large font of SUPER-CHIP is stored, right after the normal font
+const LARGE_FONT_START: usize = 0x10 * 5;
 
 pub struct Memory {
-    memory: [u8; 0x1000], // 4095 byte memory
-                          // user programs should only use memory from 0x200
+    memory: Vec<u8>, // 64KiB memory, user programs should only use memory from 0x200
+    modified: bool,  // has the memory been written since the last `take_modified`
+    decoded: Vec<Option<Op>>, // the instruction decoded at each address, cleared when its bytes are written
+    code: Vec<bool>,          // the bytes of the compiled blocks (see `blocks`)
+    code_writes: Vec<u16>,    // the bytes of `code` written since the last `take_code_writes`
+    watchpoints: Vec<Watchpoint>, // the watched ranges, in the order they were added
+    watched: Vec<u8>,         // the `Access` bits of the watchpoints containing each address
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory {
     pub fn new() -> Memory {
-        let mut mem = [0; 0x1000];
+        let mut mem = vec![0; MEMORY_SIZE];
 
         // put the sprites of the normal letters in lower memory
-        mem[0.. 0x10 * 5].clone_from_slice(&[
+        mem[0..0x10 * 5].clone_from_slice(&[
             0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
             0x20, 0x60, 0x20, 0x20, 0x70, // 1
             0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
@@ -30,20 +50,66 @@ impl Memory {
             0xF0, 0x80, 0xF0, 0x80, 0x80, // F
         ]);
 
-        Memory { memory: mem }
+        // put the sprites of the SUPER-CHIP large (8x10) letters after them
+        mem[LARGE_FONT_START..LARGE_FONT_START + 0x10 * 10].clone_from_slice(&[
+            0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+            0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+            0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+            0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+            0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+            0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+            0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+        ]);
+
+        Memory {
+            memory: mem,
+            modified: true,
+            decoded: vec![None; MEMORY_SIZE],
+            code: vec![false; MEMORY_SIZE],
+            code_writes: Vec::new(),
+            watchpoints: Vec::new(),
+            watched: vec![0; MEMORY_SIZE],
+        }
     }
 
-    pub fn read_file(&mut self, file: &mut File) {
-        file.read(&mut self.memory[0x200..]).unwrap();
+    pub fn read_file<R: Read>(&mut self, file: &mut R) {
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).unwrap();
+
+        // whatever does not fit in memory is ignored
+        let length = data.len().min(self.memory.len() - 0x200);
+        self.memory[0x200..0x200 + length].clone_from_slice(&data[..length]);
+        self.modified = true;
+        self.clear_decoded();
+        self.write_all_code();
     }
 
     pub fn store(&mut self, addr: u16, value: u8) {
-        assert_eq!(addr < 0x1000, true);
+        // any 16-bit address is valid
         self.memory[addr as usize] = value;
+        self.modified = true;
+
+        // the instructions that use this byte, `LD I, long addr` is 4 bytes
+        for start in addr.saturating_sub(3)..=addr {
+            self.decoded[start as usize] = None;
+        }
+
+        if self.code[addr as usize] {
+            self.code[addr as usize] = false;
+            self.code_writes.push(addr);
+        }
     }
 
     pub fn get(&self, addr: u16) -> u8 {
-        assert_eq!(addr < 0x1000, true);
         self.memory[addr as usize]
     }
 
@@ -51,8 +117,135 @@ impl Memory {
         &self.memory
     }
 
+    /// the instruction decoded at `addr` by `set_decoded`, `None` if it was
+    /// not decoded or its bytes were written since
+    pub fn get_decoded(&self, addr: u16) -> Option<Op> {
+        self.decoded[addr as usize]
+    }
+
+    /// remember the instruction decoded from the bytes at `addr`
+    pub fn set_decoded(&mut self, addr: u16, op: Op) {
+        self.decoded[addr as usize] = Some(op);
+    }
+
+    pub fn clear_decoded(&mut self) {
+        self.decoded.iter_mut().for_each(|op| *op = None);
+    }
+
+    /// mark `length` bytes from `addr` as part of a compiled block,
+    /// their writes are returned by `take_code_writes`
+    pub fn mark_code(&mut self, addr: u16, length: u16) {
+        for byte in &mut self.code[addr as usize..addr as usize + length as usize] {
+            *byte = true;
+        }
+    }
+
+    /// the addresses of the bytes of compiled blocks written since the last
+    /// call, the blocks containing them must be compiled again
+    pub fn take_code_writes(&mut self) -> Vec<u16> {
+        std::mem::take(&mut self.code_writes)
+    }
+
+    /// all the code is replaced (a new rom or state)
+    fn write_all_code(&mut self) {
+        for (addr, byte) in self.code.iter_mut().enumerate() {
+            if *byte {
+                *byte = false;
+                self.code_writes.push(addr as u16);
+            }
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+        self.update_watched();
+    }
+
+    /// remove the watchpoints containing `addr`, returns them
+    pub fn remove_watchpoints(&mut self, addr: u16) -> Vec<Watchpoint> {
+        let (removed, kept) = self
+            .watchpoints
+            .iter()
+            .partition(|watchpoint| watchpoint.contains(addr));
+        self.watchpoints = kept;
+        self.update_watched();
+        removed
+    }
+
+    /// remove the watchpoints equal to `watchpoint`, returns false if there was none
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|kept| kept != watchpoint);
+        self.update_watched();
+        self.watchpoints.len() != count
+    }
+
+    pub fn get_watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+        self.update_watched();
+    }
+
+    /// is there a watchpoint on the accesses of this kind to `addr`
+    pub fn is_watched(&self, addr: u16, access: Access) -> bool {
+        self.watched[addr as usize] & access.get_bit() != 0
+    }
+
+    /// is there any watchpoint on the accesses of this kind
+    pub fn has_watchpoints(&self, access: Access) -> bool {
+        self.watchpoints
+            .iter()
+            .any(|watchpoint| watchpoint.is_watching(access))
+    }
+
+    fn update_watched(&mut self) {
+        self.watched.iter_mut().for_each(|bits| *bits = 0);
+
+        for watchpoint in &self.watchpoints {
+            for access in [Access::Read, Access::Write, Access::Execute] {
+                if watchpoint.is_watching(access) {
+                    for bits in
+                        &mut self.watched[watchpoint.start as usize..=watchpoint.end as usize]
+                    {
+                        *bits |= access.get_bit();
+                    }
+                }
+            }
+        }
+    }
+
+    /// returns true if the memory has been written since the last call,
+    /// used by frontends to know when to refresh the memory view
+    pub fn take_modified(&mut self) -> bool {
+        let modified = self.modified;
+        self.modified = false;
+        modified
+    }
+
+    /// write the whole memory to a save state
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.memory);
+    }
+
+    /// read the whole memory from a save state
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.memory.copy_from_slice(reader.read_bytes(MEMORY_SIZE)?);
+        self.modified = true;
+        self.clear_decoded();
+        self.write_all_code();
+        Ok(())
+    }
+
     pub fn get_sprite_location(&self, value: u8) -> u16 {
-        assert_eq!(value <= 0xF, true);
+        assert!(value <= 0xF);
         value as u16 * 5
     }
+
+    pub fn get_large_sprite_location(&self, value: u8) -> u16 {
+        assert!(value <= 0xF);
+        LARGE_FONT_START as u16 + value as u16 * 10
+    }
 }
@@ -1,5 +1,22 @@
+pub mod assembler;
+pub mod audio;
+pub mod blocks;
+pub mod breakpoint;
+pub mod callstack;
 pub mod cpu;
-pub mod memory;
+pub mod disassembler;
 pub mod display;
+pub mod error;
+pub mod gdb;
+#[cfg(feature = "gui")]
+pub mod gui;
+pub mod memory;
+pub mod number;
+pub mod op;
+pub mod quirks;
+pub mod repl;
+pub mod rewind;
+#[cfg(feature = "gui")]
 pub mod sound;
-pub mod disassembler;
\ No newline at end of file
+pub mod state;
+pub mod symbols;
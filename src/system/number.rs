@@ -0,0 +1,34 @@
+/// parse a hexadecimal (`0x`), binary (`0b`) or decimal number, like the
+/// numbers of the assembler, the debuggers and the symbol files
+pub fn parse_number(text: &str) -> Option<u16> {
+    let text = text.trim();
+
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        u16::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_are_parsed() {
+        assert_eq!(parse_number("42"), Some(42));
+        assert_eq!(parse_number(" 0x2A4 "), Some(0x2A4));
+        assert_eq!(parse_number("0XffFF"), Some(0xFFFF));
+        assert_eq!(parse_number("0b101"), Some(5));
+        assert_eq!(parse_number("0B11"), Some(3));
+    }
+
+    #[test]
+    fn invalid_numbers_are_rejected() {
+        for text in ["", "0x", "0x10000", "65536", "-1", "0b2", "12ab", "loop"] {
+            assert_eq!(parse_number(text), None, "{}", text);
+        }
+    }
+}
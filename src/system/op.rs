@@ -0,0 +1,387 @@
+use std::fmt;
+
+/// The index of a V register (`0x0..=0xF`)
+pub type Reg = u8;
+
+/// A decoded instruction of CHIP-8, SUPER-CHIP or XO-CHIP, used by the
+/// interpreter, the disassembler and the assembler so they never disagree
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    /// 00E0 - CLS
+    Cls,
+    /// 00EE - RET
+    Ret,
+    /// 0nnn - SYS addr
+    Sys(u16),
+    /// 00Cn - SCD nibble (SUPER-CHIP)
+    Scd(u8),
+    /// 00Dn - SCU nibble (XO-CHIP)
+    Scu(u8),
+    /// 00FB - SCR (SUPER-CHIP)
+    Scr,
+    /// 00FC - SCL (SUPER-CHIP)
+    Scl,
+    /// 00FD - EXIT (SUPER-CHIP)
+    Exit,
+    /// 00FE - LOW (SUPER-CHIP)
+    Low,
+    /// 00FF - HIGH (SUPER-CHIP)
+    High,
+    /// 1nnn - JP addr
+    Jp(u16),
+    /// 2nnn - CALL addr
+    Call(u16),
+    /// 3xkk - SE Vx, byte
+    Se(Reg, u8),
+    /// 4xkk - SNE Vx, byte
+    Sne(Reg, u8),
+    /// 5xy0 - SE Vx, Vy
+    SeReg(Reg, Reg),
+    /// 5xy2 - SAVE Vx, Vy (XO-CHIP)
+    Save(Reg, Reg),
+    /// 5xy3 - LOAD Vx, Vy (XO-CHIP)
+    Load(Reg, Reg),
+    /// 6xkk - LD Vx, byte
+    Ld(Reg, u8),
+    /// 7xkk - ADD Vx, byte
+    Add(Reg, u8),
+    /// 8xy0 - LD Vx, Vy
+    LdReg(Reg, Reg),
+    /// 8xy1 - OR Vx, Vy
+    Or(Reg, Reg),
+    /// 8xy2 - AND Vx, Vy
+    And(Reg, Reg),
+    /// 8xy3 - XOR Vx, Vy
+    Xor(Reg, Reg),
+    /// 8xy4 - ADD Vx, Vy
+    AddReg(Reg, Reg),
+    /// 8xy5 - SUB Vx, Vy
+    Sub(Reg, Reg),
+    /// 8xy6 - SHR Vx {, Vy}
+    Shr(Reg, Reg),
+    /// 8xy7 - SUBN Vx, Vy
+    Subn(Reg, Reg),
+    /// 8xyE - SHL Vx {, Vy}
+    Shl(Reg, Reg),
+    /// 9xy0 - SNE Vx, Vy
+    SneReg(Reg, Reg),
+    /// Annn - LD I, addr
+    LdI(u16),
+    /// F000 nnnn - LD I, long addr (XO-CHIP), 4 bytes
+    LdILong(u16),
+    /// Bnnn - JP V0, addr
+    JpV0(u16),
+    /// Cxkk - RND Vx, byte
+    Rnd(Reg, u8),
+    /// Dxyn - DRW Vx, Vy, nibble
+    Drw(Reg, Reg, u8),
+    /// Ex9E - SKP Vx
+    Skp(Reg),
+    /// ExA1 - SKNP Vx
+    Sknp(Reg),
+    /// Fn01 - PLANE n (XO-CHIP)
+    Plane(u8),
+    /// F002 - AUDIO (XO-CHIP)
+    Audio,
+    /// Fx07 - LD Vx, DT
+    LdVxDt(Reg),
+    /// Fx0A - LD Vx, K
+    LdVxK(Reg),
+    /// Fx15 - LD DT, Vx
+    LdDtVx(Reg),
+    /// Fx18 - LD ST, Vx
+    LdStVx(Reg),
+    /// Fx1E - ADD I, Vx
+    AddI(Reg),
+    /// Fx29 - LD F, Vx
+    LdF(Reg),
+    /// Fx30 - LD HF, Vx (SUPER-CHIP)
+    LdHf(Reg),
+    /// Fx33 - LD B, Vx
+    LdB(Reg),
+    /// Fx3A - PITCH Vx (XO-CHIP)
+    Pitch(Reg),
+    /// Fx55 - LD [I], Vx
+    LdMemVx(Reg),
+    /// Fx65 - LD Vx, [I]
+    LdVxMem(Reg),
+    /// Fx75 - LD R, Vx (SUPER-CHIP)
+    LdRVx(Reg),
+    /// Fx85 - LD Vx, R (SUPER-CHIP)
+    LdVxR(Reg),
+}
+
+impl Op {
+    /// decode the 16-bit `instruction`, `next` is the word after it and is only
+    /// used by `LD I, long addr` (F000 nnnn). Returns `None` for invalid instructions
+    pub fn decode(instruction: u16, next: u16) -> Option<Op> {
+        // nibbles will have the values of the instruction as
+        // each four bytes of the instruction starting from the left as 0
+        //
+        // example: instruction = 0xfa12, nibbles = [0xf, 0xa, 0x1, 0x2]
+        let mut nibbles = [0u8; 4];
+        for (i, nibble) in nibbles.iter_mut().enumerate() {
+            let offset = (3 - i) * 4;
+            *nibble = ((instruction & (0xf << offset)) >> offset) as u8;
+        }
+
+        // the lowest 12-bit value
+        let address = instruction & 0xFFF;
+        // lowest 4-bit value in the high byte
+        let x = nibbles[1];
+        // highest 4-bit value in the low byte
+        let y = nibbles[2];
+        // the lowest 8-bit value
+        let kk = (instruction & 0xFF) as u8;
+        // the lowest 4-bit value
+        let n = nibbles[3];
+
+        let op = match nibbles[0] {
+            0x0 => match address {
+                0x0E0 => Op::Cls,
+                0x0EE => Op::Ret,
+                0x0C0..=0x0CF => Op::Scd(n),
+                0x0D0..=0x0DF => Op::Scu(n),
+                0x0FB => Op::Scr,
+                0x0FC => Op::Scl,
+                0x0FD => Op::Exit,
+                0x0FE => Op::Low,
+                0x0FF => Op::High,
+                _ => Op::Sys(address),
+            },
+            0x1 => Op::Jp(address),
+            0x2 => Op::Call(address),
+            0x3 => Op::Se(x, kk),
+            0x4 => Op::Sne(x, kk),
+            0x5 => match n {
+                0x0 => Op::SeReg(x, y),
+                0x2 => Op::Save(x, y),
+                0x3 => Op::Load(x, y),
+                _ => return None,
+            },
+            0x6 => Op::Ld(x, kk),
+            0x7 => Op::Add(x, kk),
+            0x8 => match n {
+                0x0 => Op::LdReg(x, y),
+                0x1 => Op::Or(x, y),
+                0x2 => Op::And(x, y),
+                0x3 => Op::Xor(x, y),
+                0x4 => Op::AddReg(x, y),
+                0x5 => Op::Sub(x, y),
+                0x6 => Op::Shr(x, y),
+                0x7 => Op::Subn(x, y),
+                0xE => Op::Shl(x, y),
+                _ => return None,
+            },
+            0x9 if n == 0 => Op::SneReg(x, y),
+            0xA => Op::LdI(address),
+            0xB => Op::JpV0(address),
+            0xC => Op::Rnd(x, kk),
+            0xD => Op::Drw(x, y, n),
+            0xE => match kk {
+                0x9E => Op::Skp(x),
+                0xA1 => Op::Sknp(x),
+                _ => return None,
+            },
+            0xF => match kk {
+                0x00 if x == 0 => Op::LdILong(next),
+                0x01 => Op::Plane(x),
+                0x02 if x == 0 => Op::Audio,
+                0x07 => Op::LdVxDt(x),
+                0x0A => Op::LdVxK(x),
+                0x15 => Op::LdDtVx(x),
+                0x18 => Op::LdStVx(x),
+                0x1E => Op::AddI(x),
+                0x29 => Op::LdF(x),
+                0x30 => Op::LdHf(x),
+                0x33 => Op::LdB(x),
+                0x3A => Op::Pitch(x),
+                0x55 => Op::LdMemVx(x),
+                0x65 => Op::LdVxMem(x),
+                0x75 => Op::LdRVx(x),
+                0x85 => Op::LdVxR(x),
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        Some(op)
+    }
+
+    /// the encoded instruction, the first word and the second one for
+    /// `LD I, long addr`, the values are masked to the size of their fields
+    pub fn encode(&self) -> Vec<u16> {
+        let x = |x: Reg| (x as u16 & 0xF) << 8;
+        let xy = |vx: Reg, vy: Reg| x(vx) | (vy as u16 & 0xF) << 4;
+        let addr = |address: u16| address & 0xFFF;
+
+        let instruction = match *self {
+            Op::Cls => 0x00E0,
+            Op::Ret => 0x00EE,
+            Op::Sys(address) => addr(address),
+            Op::Scd(n) => 0x00C0 | (n as u16 & 0xF),
+            Op::Scu(n) => 0x00D0 | (n as u16 & 0xF),
+            Op::Scr => 0x00FB,
+            Op::Scl => 0x00FC,
+            Op::Exit => 0x00FD,
+            Op::Low => 0x00FE,
+            Op::High => 0x00FF,
+            Op::Jp(address) => 0x1000 | addr(address),
+            Op::Call(address) => 0x2000 | addr(address),
+            Op::Se(vx, byte) => 0x3000 | x(vx) | byte as u16,
+            Op::Sne(vx, byte) => 0x4000 | x(vx) | byte as u16,
+            Op::SeReg(vx, vy) => 0x5000 | xy(vx, vy),
+            Op::Save(vx, vy) => 0x5002 | xy(vx, vy),
+            Op::Load(vx, vy) => 0x5003 | xy(vx, vy),
+            Op::Ld(vx, byte) => 0x6000 | x(vx) | byte as u16,
+            Op::Add(vx, byte) => 0x7000 | x(vx) | byte as u16,
+            Op::LdReg(vx, vy) => 0x8000 | xy(vx, vy),
+            Op::Or(vx, vy) => 0x8001 | xy(vx, vy),
+            Op::And(vx, vy) => 0x8002 | xy(vx, vy),
+            Op::Xor(vx, vy) => 0x8003 | xy(vx, vy),
+            Op::AddReg(vx, vy) => 0x8004 | xy(vx, vy),
+            Op::Sub(vx, vy) => 0x8005 | xy(vx, vy),
+            Op::Shr(vx, vy) => 0x8006 | xy(vx, vy),
+            Op::Subn(vx, vy) => 0x8007 | xy(vx, vy),
+            Op::Shl(vx, vy) => 0x800E | xy(vx, vy),
+            Op::SneReg(vx, vy) => 0x9000 | xy(vx, vy),
+            Op::LdI(address) => 0xA000 | addr(address),
+            Op::LdILong(address) => return vec![0xF000, address],
+            Op::JpV0(address) => 0xB000 | addr(address),
+            Op::Rnd(vx, byte) => 0xC000 | x(vx) | byte as u16,
+            Op::Drw(vx, vy, n) => 0xD000 | xy(vx, vy) | (n as u16 & 0xF),
+            Op::Skp(vx) => 0xE09E | x(vx),
+            Op::Sknp(vx) => 0xE0A1 | x(vx),
+            Op::Plane(n) => 0xF001 | x(n),
+            Op::Audio => 0xF002,
+            Op::LdVxDt(vx) => 0xF007 | x(vx),
+            Op::LdVxK(vx) => 0xF00A | x(vx),
+            Op::LdDtVx(vx) => 0xF015 | x(vx),
+            Op::LdStVx(vx) => 0xF018 | x(vx),
+            Op::AddI(vx) => 0xF01E | x(vx),
+            Op::LdF(vx) => 0xF029 | x(vx),
+            Op::LdHf(vx) => 0xF030 | x(vx),
+            Op::LdB(vx) => 0xF033 | x(vx),
+            Op::Pitch(vx) => 0xF03A | x(vx),
+            Op::LdMemVx(vx) => 0xF055 | x(vx),
+            Op::LdVxMem(vx) => 0xF065 | x(vx),
+            Op::LdRVx(vx) => 0xF075 | x(vx),
+            Op::LdVxR(vx) => 0xF085 | x(vx),
+        };
+
+        vec![instruction]
+    }
+
+    /// the number of bytes of the instruction, 4 for `LD I, long addr`
+    pub fn length(&self) -> u16 {
+        match self {
+            Op::LdILong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// is it one of the instructions that skip the next instruction
+    pub fn is_skip(&self) -> bool {
+        matches!(
+            self,
+            Op::Se(..) | Op::Sne(..) | Op::SeReg(..) | Op::SneReg(..) | Op::Skp(_) | Op::Sknp(_)
+        )
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Op::Cls => write!(f, "CLS"),
+            Op::Ret => write!(f, "RET"),
+            Op::Sys(address) => write!(f, "SYS 0x{:03X}", address),
+            Op::Scd(n) => write!(f, "SCD 0x{:01x}", n),
+            Op::Scu(n) => write!(f, "SCU 0x{:01x}", n),
+            Op::Scr => write!(f, "SCR"),
+            Op::Scl => write!(f, "SCL"),
+            Op::Exit => write!(f, "EXIT"),
+            Op::Low => write!(f, "LOW"),
+            Op::High => write!(f, "HIGH"),
+            Op::Jp(address) => write!(f, "JMP 0x{:03X}", address),
+            Op::Call(address) => write!(f, "CALL 0x{:03X}", address),
+            Op::Se(x, kk) => write!(f, "SE V{:01X}, 0x{:02X}", x, kk),
+            Op::Sne(x, kk) => write!(f, "SNE V{:01X}, 0x{:02X}", x, kk),
+            Op::SeReg(x, y) => write!(f, "SE V{:01X}, V{:01X}", x, y),
+            Op::Save(x, y) => write!(f, "SAVE V{:01X}, V{:01X}", x, y),
+            Op::Load(x, y) => write!(f, "LOAD V{:01X}, V{:01X}", x, y),
+            Op::Ld(x, kk) => write!(f, "LD V{:01X}, 0x{:02X}", x, kk),
+            Op::Add(x, kk) => write!(f, "ADD V{:01X}, 0x{:02X}", x, kk),
+            Op::LdReg(x, y) => write!(f, "LD V{:01X}, V{:01X}", x, y),
+            Op::Or(x, y) => write!(f, "OR V{:01X}, V{:01X}", x, y),
+            Op::And(x, y) => write!(f, "AND V{:01X}, V{:01X}", x, y),
+            Op::Xor(x, y) => write!(f, "XOR V{:01X}, V{:01X}", x, y),
+            Op::AddReg(x, y) => write!(f, "ADD V{:01X}, V{:01X}", x, y),
+            Op::Sub(x, y) => write!(f, "SUB V{:01X}, V{:01X}", x, y),
+            Op::Shr(x, y) => write!(f, "SHR V{:01X}, V{:01X}", x, y),
+            Op::Subn(x, y) => write!(f, "SUBN V{:01X}, V{:01X}", x, y),
+            Op::Shl(x, y) => write!(f, "SHL V{:01X}, V{:01X}", x, y),
+            Op::SneReg(x, y) => write!(f, "SNE V{:01X}, V{:01X}", x, y),
+            Op::LdI(address) => write!(f, "LD I, 0x{:03X}", address),
+            Op::LdILong(address) => write!(f, "LD I, long 0x{:04X}", address),
+            Op::JpV0(address) => write!(f, "JP V0, 0x{:03X}", address),
+            Op::Rnd(x, kk) => write!(f, "RND V{:01X}, 0x{:02X}", x, kk),
+            Op::Drw(x, y, n) => write!(f, "DRW V{:01X}, V{:01X}, 0x{:01x}", x, y, n),
+            Op::Skp(x) => write!(f, "SKP V{:01X}", x),
+            Op::Sknp(x) => write!(f, "SKNP V{:01X}", x),
+            Op::Plane(n) => write!(f, "PLANE 0x{:01x}", n),
+            Op::Audio => write!(f, "AUDIO"),
+            Op::LdVxDt(x) => write!(f, "LD V{:01X}, DT", x),
+            Op::LdVxK(x) => write!(f, "LD V{:01X}, K", x),
+            Op::LdDtVx(x) => write!(f, "LD DT, V{:01X}", x),
+            Op::LdStVx(x) => write!(f, "LD ST, V{:01X}", x),
+            Op::AddI(x) => write!(f, "ADD I, V{:01X}", x),
+            Op::LdF(x) => write!(f, "LD F, V{:01X}", x),
+            Op::LdHf(x) => write!(f, "LD HF, V{:01X}", x),
+            Op::LdB(x) => write!(f, "LD B, V{:01X}", x),
+            Op::Pitch(x) => write!(f, "PITCH V{:01X}", x),
+            Op::LdMemVx(x) => write!(f, "LD [I], V{:01X}", x),
+            Op::LdVxMem(x) => write!(f, "LD V{:01X}, [I]", x),
+            Op::LdRVx(x) => write!(f, "LD R, V{:01X}", x),
+            Op::LdVxR(x) => write!(f, "LD V{:01X}, R", x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::assembler::assemble;
+
+    #[test]
+    fn shifts_show_both_registers() {
+        assert_eq!(Op::Shr(0xA, 0).to_string(), "SHR VA, V0");
+        assert_eq!(Op::Shl(0x3, 0xB).to_string(), "SHL V3, VB");
+    }
+
+    #[test]
+    fn long_loads_are_shown_as_long() {
+        assert_eq!(Op::LdILong(0x0208).to_string(), "LD I, long 0x0208");
+        assert_eq!(Op::LdI(0x208).to_string(), "LD I, 0x208");
+    }
+
+    #[test]
+    fn every_instruction_assembles_back_from_its_text() {
+        // the address after `F000` past 0xFFF and one that would fit in `Annn`
+        for next in [0x1234, 0x0208] {
+            for instruction in 0..=0xFFFF {
+                let op = match Op::decode(instruction, next) {
+                    Some(op) => op,
+                    None => continue,
+                };
+
+                let rom = assemble(&op.to_string(), "op.asm").unwrap();
+                let words: Vec<u16> = rom
+                    .chunks(2)
+                    .map(|word| u16::from_be_bytes([word[0], word[1]]))
+                    .collect();
+                assert_eq!(words, op.encode(), "{}", op);
+                assert_eq!(op.encode()[0], instruction, "{}", op);
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+/// The behaviour of the instructions that differ between CHIP-8 interpreters,
+/// roms written for one interpreter may not run correctly with the quirks of another
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    /// `SHR Vx, Vy` and `SHL Vx, Vy` shift Vy and store the result in Vx,
+    /// instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    /// `LD [I], Vx` and `LD Vx, [I]` leave I pointing after the last
+    /// stored/loaded register, instead of leaving it unchanged
+    pub load_store_increments_i: bool,
+    /// `JP V0, addr` (Bnnn) jumps to `xnn + Vx` instead of `nnn + V0`
+    pub jump_uses_vx: bool,
+    /// sprites drawn with `DRW` are clipped at the edges of the screen,
+    /// instead of wrapping around to the other side
+    pub clip_sprites: bool,
+    /// `OR`, `AND` and `XOR` reset VF to zero
+    pub vf_reset: bool,
+    /// `DRW` waits for the start of the next 60Hz frame (vertical blank),
+    /// so only one sprite can be drawn per frame
+    pub display_wait: bool,
+}
+
+/// The names of the presets that can be used in `Quirks::from_name`
+pub const PRESET_NAMES: [&str; 5] = ["cosmac", "chip48", "schip", "xochip", "modern"];
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self::modern()
+    }
+}
+
+impl Quirks {
+    /// The original COSMAC VIP interpreter
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            clip_sprites: true,
+            vf_reset: true,
+            display_wait: true,
+        }
+    }
+
+    /// CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: true,
+            jump_uses_vx: true,
+            clip_sprites: true,
+            vf_reset: false,
+            display_wait: false,
+        }
+    }
+
+    /// SUPER-CHIP 1.1
+    pub fn super_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            clip_sprites: true,
+            vf_reset: false,
+            display_wait: false,
+        }
+    }
+
+    /// XO-CHIP, as implemented by Octo
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            clip_sprites: false,
+            vf_reset: false,
+            display_wait: false,
+        }
+    }
+
+    /// What most modern interpreters (and most roms found online) expect,
+    /// this is the default
+    pub fn modern() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            clip_sprites: false,
+            vf_reset: false,
+            display_wait: false,
+        }
+    }
+
+    /// the quirks as bits, in the order of the fields (used in save states)
+    pub fn to_bits(&self) -> u8 {
+        [
+            self.shift_uses_vy,
+            self.load_store_increments_i,
+            self.jump_uses_vx,
+            self.clip_sprites,
+            self.vf_reset,
+            self.display_wait,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (i, &quirk)| bits | (quirk as u8) << i)
+    }
+
+    /// the opposite of `to_bits`
+    pub fn from_bits(bits: u8) -> Quirks {
+        Quirks {
+            shift_uses_vy: bits & 0x01 != 0,
+            load_store_increments_i: bits & 0x02 != 0,
+            jump_uses_vx: bits & 0x04 != 0,
+            clip_sprites: bits & 0x08 != 0,
+            vf_reset: bits & 0x10 != 0,
+            display_wait: bits & 0x20 != 0,
+        }
+    }
+
+    /// get a preset by its name, the names are in `PRESET_NAMES`
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name {
+            "cosmac" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" => Some(Quirks::super_chip()),
+            "xochip" => Some(Quirks::xo_chip()),
+            "modern" => Some(Quirks::modern()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,892 @@
+use super::breakpoint::{Breakpoint, Operand, RunTarget, Watchpoint};
+use super::cpu::{BreakReason, CPU};
+use super::disassembler;
+use super::error::EmulationError;
+use super::memory::MEMORY_SIZE;
+use super::number::parse_number;
+use super::symbols::Symbols;
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+const PROMPT: &str = "(chip-8) ";
+
+// the defaults of `mem` and `disas` without a length
+const DEFAULT_MEMORY_LENGTH: u16 = 0x40;
+const DEFAULT_DISASSEMBLY_LENGTH: usize = 10;
+
+// the instructions run by `step <n>` between the checks for Ctrl-C
+const STEPS_BETWEEN_INTERRUPT_CHECKS: u32 = 4096;
+
+// the frames run by `next`, `finish` and `until` without a terminal (a
+// minute), as there is no Ctrl-C to stop them if the target is never reached
+const FRAMES_WITHOUT_TERMINAL: u32 = 60 * 60;
+
+const CTRL_A: u8 = 0x01;
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const CTRL_E: u8 = 0x05;
+const CTRL_U: u8 = 0x15;
+const BACKSPACE: u8 = 0x08;
+const ESCAPE: u8 = 0x1B;
+const DELETE: u8 = 0x7F;
+
+const HELP: &str = "\
+step [n]              execute n instructions (default 1, at least 1), stops at breakpoints
+next                  step over the instruction (a CALL runs until it returns)
+finish                run until the current subroutine returns
+until <addr>          run until the instruction at addr
+continue [frames]     run until a breakpoint, a watchpoint, a fault or Ctrl-C (frames needed without a terminal)
+break <breakpoint>    stop before the instruction, `<addr> [if <condition>] [after <count>]`
+watch <watchpoint>    stop on memory accesses, `<addr>[-<end>] [r][w][x]` (writes by default)
+delete <addr>         remove the breakpoint and the watchpoints at addr
+info                  list the breakpoints and the watchpoints
+regs                  show the registers, the stack and the pressed keys
+backtrace             show the calls in the stack, the innermost first
+mem <addr> [len]      show len bytes of memory (default 0x40)
+disas [addr] [n]      disassemble n instructions (default 10) from addr (default PC)
+set <reg|addr> <val>  change a register (V0..VF, I, PC, SP, DT, ST) or a byte of memory
+screen                show the display
+press <key>           hold the key (0..F) until `release`
+release [key]         release the key, or all the keys
+history               show the previous commands
+help                  show this help
+quit                  exit
+
+An empty line repeats the last command, numbers are decimal, hexadecimal (0x) or binary (0b),
+the addresses can also be the names of the symbols (--symbols).";
+
+/// The bytes typed on stdin, read by a thread so the CPU can run while
+/// waiting for Ctrl-C
+struct Input {
+    receiver: Receiver<u8>,
+    pending: VecDeque<u8>, // the bytes received while checking for Ctrl-C
+    saved_terminal: Option<String>, // the settings to restore, if the terminal was changed
+}
+
+impl Input {
+    fn new() -> Input {
+        // without canonical mode the keys are received one by one (for the
+        // line editing), and Ctrl-C is received as a byte instead of a signal
+        let saved_terminal = if io::stdin().is_terminal() {
+            stty(&["-g"])
+                .filter(|_| stty(&["-icanon", "-echo", "-isig", "min", "1"]).is_some())
+                .map(|settings| settings.trim().to_owned())
+        } else {
+            None
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0; 256];
+            let mut stdin = io::stdin();
+            while let Ok(length) = stdin.read(&mut buffer) {
+                if length == 0 {
+                    break;
+                }
+                for &byte in &buffer[..length] {
+                    if sender.send(byte).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Input {
+            receiver,
+            pending: VecDeque::new(),
+            saved_terminal,
+        }
+    }
+
+    /// are the keys received one by one, with the line editing done here
+    fn is_raw(&self) -> bool {
+        self.saved_terminal.is_some()
+    }
+
+    /// wait for the next byte, `None` at the end of the input
+    fn next_byte(&mut self) -> Option<u8> {
+        match self.pending.pop_front() {
+            Some(byte) => Some(byte),
+            None => self.receiver.recv().ok(),
+        }
+    }
+
+    /// was Ctrl-C pressed, without waiting, the other keys are kept for the next line
+    fn poll_interrupt(&mut self) -> bool {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(CTRL_C) if self.is_raw() => return true,
+                Ok(byte) => self.pending.push_back(byte),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        if let Some(settings) = &self.saved_terminal {
+            stty(&[settings]);
+        }
+    }
+}
+
+/// run `stty` on the terminal of stdin, returns its output if it succeeded
+fn stty(arguments: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(arguments)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+
+    if output.status.success() {
+        Some(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        None
+    }
+}
+
+/// Reads the commands with a simple line editor (left/right, home/end,
+/// backspace/delete, Ctrl-U) and a history (up/down), when stdin is a terminal
+struct LineEditor {
+    input: Input,
+    history: Vec<String>,
+}
+
+impl LineEditor {
+    fn new() -> LineEditor {
+        LineEditor {
+            input: Input::new(),
+            history: Vec::new(),
+        }
+    }
+
+    /// read the next line, `None` at the end of the input (or Ctrl-D)
+    fn read_line(&mut self, prompt: &str) -> Option<String> {
+        print!("{}", prompt);
+        io::stdout().flush().ok();
+
+        let line = if self.input.is_raw() {
+            self.edit_line(prompt)?
+        } else {
+            self.read_plain_line()?
+        };
+
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        Some(line)
+    }
+
+    /// a line from the input without editing (from a pipe or a file)
+    fn read_plain_line(&mut self) -> Option<String> {
+        let mut line = Vec::new();
+        loop {
+            match self.input.next_byte() {
+                Some(b'\n') => break,
+                Some(b'\r') => {}
+                Some(byte) => line.push(byte),
+                None if line.is_empty() => return None,
+                None => break,
+            }
+        }
+
+        Some(String::from_utf8_lossy(&line).into_owned())
+    }
+
+    fn edit_line(&mut self, prompt: &str) -> Option<String> {
+        let mut line: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // the entry of the history shown, `history.len()` for the new line
+        let mut history_index = self.history.len();
+        let mut new_line = Vec::new();
+
+        loop {
+            let byte = match self.input.next_byte() {
+                Some(byte) => byte,
+                None => {
+                    println!();
+                    return None;
+                }
+            };
+
+            match byte {
+                b'\r' | b'\n' => {
+                    println!();
+                    return Some(line.into_iter().collect());
+                }
+                CTRL_C => {
+                    println!("^C");
+                    line.clear();
+                    cursor = 0;
+                    print!("{}", prompt);
+                }
+                CTRL_D if line.is_empty() => {
+                    println!();
+                    return None;
+                }
+                CTRL_A => cursor = 0,
+                CTRL_E => cursor = line.len(),
+                CTRL_U => {
+                    line.drain(..cursor);
+                    cursor = 0;
+                }
+                BACKSPACE | DELETE if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                ESCAPE => match self.read_escape_sequence().as_str() {
+                    "[D" | "OD" => cursor = cursor.saturating_sub(1),
+                    "[C" | "OC" => cursor = (cursor + 1).min(line.len()),
+                    "[H" | "OH" | "[1~" | "[7~" => cursor = 0,
+                    "[F" | "OF" | "[4~" | "[8~" => cursor = line.len(),
+                    "[3~" if cursor < line.len() => {
+                        line.remove(cursor);
+                    }
+                    // older or newer entry of the history
+                    sequence @ ("[A" | "OA" | "[B" | "OB") => {
+                        if history_index == self.history.len() {
+                            new_line = line.clone();
+                        }
+
+                        history_index = if sequence.ends_with('A') {
+                            history_index.saturating_sub(1)
+                        } else {
+                            (history_index + 1).min(self.history.len())
+                        };
+
+                        line = match self.history.get(history_index) {
+                            Some(entry) => entry.chars().collect(),
+                            None => new_line.clone(),
+                        };
+                        cursor = line.len();
+                    }
+                    _ => {}
+                },
+                0x20..=0x7E => {
+                    line.insert(cursor, byte as char);
+                    cursor += 1;
+                }
+                _ => {}
+            }
+
+            // draw the whole line again and put the cursor back
+            print!("\r\x1B[K{}{}", prompt, line.iter().collect::<String>());
+            if cursor < line.len() {
+                print!("\x1B[{}D", line.len() - cursor);
+            }
+            io::stdout().flush().ok();
+        }
+    }
+
+    /// the rest of an escape sequence (after ESC), like `[A` for up
+    fn read_escape_sequence(&mut self) -> String {
+        let mut sequence = String::new();
+
+        if let Some(byte) = self.input.next_byte() {
+            sequence.push(byte as char);
+            if byte == b'[' || byte == b'O' {
+                // parameters until the final byte
+                while let Some(byte) = self.input.next_byte() {
+                    sequence.push(byte as char);
+                    if (0x40..=0x7E).contains(&byte) {
+                        break;
+                    }
+                }
+            }
+        }
+
+        sequence
+    }
+}
+
+/// an address, a number or the name of a symbol
+fn parse_address(symbols: &Symbols, text: Option<&str>) -> Result<u16, String> {
+    let text = text.ok_or("missing the address")?;
+    symbols
+        .parse_address(text)
+        .ok_or_else(|| format!("invalid address `{}`", text))
+}
+
+/// the count of `step` or `continue`, at least 1
+fn parse_count(text: &str) -> Result<u32, String> {
+    match parse_number(text) {
+        Some(count) if count > 0 => Ok(count as u32),
+        Some(_) => Err("the count must be at least 1".to_owned()),
+        None => Err(format!("invalid count `{}`", text)),
+    }
+}
+
+fn parse_key(text: &str) -> Result<u8, String> {
+    match u8::from_str_radix(text.trim_start_matches("0x"), 16) {
+        Ok(key) if key <= 0xF => Ok(key),
+        _ => Err(format!("invalid key `{}`, expected 0..F", text)),
+    }
+}
+
+/// A terminal debugger controlling the CPU, see `CPU::run_repl`
+struct Repl {
+    cpu: CPU,
+    editor: LineEditor,
+    last_command: String,
+    last_break: Option<BreakReason>, // the reason of the last stop, shown in `regs`
+}
+
+impl Repl {
+    fn new(cpu: CPU) -> Repl {
+        Repl {
+            cpu,
+            editor: LineEditor::new(),
+            last_command: String::new(),
+            last_break: None,
+        }
+    }
+
+    fn run(&mut self) {
+        println!("Type `help` for the commands.");
+        self.show_current_instruction();
+
+        while let Some(line) = self.editor.read_line(PROMPT) {
+            // an empty line repeats the last command, like in gdb
+            let line = if line.trim().is_empty() {
+                self.last_command.clone()
+            } else {
+                line.trim().to_owned()
+            };
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut words = line.splitn(2, char::is_whitespace);
+            let command = words.next().unwrap_or("");
+            let arguments = words.next().unwrap_or("").trim();
+
+            if let "quit" | "q" | "exit" = command {
+                return;
+            }
+
+            if let Err(error) = self.execute(command, arguments) {
+                println!("error: {}", error);
+            }
+            self.last_command = line;
+        }
+    }
+
+    fn execute(&mut self, command: &str, arguments: &str) -> Result<(), String> {
+        let mut words = arguments.split_whitespace();
+
+        match command {
+            "step" | "s" | "stepi" | "si" => {
+                let count = match words.next() {
+                    Some(text) => parse_count(text)?,
+                    None => 1,
+                };
+                self.step(count);
+            }
+            "next" | "n" => match self.cpu.get_step_over_target() {
+                Some(target) => self.run_to(target),
+                None => self.step(1),
+            },
+            "finish" | "fin" => match self.cpu.get_step_out_target() {
+                Some(target) => self.run_to(target),
+                None => return Err("not in a subroutine".to_owned()),
+            },
+            "until" | "u" => {
+                let address = parse_address(self.cpu.get_symbols(), words.next())?;
+                let target = self.cpu.get_run_to_target(address);
+                self.run_to(target);
+            }
+            "continue" | "c" => {
+                let frames = match words.next() {
+                    Some(text) => Some(parse_count(text)?),
+                    // without a terminal there is no Ctrl-C to stop it
+                    None if !self.editor.input.is_raw() => {
+                        return Err(
+                            "without a terminal `continue` needs a number of frames".to_owned()
+                        )
+                    }
+                    None => None,
+                };
+                self.continue_running(frames);
+            }
+            "break" | "b" => {
+                let breakpoint = Breakpoint::parse(arguments, self.cpu.get_symbols())?;
+                println!("breakpoint at {}", breakpoint);
+                self.cpu.add_breakpoint(breakpoint);
+            }
+            "watch" | "w" => {
+                let watchpoint = Watchpoint::parse(arguments, self.cpu.get_symbols())?;
+                println!("watchpoint on {}", watchpoint);
+                self.cpu.get_memory_mut().add_watchpoint(watchpoint);
+            }
+            "delete" | "d" => {
+                let address = parse_address(self.cpu.get_symbols(), words.next())?;
+                let breakpoint = self.cpu.remove_breakpoint(address);
+                let watchpoints = self.cpu.get_memory_mut().remove_watchpoints(address);
+                if breakpoint.is_none() && watchpoints.is_empty() {
+                    return Err(format!("no breakpoint or watchpoint at 0x{:03X}", address));
+                }
+                if let Some(breakpoint) = breakpoint {
+                    println!("deleted breakpoint at {}", breakpoint);
+                }
+                for watchpoint in watchpoints {
+                    println!("deleted watchpoint on {}", watchpoint);
+                }
+            }
+            "info" | "i" => self.show_breakpoints(),
+            "regs" | "r" => self.show_registers(),
+            "backtrace" | "bt" => self.show_call_stack(),
+            "mem" | "x" => {
+                let address = parse_address(self.cpu.get_symbols(), words.next())?;
+                let length = match words.next() {
+                    Some(text) => {
+                        parse_number(text).ok_or_else(|| format!("invalid length `{}`", text))?
+                    }
+                    None => DEFAULT_MEMORY_LENGTH,
+                };
+                self.show_memory(address, length);
+            }
+            "disas" | "dis" => {
+                let address = match words.next() {
+                    Some(text) => parse_address(self.cpu.get_symbols(), Some(text))?,
+                    None => self.cpu.get_pc(),
+                };
+                let count = match words.next() {
+                    Some(text) => parse_number(text)
+                        .ok_or_else(|| format!("invalid count `{}`", text))?
+                        as usize,
+                    None => DEFAULT_DISASSEMBLY_LENGTH,
+                };
+                self.show_disassembly(address, count);
+            }
+            "set" => {
+                let target = words.next().ok_or("missing the register or address")?;
+                let text = words.next().ok_or("missing the value")?;
+                let value = self
+                    .cpu
+                    .get_symbols()
+                    .parse_address(text)
+                    .ok_or_else(|| format!("invalid value `{}`", text))?;
+                self.set(target, value)?;
+            }
+            "screen" => self.show_screen(),
+            "press" | "p" => {
+                let key = parse_key(words.next().ok_or("missing the key")?)?;
+                self.cpu.set_key(key, true);
+            }
+            "release" => match words.next() {
+                Some(key) => self.cpu.set_key(parse_key(key)?, false),
+                None => self.cpu.set_keyboard([false; 16]),
+            },
+            "history" => {
+                for (i, line) in self.editor.history.iter().enumerate() {
+                    println!("{:4}  {}", i + 1, line);
+                }
+            }
+            "help" | "h" | "?" => println!("{}", HELP),
+            _ => return Err(format!("unknown command `{}`, try `help`", command)),
+        }
+
+        Ok(())
+    }
+
+    /// execute `count` instructions, the first one even if there is a breakpoint on it
+    fn step(&mut self, count: u32) {
+        self.last_break = None;
+        let mut result = self.cpu.step();
+
+        for i in 1..count {
+            if result.is_err() || self.cpu.has_exited() {
+                break;
+            }
+            if let Some(reason) = self.cpu.take_break() {
+                self.last_break = Some(reason);
+                break;
+            }
+            // stop before the instruction faults, `stopped` shows the warning
+            if self.cpu.get_stack_imbalance().is_some() {
+                break;
+            }
+            if i % STEPS_BETWEEN_INTERRUPT_CHECKS == 0 && self.editor.input.poll_interrupt() {
+                println!("interrupted");
+                break;
+            }
+
+            result = self.cpu.run_cycles(1);
+        }
+
+        self.stopped(result);
+    }
+
+    /// run until the target is reached, without a terminal for at most
+    /// `FRAMES_WITHOUT_TERMINAL` frames
+    fn run_to(&mut self, target: RunTarget) {
+        self.cpu.run_to(target);
+
+        let frames = if self.editor.input.is_raw() {
+            None
+        } else {
+            Some(FRAMES_WITHOUT_TERMINAL)
+        };
+        self.continue_running(frames);
+    }
+
+    /// run until the CPU breaks or faults, Ctrl-C is pressed or
+    /// `frames` frames were run
+    fn continue_running(&mut self, frames: Option<u32>) {
+        self.last_break = None;
+        let mut frame = 0;
+
+        let result = loop {
+            if frames == Some(frame) {
+                // `run_to` did not reach its target
+                self.cpu.clear_run_target();
+                println!("stopped after {} frames", frame);
+                break Ok(());
+            }
+            frame += 1;
+
+            let result = self.cpu.run_frame();
+            if result.is_err() || self.cpu.has_exited() {
+                break result;
+            }
+            if let Some(reason) = self.cpu.take_break() {
+                self.last_break = Some(reason);
+                break result;
+            }
+            if self.editor.input.poll_interrupt() {
+                self.cpu.clear_run_target();
+                println!("interrupted");
+                break Ok(());
+            }
+        };
+
+        self.stopped(result);
+    }
+
+    /// show why the CPU stopped and where
+    fn stopped(&mut self, result: Result<(), EmulationError>) {
+        if let Some(reason) = self.cpu.take_break() {
+            self.last_break = Some(reason);
+        }
+
+        if let Err(error) = result {
+            println!("fault: {}", error);
+        } else if self.cpu.has_exited() {
+            println!("the rom exited");
+        } else if let Some(reason) = &self.last_break {
+            println!("{}", reason);
+        } else if self.cpu.is_waiting_for_keypress() {
+            println!("waiting for a key (`press <key>`)");
+        }
+
+        self.show_current_instruction();
+        if let Some(imbalance) = self.cpu.get_stack_imbalance() {
+            println!("warning: {}", imbalance);
+        }
+    }
+
+    /// the address with its symbol, if any
+    fn format_address(&self, address: u16) -> String {
+        match self.cpu.get_symbols().describe(address) {
+            Some(name) => format!("{:04X} <{}>", address, name),
+            None => format!("{:04X}", address),
+        }
+    }
+
+    fn show_current_instruction(&self) {
+        let pc = self.cpu.get_pc();
+        let symbols = self.cpu.get_symbols();
+        let text = self
+            .cpu
+            .get_instruction(pc)
+            .map_or_else(|| "??".to_owned(), |op| symbols.format_op(&op));
+
+        match symbols.describe(pc) {
+            Some(name) => println!("=> {:04X} <{}>: {}", pc, name, text),
+            None => println!("=> {:04X}: {}", pc, text),
+        }
+    }
+
+    fn show_registers(&self) {
+        for (i, v) in self.cpu.get_v().iter().enumerate() {
+            print!(
+                "V{:X}: {:02X}{}",
+                i,
+                v,
+                if i % 4 == 3 { "\n" } else { "  " }
+            );
+        }
+        println!(
+            "I: {:04X}  PC: {:04X}  SP: {:02X}  DT: {:02X}  ST: {:02X}",
+            self.cpu.get_i(),
+            self.cpu.get_pc(),
+            self.cpu.get_sp(),
+            self.cpu.get_dt(),
+            self.cpu.get_st()
+        );
+
+        // the top of the stack first, like the debugger in the window
+        let stack: Vec<String> = self
+            .cpu
+            .get_stack()
+            .iter()
+            .rev()
+            .map(|&entry| self.format_address(entry))
+            .collect();
+        println!("stack: {}", stack.join(", "));
+
+        let keys: Vec<String> = (0..16)
+            .filter(|&key| self.cpu.get_keyboard()[key])
+            .map(|key| format!("{:X}", key))
+            .collect();
+        println!("keys: {}", keys.join(" "));
+
+        if let Some(fault) = self.cpu.get_fault() {
+            println!("fault: {}", fault);
+        }
+        if let Some(reason) = &self.last_break {
+            println!("break: {}", reason);
+        }
+    }
+
+    fn show_call_stack(&self) {
+        let frames = self.cpu.get_call_stack();
+        if frames.is_empty() {
+            println!("no calls in the stack");
+        }
+
+        for (i, frame) in frames.iter().enumerate() {
+            let entry = frame
+                .entry
+                .map_or_else(|| "?".to_owned(), |entry| self.format_address(entry));
+            let call_site = frame.call_site.map_or_else(
+                || "?".to_owned(),
+                |call_site| self.format_address(call_site),
+            );
+
+            print!(
+                "#{:<2} {}  called from {}, returns to {}",
+                i,
+                entry,
+                call_site,
+                self.format_address(frame.return_address)
+            );
+            if frame.call_site.is_none() {
+                print!("  (not after a CALL)");
+            }
+            if frame.recursion > 0 {
+                print!("  (recursion {} deep)", frame.recursion + 1);
+            }
+            println!();
+        }
+
+        if let Some(imbalance) = self.cpu.get_stack_imbalance() {
+            println!("warning: {}", imbalance);
+        }
+    }
+
+    fn show_breakpoints(&self) {
+        let mut empty = true;
+
+        for breakpoint in self.cpu.get_breakpoints() {
+            println!("breakpoint {} (hit {} times)", breakpoint, breakpoint.hits);
+            empty = false;
+        }
+        for watchpoint in self.cpu.get_memory().get_watchpoints() {
+            println!("watchpoint {}", watchpoint);
+            empty = false;
+        }
+
+        if empty {
+            println!("no breakpoints or watchpoints");
+        }
+    }
+
+    /// rows of 16 bytes with their characters
+    fn show_memory(&self, address: u16, length: u16) {
+        let memory = self.cpu.get_memory().get_memory();
+        let end = (address as usize + length as usize).min(MEMORY_SIZE);
+
+        for start in (address as usize..end).step_by(16) {
+            let row = &memory[start..(start + 16).min(end)];
+            let hex: Vec<String> = row.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let ascii: String = row
+                .iter()
+                .map(|&byte| {
+                    if (0x20..0x7F).contains(&byte) {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+
+            println!("{:04X}: {:<47}  {}", start, hex.join(" "), ascii);
+        }
+    }
+
+    /// the instructions following the flow from `address`, with `=>` at PC,
+    /// `*` at the breakpoints and the names of the symbols before their address
+    fn show_disassembly(&self, address: u16, count: usize) {
+        let memory = &self.cpu.get_memory().get_memory()[address as usize..];
+        let pc = self.cpu.get_pc();
+        let mut entry_points = vec![address];
+        if pc >= address {
+            entry_points.push(pc);
+        }
+
+        let symbols = self.cpu.get_symbols();
+        let analysis = disassembler::analyze(memory, address, &entry_points);
+        for instruction in disassembler::disassemble_flow(memory, address, &analysis)
+            .iter()
+            .take(count)
+        {
+            let current = if instruction.address == pc {
+                "=>"
+            } else {
+                "  "
+            };
+            let breakpoint = if self.cpu.get_breakpoint(instruction.address).is_some() {
+                "*"
+            } else {
+                " "
+            };
+
+            if let Some(name) = symbols.get_name(instruction.address) {
+                println!("{}:", name);
+            }
+            let text = match instruction.op {
+                Some(op) => symbols.format_op(&op),
+                None => instruction.opcode.clone(),
+            };
+
+            println!(
+                "{}{} {:04X}: {}",
+                current, breakpoint, instruction.address, text
+            );
+        }
+    }
+
+    /// change a register, or the byte at an address
+    fn set(&mut self, target: &str, value: u16) -> Result<(), String> {
+        match Operand::parse(target, self.cpu.get_symbols())? {
+            Operand::Value(address) => {
+                if value > 0xFF {
+                    return Err(format!("0x{:X} does not fit in a byte", value));
+                }
+                self.cpu.get_memory_mut().store(address, value as u8);
+            }
+            register => register.set_value(&mut self.cpu, value)?,
+        }
+
+        Ok(())
+    }
+
+    /// the display in text, `#` for the pixels of the first plane, `o` for
+    /// the second and `@` for both
+    fn show_screen(&self) {
+        let display = self.cpu.get_display();
+        let border = format!("+{}+", "-".repeat(display.get_width() as usize));
+
+        println!("{}", border);
+        for y in 0..display.get_height() {
+            let row: String = (0..display.get_width())
+                .map(|x| match display.get_pixel(x, y) {
+                    0 => ' ',
+                    1 => '#',
+                    2 => 'o',
+                    _ => '@',
+                })
+                .collect();
+            println!("|{}|", row);
+        }
+        println!("{}", border);
+    }
+}
+
+impl CPU {
+    /// debug the CPU from the terminal, without a window, with commands
+    /// read from stdin (`step`, `continue`, `break`, `regs`, `mem`, ...,
+    /// see `help`) until `quit` or the end of the input
+    pub fn run_repl(self) {
+        Repl::new(self).run();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a REPL reading from a channel instead of stdin, like from a pipe
+    fn repl() -> Repl {
+        let rom = [
+            0x70, 0x01, // ADD V0, 1
+            0x12, 0x00, // JP 0x200
+        ];
+        let mut cpu = CPU::new();
+        cpu.read_file(&mut &rom[..]);
+
+        let (_, receiver) = mpsc::channel();
+        let input = Input {
+            receiver,
+            pending: VecDeque::new(),
+            saved_terminal: None,
+        };
+        Repl {
+            cpu,
+            editor: LineEditor {
+                input,
+                history: Vec::new(),
+            },
+            last_command: String::new(),
+            last_break: None,
+        }
+    }
+
+    #[test]
+    fn step_zero_is_rejected() {
+        let mut repl = repl();
+        assert!(repl.execute("step", "0").is_err());
+        assert_eq!(repl.cpu.get_pc(), 0x200);
+
+        repl.execute("step", "3").unwrap();
+        assert_eq!(repl.cpu.get_v()[0], 2);
+        assert_eq!(repl.cpu.get_pc(), 0x202);
+    }
+
+    #[test]
+    fn runs_to_a_target_without_a_terminal_are_bounded() {
+        let rom = [
+            0x22, 0x04, // CALL 0x204
+            0x12, 0x00, // JP 0x200
+            0x12, 0x04, // JP 0x204, never returns
+        ];
+        let mut repl = repl();
+        repl.cpu.read_file(&mut &rom[..]);
+
+        repl.execute("step", "").unwrap();
+        repl.execute("finish", "").unwrap();
+        assert_eq!(repl.cpu.get_pc(), 0x204);
+        assert!(repl.cpu.get_run_target().is_none());
+
+        repl.execute("until", "0x202").unwrap();
+        assert_eq!(repl.cpu.get_pc(), 0x204);
+        assert!(repl.cpu.get_run_target().is_none());
+    }
+
+    #[test]
+    fn continue_without_a_terminal_needs_frames() {
+        let mut repl = repl();
+        assert!(repl.execute("continue", "").is_err());
+        assert!(repl.execute("continue", "0").is_err());
+        assert_eq!(repl.cpu.get_pc(), 0x200);
+
+        repl.execute("continue", "2").unwrap();
+        assert_ne!(repl.cpu.get_v()[0], 0);
+    }
+}
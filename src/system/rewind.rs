@@ -0,0 +1,266 @@
+use std::collections::VecDeque;
+
+/// The default memory limit of the rewind buffer
+pub const DEFAULT_REWIND_MEMORY: usize = 16 * 1024 * 1024;
+
+/// A bounded buffer of save states (see `CPU::save_state`) to go back in time.
+///
+/// Only the newest state is stored whole, the older ones are stored as the
+/// difference from the state after them, which is small since most of the
+/// memory does not change between two snapshots. When the buffer uses more
+/// than its memory limit, the oldest states are dropped.
+pub struct Rewind {
+    newest: Option<Vec<u8>>,   // the last pushed state
+    deltas: VecDeque<Vec<u8>>, // the differences to go from a state to the one before it, oldest first
+    used_memory: usize,        // the size of `newest` and `deltas` in bytes
+    max_memory: usize,         // the limit of `used_memory`
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Self::new(DEFAULT_REWIND_MEMORY)
+    }
+}
+
+impl Rewind {
+    /// creates an empty buffer that keeps at most `max_memory` bytes of states,
+    /// with `0` nothing is kept
+    pub fn new(max_memory: usize) -> Rewind {
+        Rewind {
+            newest: None,
+            deltas: VecDeque::new(),
+            used_memory: 0,
+            max_memory,
+        }
+    }
+
+    pub fn get_max_memory(&self) -> usize {
+        self.max_memory
+    }
+
+    /// change the memory limit, old states are dropped if needed
+    pub fn set_max_memory(&mut self, max_memory: usize) {
+        self.max_memory = max_memory;
+        self.drop_old_states();
+    }
+
+    pub fn get_used_memory(&self) -> usize {
+        self.used_memory
+    }
+
+    /// the number of states that can be popped
+    pub fn len(&self) -> usize {
+        match self.newest {
+            Some(_) => self.deltas.len() + 1,
+            None => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.used_memory = 0;
+    }
+
+    /// add a new state, it will be the first one returned by `pop`
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(newest) = self.newest.take() {
+            let delta = Self::encode_delta(&state, &newest);
+            self.used_memory += delta.len();
+            self.used_memory -= newest.len();
+            self.deltas.push_back(delta);
+        }
+
+        self.used_memory += state.len();
+        self.newest = Some(state);
+
+        self.drop_old_states();
+    }
+
+    /// remove and return the newest state
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+        self.used_memory -= newest.len();
+
+        if let Some(delta) = self.deltas.pop_back() {
+            let previous = Self::apply_delta(&newest, &delta);
+            self.used_memory -= delta.len();
+            self.used_memory += previous.len();
+            self.newest = Some(previous);
+        }
+
+        Some(newest)
+    }
+
+    fn drop_old_states(&mut self) {
+        while self.used_memory > self.max_memory {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used_memory -= delta.len(),
+                None => {
+                    // only the newest is left, and it does not fit
+                    self.clear();
+                }
+            }
+        }
+    }
+
+    /// the difference to get `to` from `from`, it is made of the length of
+    /// `to`, then blocks of (number of unchanged bytes, number of changed bytes,
+    /// the changed bytes XORed), all the numbers are u32 little endian
+    fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+        let length = from.len().max(to.len());
+        let byte_at = |data: &[u8], i: usize| data.get(i).copied().unwrap_or(0);
+
+        let mut delta = Vec::new();
+        delta.extend_from_slice(&(to.len() as u32).to_le_bytes());
+
+        let mut i = 0;
+        while i < length {
+            let start = i;
+            while i < length && byte_at(from, i) == byte_at(to, i) {
+                i += 1;
+            }
+            let unchanged = i - start;
+
+            let changed_start = i;
+            while i < length && byte_at(from, i) != byte_at(to, i) {
+                i += 1;
+            }
+            let changed = i - changed_start;
+
+            if changed == 0 {
+                break;
+            }
+
+            delta.extend_from_slice(&(unchanged as u32).to_le_bytes());
+            delta.extend_from_slice(&(changed as u32).to_le_bytes());
+            for j in changed_start..i {
+                delta.push(byte_at(from, j) ^ byte_at(to, j));
+            }
+        }
+
+        delta
+    }
+
+    /// the opposite of `encode_delta`, get `to` from `from` and the delta
+    fn apply_delta(from: &[u8], delta: &[u8]) -> Vec<u8> {
+        let read_u32 = |position: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&delta[position..position + 4]);
+            u32::from_le_bytes(bytes) as usize
+        };
+
+        let length = read_u32(0);
+        let mut result = from.to_vec();
+        result.resize(from.len().max(length), 0);
+
+        let mut position = 4;
+        let mut i = 0;
+        while position < delta.len() {
+            let unchanged = read_u32(position);
+            let changed = read_u32(position + 4);
+            position += 8;
+
+            i += unchanged;
+            for (byte, xor) in result[i..i + changed]
+                .iter_mut()
+                .zip(&delta[position..position + changed])
+            {
+                *byte ^= xor;
+            }
+            i += changed;
+            position += changed;
+        }
+
+        result.truncate(length);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(from: &[u8], to: &[u8]) {
+        let delta = Rewind::encode_delta(from, to);
+        assert_eq!(Rewind::apply_delta(from, &delta), to);
+    }
+
+    #[test]
+    fn delta_round_trips() {
+        round_trip(&[1, 2, 3, 4], &[1, 2, 3, 4]);
+        round_trip(&[1, 2, 3, 4], &[0, 2, 0, 0]);
+        round_trip(&[1, 2, 3, 4], &[1, 2, 3, 4, 5, 6]);
+        round_trip(&[1, 2, 3, 4, 5, 6], &[1, 9, 3]);
+        round_trip(&[], &[7, 0, 7]);
+        round_trip(&[7, 0, 7], &[]);
+    }
+
+    #[test]
+    fn delta_only_has_the_changed_bytes() {
+        let from = vec![0; 1000];
+        let mut to = from.clone();
+        to[10] = 1;
+        to[11] = 2;
+        to[500] = 3;
+
+        let delta = Rewind::encode_delta(&from, &to);
+        // the length, then two blocks of (unchanged, changed, bytes)
+        assert_eq!(delta.len(), 4 + (8 + 2) + (8 + 1));
+        assert_eq!(Rewind::encode_delta(&from, &from).len(), 4);
+    }
+
+    #[test]
+    fn pop_returns_the_newest_states_first() {
+        let mut rewind = Rewind::new(DEFAULT_REWIND_MEMORY);
+        let states: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 100 + i as usize]).collect();
+        for state in &states {
+            rewind.push(state.clone());
+        }
+        assert_eq!(rewind.len(), states.len());
+
+        for state in states.iter().rev() {
+            assert_eq!(rewind.pop().as_ref(), Some(state));
+        }
+        assert_eq!(rewind.pop(), None);
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.get_used_memory(), 0);
+    }
+
+    #[test]
+    fn used_memory_stays_under_the_limit() {
+        let mut rewind = Rewind::new(1000);
+        let mut state = vec![0u8; 400];
+        for i in 0..100 {
+            state[i * 3] = i as u8 + 1;
+            rewind.push(state.clone());
+            assert!(rewind.get_used_memory() <= 1000);
+        }
+
+        // the oldest were dropped, the newest are still there
+        let kept = rewind.len();
+        assert!(kept > 1 && kept < 100);
+        assert_eq!(rewind.pop(), Some(state));
+
+        rewind.set_max_memory(400);
+        assert_eq!(rewind.len(), 1);
+        assert!(rewind.get_used_memory() <= 400);
+    }
+
+    #[test]
+    fn states_larger_than_the_limit_are_not_kept() {
+        let mut rewind = Rewind::new(0);
+        rewind.push(vec![1; 10]);
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.get_used_memory(), 0);
+
+        let mut rewind = Rewind::new(100);
+        rewind.push(vec![1; 50]);
+        rewind.push(vec![2; 200]);
+        assert!(rewind.is_empty());
+    }
+}
@@ -1,31 +1,86 @@
-use rodio::{source::SineWave, Sink};
+use super::audio::{Audio, AudioRenderer};
+use rodio::{Sink, Source};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const SAMPLE_RATE: u32 = 44100;
+// number of samples rendered at once, to not lock the renderer for every sample
+const CHUNK_SIZE: usize = 256;
+
+/// A rodio source that plays the audio pattern of the CPU
+struct PatternSource {
+    renderer: Arc<Mutex<AudioRenderer>>,
+    buffer: [f32; CHUNK_SIZE],
+    position: usize,
+}
+
+impl Iterator for PatternSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.position == CHUNK_SIZE {
+            self.renderer.lock().unwrap().render(&mut self.buffer);
+            self.position = 0;
+        }
+
+        let sample = self.buffer[self.position];
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+impl Source for PatternSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
 
 pub struct Sound {
-    sink: Sink,
+    _sink: Sink,
+    renderer: Arc<Mutex<AudioRenderer>>,
+}
+
+impl Default for Sound {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Sound {
-    pub fn new(freq: u32) -> Self {
+    pub fn new() -> Self {
         let device = rodio::default_output_device().unwrap();
         let sink = Sink::new(&device);
 
-        // Add a dummy source of the sake of the example.
-        let source = SineWave::new(freq);
-        sink.append(source);
-        sink.pause(); // Start without playing.
+        let renderer = Arc::new(Mutex::new(AudioRenderer::new(SAMPLE_RATE)));
 
-        Sound { sink: sink }
-    }
-
-    pub fn play(&mut self) {
-        self.sink.play();
-    }
+        // the source is always playing, it renders silence when the CPU is not beeping
+        sink.append(PatternSource {
+            renderer: renderer.clone(),
+            buffer: [0.; CHUNK_SIZE],
+            position: CHUNK_SIZE,
+        });
 
-    pub fn stop(&mut self) {
-        self.sink.pause();
+        Sound {
+            _sink: sink,
+            renderer,
+        }
     }
 
-    pub fn is_paused(&self) -> bool {
-        self.sink.is_paused()
+    /// update the played sound from the audio state of the CPU,
+    /// `playing` should be true while ST is not zero
+    pub fn update(&mut self, audio: &Audio, playing: bool) {
+        self.renderer.lock().unwrap().update(audio, playing);
     }
 }
@@ -0,0 +1,375 @@
+use std::error::Error;
+use std::fmt;
+
+/// The first bytes of every save state file
+pub const STATE_MAGIC: [u8; 8] = *b"CHIP8SST";
+/// The version of the save state format, increased on every incompatible
+/// change, states of other versions are rejected
+pub const STATE_VERSION: u16 = 1;
+
+// magic + version + reserved + body length + checksum
+const HEADER_SIZE: usize = 8 + 2 + 2 + 4 + 4;
+
+/// An error in loading a save state, the CPU is not changed when this happens
+#[derive(Clone, Debug, PartialEq)]
+pub enum SaveStateError {
+    /// the data does not start with `STATE_MAGIC`, not a save state
+    InvalidMagic,
+    /// the state was saved by a different version of the format
+    UnsupportedVersion(u16),
+    /// the data is shorter or longer than what the header says
+    InvalidLength,
+    /// the checksum in the header does not match the data (corrupted state)
+    ChecksumMismatch,
+    /// the checksum is correct but a value is not valid (e.g. SP > 16)
+    InvalidData(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SaveStateError::InvalidMagic => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported save state version {} (expected {})",
+                version, STATE_VERSION
+            ),
+            SaveStateError::InvalidLength => write!(f, "save state has an invalid length"),
+            SaveStateError::ChecksumMismatch => write!(f, "save state checksum mismatch"),
+            SaveStateError::InvalidData(what) => write!(f, "invalid save state data: {}", what),
+        }
+    }
+}
+
+impl Error for SaveStateError {}
+
+/// Writes the body of a save state, all the values are little endian
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// the whole state, the header followed by the written body
+    pub fn finish(self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(HEADER_SIZE + self.data.len());
+        result.extend_from_slice(&STATE_MAGIC);
+        result.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        result.extend_from_slice(&0u16.to_le_bytes()); // reserved
+        result.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        result.extend_from_slice(&crc32(&self.data).to_le_bytes());
+        result.extend_from_slice(&self.data);
+        result
+    }
+}
+
+/// Reads the body of a save state, the header is checked in `new`
+#[derive(Clone)]
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    /// check the header (magic, version, length and checksum) of `state`
+    /// and start reading its body
+    pub fn new(state: &'a [u8]) -> Result<StateReader<'a>, SaveStateError> {
+        if state.len() < STATE_MAGIC.len() || state[..STATE_MAGIC.len()] != STATE_MAGIC {
+            return Err(SaveStateError::InvalidMagic);
+        }
+        if state.len() < HEADER_SIZE {
+            return Err(SaveStateError::InvalidLength);
+        }
+
+        let mut header = StateReader {
+            data: &state[STATE_MAGIC.len()..HEADER_SIZE],
+        };
+        let version = header.read_u16()?;
+        let _reserved = header.read_u16()?;
+        let length = header.read_u32()? as usize;
+        let checksum = header.read_u32()?;
+
+        if version != STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+
+        let body = &state[HEADER_SIZE..];
+        if body.len() != length {
+            return Err(SaveStateError::InvalidLength);
+        }
+        if crc32(body) != checksum {
+            return Err(SaveStateError::ChecksumMismatch);
+        }
+
+        Ok(StateReader { data: body })
+    }
+
+    /// returns an error if there is data left that was not read
+    pub fn finish(self) -> Result<(), SaveStateError> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(SaveStateError::InvalidLength)
+        }
+    }
+
+    pub fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.data.len() < length {
+            return Err(SaveStateError::InvalidLength);
+        }
+
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::InvalidData("boolean")),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.read_bytes(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SaveStateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.read_bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SaveStateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.read_bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+/// CRC-32 (the one used by zip and png) of `data`
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::cpu::CPU;
+
+    /// a CPU that ran a small rom drawing a sprite and calling a subroutine
+    fn running_cpu() -> CPU {
+        let rom = [
+            0x60, 0x05, // LD V0, 5
+            0xF0, 0x29, // LD F, V0
+            0x61, 0x0A, // LD V1, 10
+            0xD1, 0x15, // DRW V1, V1, 5
+            0x22, 0x0E, // CALL 0x20E
+            0x12, 0x08, // JP 0x208
+            0x00, 0x00, //
+            0xC2, 0xFF, // RND V2, 0xFF
+            0xF0, 0x15, // LD DT, V0
+            0x00, 0xEE, // RET
+        ];
+        let mut cpu = CPU::new();
+        cpu.set_seed(42);
+        cpu.read_file(&mut &rom[..]);
+        cpu.run_cycles(7).unwrap();
+        cpu
+    }
+
+    /// change a byte of the body of `state` and fix its checksum
+    fn patch_body(state: &mut [u8], offset: usize, value: u8) {
+        state[HEADER_SIZE + offset] = value;
+        let checksum = crc32(&state[HEADER_SIZE..]);
+        state[16..20].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0xAB);
+        writer.write_bool(true);
+        writer.write_u16(0x1234);
+        writer.write_u32(0xDEAD_BEEF);
+        writer.write_u64(0x0123_4567_89AB_CDEF);
+        writer.write_bytes(&[1, 2, 3]);
+        let state = writer.finish();
+
+        let mut reader = StateReader::new(&state).unwrap();
+        assert_eq!(reader.read_u8(), Ok(0xAB));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x1234));
+        assert_eq!(reader.read_u32(), Ok(0xDEAD_BEEF));
+        assert_eq!(reader.read_u64(), Ok(0x0123_4567_89AB_CDEF));
+        assert_eq!(reader.read_bytes(3), Ok(&[1, 2, 3][..]));
+        assert_eq!(reader.read_u8(), Err(SaveStateError::InvalidLength));
+        assert_eq!(reader.finish(), Ok(()));
+    }
+
+    #[test]
+    fn unread_data_is_rejected() {
+        let mut writer = StateWriter::new();
+        writer.write_u16(1);
+        let state = writer.finish();
+
+        let mut reader = StateReader::new(&state).unwrap();
+        reader.read_u8().unwrap();
+        assert_eq!(reader.finish(), Err(SaveStateError::InvalidLength));
+    }
+
+    #[test]
+    fn crc32_matches_the_standard() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    // where the seed of `RND` is in the body of a CPU state
+    const SEED_OFFSET: usize = 16 + 2 + 1 + 1 + 2 + 1 + 16 * 2 + 16 + 1 + 1 + 1 + 8 + 4 + 1;
+
+    #[test]
+    fn cpu_state_round_trips() {
+        let cpu = running_cpu();
+        let state = cpu.save_state();
+
+        let mut loaded = CPU::new();
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.get_pc(), cpu.get_pc());
+        assert_eq!(loaded.get_stack(), cpu.get_stack());
+
+        // the same, except for the seed drawn from the new generator
+        let resaved = loaded.save_state();
+        let seed = HEADER_SIZE + SEED_OFFSET..HEADER_SIZE + SEED_OFFSET + 8;
+        assert_eq!(
+            resaved[HEADER_SIZE..seed.start],
+            state[HEADER_SIZE..seed.start]
+        );
+        assert_eq!(resaved[seed.end..], state[seed.end..]);
+    }
+
+    #[test]
+    fn loading_a_state_is_deterministic() {
+        let state = running_cpu().save_state();
+
+        let mut first = CPU::new();
+        first.load_state(&state).unwrap();
+        first.run_cycles(200).unwrap();
+
+        // random numbers included
+        let mut second = running_cpu();
+        second.run_cycles(50).unwrap();
+        second.load_state(&state).unwrap();
+        second.run_cycles(200).unwrap();
+
+        assert_eq!(first.save_state(), second.save_state());
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let mut state = running_cpu().save_state();
+        state[0] = b'X';
+        assert_eq!(
+            CPU::new().load_state(&state),
+            Err(SaveStateError::InvalidMagic)
+        );
+        assert_eq!(
+            CPU::new().load_state(b"CHIP"),
+            Err(SaveStateError::InvalidMagic)
+        );
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut state = running_cpu().save_state();
+        state[8..10].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            CPU::new().load_state(&state),
+            Err(SaveStateError::UnsupportedVersion(STATE_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn corrupted_states_are_rejected() {
+        let state = running_cpu().save_state();
+
+        let mut corrupted = state.clone();
+        corrupted[HEADER_SIZE + 100] ^= 1;
+        assert_eq!(
+            CPU::new().load_state(&corrupted),
+            Err(SaveStateError::ChecksumMismatch)
+        );
+
+        assert_eq!(
+            CPU::new().load_state(&state[..state.len() - 1]),
+            Err(SaveStateError::InvalidLength)
+        );
+        assert_eq!(
+            CPU::new().load_state(&state[..HEADER_SIZE - 1]),
+            Err(SaveStateError::InvalidLength)
+        );
+    }
+
+    #[test]
+    fn invalid_data_does_not_change_the_cpu() {
+        let mut state = running_cpu().save_state();
+        // SP is after V0-VF, I, DT, ST and PC
+        patch_body(&mut state, 16 + 2 + 1 + 1 + 2, 17);
+
+        let mut cpu = running_cpu();
+        let before = cpu.save_state();
+        assert_eq!(
+            cpu.load_state(&state),
+            Err(SaveStateError::InvalidData("stack pointer"))
+        );
+        assert_eq!(cpu.save_state(), before);
+    }
+}
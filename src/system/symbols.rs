@@ -0,0 +1,339 @@
+use super::number::parse_number;
+use super::op::Op;
+use std::collections::{BTreeMap, HashMap};
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Names of addresses (the labels of the source of a rom), loaded from a
+/// symbol file, used by the debuggers in place of the raw addresses
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    names: BTreeMap<u16, String>,    // the name shown for every address
+    addresses: HashMap<String, u16>, // the address of every name
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    /// parse a symbol file, either `name = address` lines (with `#` or `;`
+    /// comments) or JSON, an object of names to addresses or with such an
+    /// object in `labels` (like the symbols written by Octo)
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        if text.trim_start().starts_with('{') {
+            Symbols::parse_json(text)
+        } else {
+            Symbols::parse_lines(text)
+        }
+    }
+
+    fn parse_lines(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split(['#', ';']).next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (name, address) = match line.find('=') {
+                Some(position) => (line[..position].trim(), line[position + 1..].trim()),
+                None => return Err(format!("line {}: expected `name = address`", i + 1)),
+            };
+            let address = parse_number(address)
+                .ok_or_else(|| format!("line {}: invalid address `{}`", i + 1, address))?;
+
+            symbols
+                .insert(name, address)
+                .map_err(|error| format!("line {}: {}", i + 1, error))?;
+        }
+
+        Ok(symbols)
+    }
+
+    fn parse_json(text: &str) -> Result<Symbols, String> {
+        let mut entries = match Json::parse(text)? {
+            Json::Object(entries) => entries,
+            _ => return Err("expected a JSON object".to_owned()),
+        };
+
+        if let Some(position) = entries.iter().position(|(key, _)| key == "labels") {
+            entries = match entries.swap_remove(position).1 {
+                Json::Object(labels) => labels,
+                _ => return Err("expected an object in `labels`".to_owned()),
+            };
+        }
+
+        let mut symbols = Symbols::new();
+        for (name, value) in entries {
+            // the other values (constants of other kinds, ...) are not addresses
+            let address = match value {
+                Json::Number(number) => number,
+                Json::String(text) => match parse_number(&text) {
+                    Some(address) => address as f64,
+                    None => continue,
+                },
+                _ => continue,
+            };
+
+            if address < 0.0 || address > u16::MAX as f64 || address.fract() != 0.0 {
+                return Err(format!("invalid address {} of `{}`", address, name));
+            }
+            symbols.insert(&name, address as u16)?;
+        }
+
+        Ok(symbols)
+    }
+
+    /// add a symbol, the first name of an address is the one shown
+    pub fn insert(&mut self, name: &str, address: u16) -> Result<(), String> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("invalid name `{}`", name));
+        }
+        if parse_number(name).is_some() {
+            return Err(format!("the name `{}` is a number", name));
+        }
+
+        self.addresses.insert(name.to_owned(), address);
+        self.names.entry(address).or_insert_with(|| name.to_owned());
+        Ok(())
+    }
+
+    pub fn get_name(&self, address: u16) -> Option<&str> {
+        self.names.get(&address).map(|name| name.as_str())
+    }
+
+    pub fn get_address(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    /// the address of a symbol or a number
+    pub fn parse_address(&self, text: &str) -> Option<u16> {
+        self.get_address(text.trim()).or_else(|| parse_number(text))
+    }
+
+    /// the name of `address`, or `name+offset` from the closest symbol before it
+    pub fn describe(&self, address: u16) -> Option<String> {
+        let (&start, name) = self.names.range(..=address).next_back()?;
+
+        if start == address {
+            Some(name.clone())
+        } else {
+            Some(format!("{}+0x{:X}", name, address - start))
+        }
+    }
+
+    /// the text of the instruction with the address it uses (by `SYS`, `JMP`,
+    /// `CALL`, `LD I` or `JP V0`) replaced by its name
+    pub fn format_op(&self, op: &Op) -> String {
+        let name = |address| self.get_name(address);
+
+        match *op {
+            Op::Sys(address) => name(address).map(|name| format!("SYS {}", name)),
+            Op::Jp(address) => name(address).map(|name| format!("JMP {}", name)),
+            Op::Call(address) => name(address).map(|name| format!("CALL {}", name)),
+            Op::LdI(address) => name(address).map(|name| format!("LD I, {}", name)),
+            Op::LdILong(address) => name(address).map(|name| format!("LD I, long {}", name)),
+            Op::JpV0(address) => name(address).map(|name| format!("JP V0, {}", name)),
+            _ => None,
+        }
+        .unwrap_or_else(|| op.to_string())
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+}
+
+/// A JSON value, only what is needed to read symbol files
+enum Json {
+    Number(f64),
+    String(String),
+    Object(Vec<(String, Json)>),
+    Other, // `null`, `true`, `false` and arrays
+}
+
+impl Json {
+    fn parse(text: &str) -> Result<Json, String> {
+        let mut chars = text.chars().peekable();
+        let value = Json::parse_value(&mut chars)?;
+
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Ok(value),
+            Some(c) => Err(format!("unexpected `{}` after the JSON value", c)),
+        }
+    }
+
+    fn parse_value(chars: &mut Peekable<Chars>) -> Result<Json, String> {
+        skip_whitespace(chars);
+
+        match chars.peek().copied() {
+            Some('{') => {
+                chars.next();
+                let mut entries = Vec::new();
+                if !consume(chars, '}') {
+                    loop {
+                        skip_whitespace(chars);
+                        let key = parse_string(chars)?;
+                        skip_whitespace(chars);
+                        expect(chars, ':')?;
+                        entries.push((key, Json::parse_value(chars)?));
+                        if !consume(chars, ',') {
+                            expect(chars, '}')?;
+                            break;
+                        }
+                    }
+                }
+                Ok(Json::Object(entries))
+            }
+            Some('[') => {
+                chars.next();
+                if !consume(chars, ']') {
+                    loop {
+                        Json::parse_value(chars)?;
+                        if !consume(chars, ',') {
+                            expect(chars, ']')?;
+                            break;
+                        }
+                    }
+                }
+                Ok(Json::Other)
+            }
+            Some('"') => Ok(Json::String(parse_string(chars)?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_ascii_digit() || "+-.eE".contains(c)) {
+                        break;
+                    }
+                    number.push(c);
+                    chars.next();
+                }
+                number
+                    .parse()
+                    .map(Json::Number)
+                    .map_err(|_| format!("invalid number `{}`", number))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !c.is_ascii_alphabetic() {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                match word.as_str() {
+                    "true" | "false" | "null" => Ok(Json::Other),
+                    _ => Err(format!("unexpected `{}`", word)),
+                }
+            }
+            Some(c) => Err(format!("unexpected `{}`", c)),
+            None => Err("unexpected end of the JSON".to_owned()),
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+/// skip `c` (after whitespace) if it is next, returns true if it was
+fn consume(chars: &mut Peekable<Chars>, c: char) -> bool {
+    skip_whitespace(chars);
+    if chars.peek() == Some(&c) {
+        chars.next();
+        true
+    } else {
+        false
+    }
+}
+
+fn expect(chars: &mut Peekable<Chars>, c: char) -> Result<(), String> {
+    if consume(chars, c) {
+        Ok(())
+    } else {
+        Err(format!("expected `{}`", c))
+    }
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    expect(chars, '"')?;
+
+    let mut result = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(result),
+            Some('\\') => match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some('r') => result.push('\r'),
+                Some('b') => result.push('\u{8}'),
+                Some('f') => result.push('\u{c}'),
+                Some('u') => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    let c = u32::from_str_radix(&code, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| format!("invalid escape `\\u{}`", code))?;
+                    result.push(c);
+                }
+                Some(c) => result.push(c),
+                None => return Err("unexpected end of the JSON".to_owned()),
+            },
+            Some(c) => result.push(c),
+            None => return Err("unterminated string".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols() -> Symbols {
+        Symbols::parse("main = 0x200\nsprite = 0x300\nfar = 0x1234").unwrap()
+    }
+
+    #[test]
+    fn addresses_are_replaced_by_their_names() {
+        let symbols = symbols();
+
+        assert_eq!(symbols.format_op(&Op::Sys(0x200)), "SYS main");
+        assert_eq!(symbols.format_op(&Op::Jp(0x200)), "JMP main");
+        assert_eq!(symbols.format_op(&Op::Call(0x200)), "CALL main");
+        assert_eq!(symbols.format_op(&Op::LdI(0x300)), "LD I, sprite");
+        assert_eq!(symbols.format_op(&Op::LdILong(0x1234)), "LD I, long far");
+        assert_eq!(symbols.format_op(&Op::LdILong(0x300)), "LD I, long sprite");
+        assert_eq!(symbols.format_op(&Op::JpV0(0x300)), "JP V0, sprite");
+    }
+
+    #[test]
+    fn other_instructions_are_unchanged() {
+        let symbols = symbols();
+
+        // no name for the address
+        assert_eq!(symbols.format_op(&Op::Jp(0x202)), "JMP 0x202");
+        // the instructions without an address
+        for op in [Op::LdILong(0x1236), Op::Rnd(0, 0xFF), Op::Cls, Op::Ret] {
+            assert_eq!(symbols.format_op(&op), op.to_string());
+        }
+    }
+
+    #[test]
+    fn describe_uses_the_closest_symbol_before() {
+        let symbols = symbols();
+
+        assert_eq!(symbols.describe(0x200).as_deref(), Some("main"));
+        assert_eq!(symbols.describe(0x20A).as_deref(), Some("main+0xA"));
+        assert_eq!(symbols.describe(0x1FF), None);
+    }
+}